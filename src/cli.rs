@@ -2,30 +2,54 @@
 //! options required by the engine
 
 use crate::{
-    engine::Opts as EngineOpts,
-    models::{Operation, Unit, Value, ValueSet, Target},
+    engine::{Opts as EngineOpts, Action},
+    models::{Operation, Unit, Value, Target},
     parser::parse_target,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::{Arg, Command};
 
 use std::collections::HashMap;
+use std::io::IsTerminal;
 
 use async_std::path::PathBuf;
 
 mod reporter;
+mod args;
+mod journal;
+mod history;
 
 pub use reporter::{EngineLogger, Verbosity};
+pub use journal::Journal;
 
-pub struct CLI {
+pub struct Cli {
     matches: clap::ArgMatches,
+    /// Shared by the journal and the engine's checkpoint, so a failed run can be resumed by
+    /// the id it's listed under
+    run_id: String,
 }
 
-impl CLI {
-    pub fn init() -> Result<CLI> {
-        let matches = get_cli_definition().get_matches();
-        let cli = CLI { matches };
+impl Cli {
+    pub fn init() -> Result<Cli> {
+        let mut definition = get_cli_definition();
+        let matches = definition.get_matches_mut();
+
+        // Help for a unit is shown by the engine, but without one there's only our own to show
+        if matches.get_flag("help") && !matches.contains_id("unit_name") {
+            definition.print_help()?;
+            std::process::exit(0);
+        }
+
+        let cli = Cli { matches, run_id: journal::new_run_id() };
+
+        // Every operation but history needs a unit
+        if !cli.matches.get_flag("help") && !cli.is_history() && !cli.matches.contains_id("unit_name") {
+            definition.error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  <unit_name>",
+            ).exit();
+        }
 
         Ok(cli)
     }
@@ -70,30 +94,142 @@ impl CLI {
         Ok(EngineLogger::new(self.get_verbosity_level()?))
     }
 
+    /// Provides an observer which records the run in the journal, if it's an operation on a
+    /// unit and state is being kept
+    pub fn get_journal(&self) -> Result<Option<Journal>> {
+        let op = match self.get_action()? {
+            Action::Run(op) => op,
+            _ => return Ok(None),
+        };
+
+        Ok(self.get_state_dir().map(|dir| Journal::new(journal_path(&dir), op, self.run_id.clone())))
+    }
+
+    pub fn is_history(&self) -> bool {
+        self.matches.get_one::<String>("operation").is_some_and(|op| op == "history")
+    }
+
+    /// Lists the runs in the journal, or shows one of them if its id is given
+    pub fn show_history(&self) -> Result<()> {
+        let dir = self.get_state_dir()
+            .ok_or_else(|| anyhow!("History is kept in the state directory, which isn't set"))?;
+        let journal = journal_path(&dir);
+
+        if let Some(id) = self.matches.get_one::<String>("unit_name") {
+            return history::show(&journal, id);
+        }
+
+        let filter = history::Filter {
+            target: match self.matches.get_one::<String>("target") {
+                Some(t) => Some(parse_target(t)?.to_string()),
+                None => None,
+            },
+            unit: self.matches.get_one::<String>("unit").cloned(),
+            status: match self.matches.get_one::<String>("status").map(String::as_str) {
+                Some("success") => Some(journal::RunStatus::Success),
+                Some(_) => Some(journal::RunStatus::Failed),
+                None => None,
+            },
+            since: self.matches.get_one::<String>("since").map(|d| history::parse_date(d, false)).transpose()?,
+            until: self.matches.get_one::<String>("until").map(|d| history::parse_date(d, true)).transpose()?,
+        };
+        history::list(&journal, &filter)
+    }
+
+    fn get_action(&self) -> Result<Action> {
+        if self.matches.get_flag("help") {
+            return Ok(Action::Help);
+        }
+
+        let operation = self.matches.get_one::<String>("operation").unwrap();
+
+        match operation.as_str() {
+            "bundle" => {
+                let output = self.matches.get_one::<String>("output").unwrap();
+                Ok(Action::Bundle(PathBuf::from(output)))
+            },
+            "lock" => Ok(Action::Lock(self.get_lockfile_path())),
+            _ => Ok(Action::Run(operation.parse::<Operation>().unwrap())),
+        }
+    }
+
+    fn get_trusted_keys_path(&self) -> Option<PathBuf> {
+        match self.matches.get_one::<String>("trusted_keys") {
+            Some(p) => Some(PathBuf::from(p)),
+            None => std::env::var("SYSU_TRUSTED_KEYS").ok().map(PathBuf::from),
+        }
+    }
+
+    /// Finds the state directory from the flag, the environment or the XDG state home, unless
+    /// state isn't to be kept
+    fn get_state_dir(&self) -> Option<PathBuf> {
+        if self.matches.get_flag("no_state") {
+            return None;
+        }
+
+        if let Some(dir) = self.matches.get_one::<String>("state_dir") {
+            return Some(PathBuf::from(dir));
+        }
+
+        if let Ok(dir) = std::env::var("SYSU_STATE_DIR") {
+            return Some(PathBuf::from(dir));
+        }
+
+        match std::env::var("XDG_STATE_HOME") {
+            Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("sysunit")),
+            _ => std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(".local/state/sysunit")),
+        }
+    }
+
+    fn get_lockfile_path(&self) -> PathBuf {
+        PathBuf::from(self.matches.get_one::<String>("lockfile").unwrap())
+    }
+
     pub fn get_engine_options(&self) -> Result<EngineOpts> {
         let engine_opts = EngineOpts {
             remove_deps: self.matches.get_flag("remove_deps"),
-            operation: self
-                .matches
-                .get_one::<String>("operation")
-                .unwrap()
-                .parse::<Operation>()
-                .unwrap(),
+            action: self.get_action()?,
             debug: self.matches.get_flag("debug"),
             search_paths: self.get_search_paths()?,
             unit: self.get_unit()?.into(),
             adapters: self.get_adapters()?,
+            lockfile: if self.matches.get_flag("locked") {
+                Some(self.get_lockfile_path())
+            } else {
+                None
+            },
+            trusted_keys: self.get_trusted_keys_path(),
+            require_signed: self.matches.get_flag("require_signed"),
+            // Only prompt when there's someone at the terminal to answer
+            prompt: !self.matches.get_flag("no_prompt") && std::io::stdin().is_terminal(),
+            state_dir: self.get_state_dir(),
+            prune: self.matches.get_flag("prune"),
+            run_id: self.run_id.clone(),
+            resume: self.matches.get_one::<String>("resume").cloned(),
+            recheck: self.matches.get_flag("recheck"),
         };
 
-        let operation = engine_opts.operation;
+        let action = &engine_opts.action;
         let remove_deps = engine_opts.remove_deps;
 
-        if !matches!(operation, Operation::Remove) && remove_deps {
+        if !matches!(action, Action::Run(Operation::Remove)) && remove_deps {
             return Err(anyhow!(
                 "--remove-deps can only be used with the 'remove' operation"
             ));
         }
 
+        if !matches!(action, Action::Run(Operation::Apply)) && engine_opts.prune {
+            return Err(anyhow!(
+                "--prune can only be used with the 'apply' operation"
+            ));
+        }
+
+        if !matches!(action, Action::Run(Operation::Apply)) && engine_opts.resume.is_some() {
+            return Err(anyhow!(
+                "--resume can only be used with the 'apply' operation"
+            ));
+        }
+
         Ok(engine_opts)
     }
 
@@ -101,28 +237,48 @@ impl CLI {
         let matches = &self.matches;
         let unit_name = matches.get_one::<String>("unit_name").unwrap();
 
-        let mut arg_set = ValueSet::new();
-
-        if let Some(occurences) = matches.get_occurrences::<String>("args") {
-            for occurence in occurences {
-                for value in occurence {
-                    let parts: Vec<&str> = value.split('=').collect();
-                    if parts.len() != 2 {
-                        return Err(anyhow!("Arguments must be in the form of KEY=VALUE"));
-                    } else {
-                        let arg_value = Value::from_string(parts[1]);
-                        arg_set.add_value(parts[0], arg_value);
-                    }
-                }
+        let mut root_args = args::RootArgs::default();
+
+        // Each source overrides those before it, so values from the file can be replaced
+        if let Some(path) = matches.get_one::<String>("args_file") {
+            for (key, value) in args::read_args_file(path)?.values {
+                root_args.add(&key, value)?;
             }
         }
 
+        for arg in self.get_all("arg_env") {
+            let (key, var) = arg.split_once('=')
+                .ok_or_else(|| anyhow!("Environment arguments must be in the form of KEY=ENVVAR or KEY:TYPE=ENVVAR"))?;
+            let text = std::env::var(var)
+                .map_err(|_| anyhow!("Environment variable {} for argument {} is not set", var, key))?;
+            root_args.add(key, Value::String(text))?;
+        }
+
+        for arg in self.get_all("arg_file") {
+            let (key, path) = arg.split_once('=')
+                .ok_or_else(|| anyhow!("File arguments must be in the form of KEY=PATH or KEY:TYPE=PATH"))?;
+            let text = std::fs::read_to_string(path)
+                .context(format!("Failed to read file {} for argument {}", path, key))?;
+            root_args.add(key, Value::String(args::file_text(&text).to_string()))?;
+        }
+
+        for arg in self.get_all("args") {
+            let (key, raw_value) = arg.split_once('=')
+                .ok_or_else(|| anyhow!("Arguments must be in the form of KEY=VALUE or KEY:TYPE=VALUE"))?;
+            root_args.add(key, Value::String(raw_value.to_string()))?;
+        }
+
         let target = match matches.get_one::<String>("target") {
             Some(t) => parse_target(t)?,
             None => Target::default(),
         };
 
-        Ok(Unit::new(unit_name.to_string(), arg_set, target))
+        Ok(Unit { raw_args: root_args.raw, ..Unit::new(unit_name.to_string(), root_args.values, target) })
+    }
+
+    /// Provides every value given for an option which can be repeated
+    fn get_all(&self, id: &str) -> Vec<&String> {
+        self.matches.get_many::<String>(id).map(|values| values.collect()).unwrap_or_default()
     }
 
     fn get_search_paths(&self) -> Result<Vec<PathBuf>> {
@@ -142,21 +298,32 @@ impl CLI {
     }
 }
 
+fn journal_path(state_dir: &PathBuf) -> std::path::PathBuf {
+    std::path::PathBuf::from(state_dir.join("journal.jsonl").as_os_str())
+}
+
 fn get_cli_definition() -> Command {
     Command::new("sysu")
         .version("1.0")
         .about("Applies idempotent state-changing shell scripts")
+        .disable_help_flag(true)
+        .arg(
+            Arg::new("help")
+                .help("Print help, or the params of the unit if one is given")
+                .action(clap::ArgAction::SetTrue)
+                .short('h')
+                .long("help"),
+        )
         .arg(
             Arg::new("operation")
                 .help("The operation to be applied")
-                .required(true)
-                .value_parser(["check", "apply", "remove", "meta"])
+                .required_unless_present("help")
+                .value_parser(["check", "apply", "remove", "meta", "bundle", "lock", "history"])
                 .index(1),
         )
         .arg(
             Arg::new("unit_name")
-                .help("The unit to be applied")
-                .required(true)
+                .help("The unit to be applied, or for history, the id of a run to show")
                 .index(2),
         )
         .arg(
@@ -212,6 +379,135 @@ fn get_cli_definition() -> Command {
                 .value_name("KEY=VALUE")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("args_file")
+                .help("TOML or JSON file of arguments to be passed to the unit")
+                .long("args-file")
+                .value_name("PATH")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("arg_env")
+                .help("Argument to be passed to the unit, read from an environment variable")
+                .long("arg-env")
+                .action(clap::ArgAction::Append)
+                .value_name("KEY=ENVVAR")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("arg_file")
+                .help("Argument to be passed to the unit, read from the contents of a file")
+                .long("arg-file")
+                .action(clap::ArgAction::Append)
+                .value_name("KEY=PATH")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("no_prompt")
+                .help("Fail instead of prompting for required arguments which weren't given")
+                .action(clap::ArgAction::SetTrue)
+                .long("no-prompt"),
+        )
+        .arg(
+            Arg::new("output")
+                .help("Path of the archive written by the bundle operation")
+                .long("output")
+                .short('o')
+                .value_name("PATH")
+                .default_value("bundle.tar.gz")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("lockfile")
+                .help("Path of the lockfile written by the lock operation and read with --locked")
+                .long("lockfile")
+                .value_name("PATH")
+                .default_value("sysunit.lock")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("locked")
+                .help("Refuse to run units whose scripts don't match the lockfile")
+                .action(clap::ArgAction::SetTrue)
+                .long("locked"),
+        )
+        .arg(
+            Arg::new("trusted_keys")
+                .help("File of ed25519 public keys which unit signatures are checked against")
+                .long("trusted-keys")
+                .value_name("PATH")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("require_signed")
+                .help("Refuse to run units without a valid signature from a trusted key")
+                .action(clap::ArgAction::SetTrue)
+                .long("require-signed"),
+        )
+        .arg(
+            Arg::new("state_dir")
+                .help("Directory where the units applied to each target are recorded")
+                .long("state-dir")
+                .value_name("PATH")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("no_state")
+                .help("Don't record the units which are applied or removed, or the run in the journal")
+                .action(clap::ArgAction::SetTrue)
+                .long("no-state")
+                .conflicts_with("state_dir"),
+        )
+        .arg(
+            Arg::new("prune")
+                .help("Remove units applied last time which are no longer in the unit's graph")
+                .action(clap::ArgAction::SetTrue)
+                .long("prune")
+                .conflicts_with("no_state"),
+        )
+        .arg(
+            Arg::new("resume")
+                .help("Resume a failed run, skipping the units it completed")
+                .long("resume")
+                .value_name("RUN_ID")
+                .num_args(1)
+                .conflicts_with_all(["no_state", "prune"]),
+        )
+        .arg(
+            Arg::new("recheck")
+                .help("Check the units completed by the resumed run again, rather than skipping them")
+                .action(clap::ArgAction::SetTrue)
+                .long("recheck")
+                .requires("resume"),
+        )
+        .arg(
+            Arg::new("unit")
+                .help("List only runs which ran this unit")
+                .long("unit")
+                .value_name("NAME")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("status")
+                .help("List only runs which succeeded or failed")
+                .long("status")
+                .value_parser(["success", "failed"])
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("since")
+                .help("List only runs started on or after this UTC date")
+                .long("since")
+                .value_name("YYYY-MM-DD")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("until")
+                .help("List only runs started on or before this UTC date")
+                .long("until")
+                .value_name("YYYY-MM-DD")
+                .num_args(1),
+        )
         .arg(
             Arg::new("adapter")
                 .help("Specify protocol and command in the form of <protocol>=<command>")
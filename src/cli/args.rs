@@ -0,0 +1,207 @@
+//! Reads arguments for the root unit from files of values, and gathers them from each
+//! place they can be given
+
+use crate::models::{Value, ValueSet, ValueType};
+use crate::parser::parse_typed_value;
+
+use anyhow::{anyhow, Context, Result};
+
+use std::collections::BTreeMap;
+
+/// Reads a TOML or JSON file of argument names and values, going by its extension.
+/// Strings are left for the caller to read as the type of their param.
+pub fn read_args_file(path: &str) -> Result<ValueSet> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Failed to read args file {}", path))?;
+
+    let parsed = if path.ends_with(".json") {
+        parse_json(&contents)
+    } else if path.ends_with(".toml") {
+        parse_toml(&contents)
+    } else {
+        Err(anyhow!("Args files must end in .toml or .json"))
+    };
+
+    parsed.context(format!("Failed to parse args file {}", path))
+}
+
+fn parse_json(contents: &str) -> Result<ValueSet> {
+    let entries: serde_json::Map<String, serde_json::Value> = serde_json::from_str(contents)?;
+    let mut args = ValueSet::new();
+    for (key, value) in entries {
+        let value = json_value(&key, value, true)?;
+        args.add_value(&key, value);
+    }
+    Ok(args)
+}
+
+fn parse_toml(contents: &str) -> Result<ValueSet> {
+    let entries: toml::Table = contents.parse()?;
+    let mut args = ValueSet::new();
+    for (key, value) in entries {
+        let value = toml_value(&key, value, true)?;
+        args.add_value(&key, value);
+    }
+    Ok(args)
+}
+
+/// Arguments for the root unit, gathered from all the places they can be given
+#[derive(Default)]
+pub struct RootArgs {
+    pub values: ValueSet,
+    /// Names of values given as text, which are read as the type of their param once
+    /// it's known
+    pub raw: Vec<String>,
+}
+
+impl RootArgs {
+    /// Adds a value under a key which may name its type, like `port:int`, in which case
+    /// text is read as that type straight away
+    pub fn add(&mut self, key: &str, value: Value) -> Result<()> {
+        let (key, value_type) = match key.split_once(':') {
+            Some((key, type_name)) => (key, Some(ValueType::from_str(type_name)?)),
+            None => (key, None),
+        };
+
+        self.raw.retain(|name| name != key);
+        let value = match (value, value_type) {
+            (Value::String(text), Some(value_type)) => parse_typed_value(&text, &value_type)
+                .context(format!("Invalid value for argument {}", key))?,
+            (Value::String(text), None) => {
+                self.raw.push(key.to_string());
+                Value::String(text)
+            },
+            (value, Some(value_type)) if value.get_type() != value_type => {
+                return Err(anyhow!("Argument {} is of type {}, not {}", key, value.get_type(), value_type));
+            },
+            (value, _) => value,
+        };
+        self.values.add_value(key, value);
+        Ok(())
+    }
+}
+
+/// Text of a file given as an argument, without the newline files nearly always end in,
+/// which isn't meant to be part of the value
+pub fn file_text(text: &str) -> &str {
+    text.strip_suffix('\n').map(|t| t.strip_suffix('\r').unwrap_or(t)).unwrap_or(text)
+}
+
+/// Converts a JSON value, which may only be a list or map at the top level, since values
+/// can't be nested
+fn json_value(key: &str, value: serde_json::Value, top: bool) -> Result<Value> {
+    use serde_json::Value as J;
+    match value {
+        J::String(s) => Ok(Value::String(s)),
+        J::Bool(b) => Ok(Value::Bool(b)),
+        J::Number(n) if n.is_f64() => Ok(Value::Float(n.as_f64().unwrap_or(f64::NAN) as f32)),
+        J::Number(n) => n.as_i64()
+            .and_then(|i| i32::try_from(i).ok())
+            .map(Value::Int)
+            .ok_or_else(|| anyhow!("Argument {} is too large for an int", key)),
+        J::Array(items) if top => Ok(Value::List(
+            items.into_iter().map(|item| json_value(key, item, false)).collect::<Result<_>>()?
+        )),
+        J::Object(entries) if top => Ok(Value::Map(
+            entries.into_iter()
+                .map(|(k, v)| Ok((k, json_value(key, v, false)?)))
+                .collect::<Result<BTreeMap<_, _>>>()?
+        )),
+        J::Null => Err(anyhow!("Argument {} is null", key)),
+        _ => Err(anyhow!("Argument {} has nested lists or maps, which aren't supported", key)),
+    }
+}
+
+fn toml_value(key: &str, value: toml::Value, top: bool) -> Result<Value> {
+    use toml::Value as T;
+    match value {
+        T::String(s) => Ok(Value::String(s)),
+        T::Boolean(b) => Ok(Value::Bool(b)),
+        T::Integer(i) => i32::try_from(i)
+            .map(Value::Int)
+            .map_err(|_| anyhow!("Argument {} is too large for an int", key)),
+        T::Float(f) => Ok(Value::Float(f as f32)),
+        T::Datetime(d) => Ok(Value::String(d.to_string())),
+        T::Array(items) if top => Ok(Value::List(
+            items.into_iter().map(|item| toml_value(key, item, false)).collect::<Result<_>>()?
+        )),
+        T::Table(entries) if top => Ok(Value::Map(
+            entries.into_iter()
+                .map(|(k, v)| Ok((k, toml_value(key, v, false)?)))
+                .collect::<Result<BTreeMap<_, _>>>()?
+        )),
+        _ => Err(anyhow!("Argument {} has nested lists or maps, which aren't supported", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args_files() {
+        let json = parse_json(r#"{"name": "web", "port": 8080, "ratio": 1.5, "debug": true, "pkgs": ["git", 2], "env": {"EDITOR": "vim"}}"#).unwrap();
+        let toml = parse_toml(r#"
+            name = "web"
+            port = 8080
+            ratio = 1.5
+            debug = true
+            pkgs = ["git", 2]
+            env = { EDITOR = "vim" }
+        "#).unwrap();
+
+        for args in [json, toml] {
+            assert_eq!(args.get("name"), Some(&Value::String("web".to_string())));
+            assert_eq!(args.get("port"), Some(&Value::Int(8080)));
+            assert_eq!(args.get("ratio"), Some(&Value::Float(1.5)));
+            assert_eq!(args.get("debug"), Some(&Value::Bool(true)));
+            assert_eq!(args.get("pkgs"), Some(&Value::List(vec![Value::String("git".to_string()), Value::Int(2)])));
+            assert_eq!(args.get("env").unwrap().to_string(), "{EDITOR=vim}");
+        }
+
+        assert!(parse_json(r#"{"pkgs": [["nested"]]}"#).is_err());
+        assert!(parse_toml("env = { a = { b = 1 } }").is_err());
+        assert!(parse_json(r#"["not", "a", "map"]"#).is_err());
+
+        // Ints too large for an int are refused rather than read as floats
+        assert!(parse_json(r#"{"size": 3000000000}"#).is_err());
+        assert!(parse_toml("size = 3000000000").is_err());
+        assert_eq!(parse_json(r#"{"size": 3.0}"#).unwrap().get("size"), Some(&Value::Float(3.0)));
+    }
+
+    #[test]
+    fn test_root_args() {
+        // Later sources replace earlier ones, and whether a value is raw goes with it
+        let mut args = RootArgs::default();
+        args.add("port", Value::Int(80)).unwrap();
+        args.add("port", Value::String("8080".to_string())).unwrap();
+        assert_eq!(args.values.get("port"), Some(&Value::String("8080".to_string())));
+        assert_eq!(args.raw, vec!["port".to_string()]);
+        args.add("port:int", Value::String("8081".to_string())).unwrap();
+        assert_eq!(args.values.get("port"), Some(&Value::Int(8081)));
+        assert!(args.raw.is_empty());
+
+        // As given by --arg-env or --arg-file, text with a type is read as it straight away
+        let mut args = RootArgs::default();
+        args.add("ratio:float", Value::String(file_text("1.5\n").to_string())).unwrap();
+        args.add("debug:bool", Value::String("true".to_string())).unwrap();
+        assert_eq!(args.values.get("ratio"), Some(&Value::Float(1.5)));
+        assert_eq!(args.values.get("debug"), Some(&Value::Bool(true)));
+        assert!(args.raw.is_empty());
+        assert!(args.add("port:int", Value::String("http".to_string())).is_err());
+        assert!(args.add("port:port", Value::String("80".to_string())).is_err());
+
+        // Values from args files must already be of the type named
+        assert!(args.add("name:string", Value::String("web".to_string())).is_ok());
+        assert!(args.add("pkgs:map", Value::List(vec![])).is_err());
+    }
+
+    #[test]
+    fn test_file_text() {
+        assert_eq!(file_text("secret\n"), "secret");
+        assert_eq!(file_text("secret\r\n"), "secret");
+        assert_eq!(file_text("secret"), "secret");
+        // Only the one final newline is taken off
+        assert_eq!(file_text("line one\nline two\n\n"), "line one\nline two\n");
+    }
+}
@@ -0,0 +1,210 @@
+//! Lists the runs kept in the journal, or shows everything one of them did
+
+use super::journal::{RunRecord, RunStatus, read_journal, format_time, days_from_civil};
+
+use anyhow::{anyhow, Result};
+use colored::*;
+
+use std::path::PathBuf;
+
+/// Narrows the runs which are listed
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Runs which ran a unit on this target
+    pub target: Option<String>,
+    /// Runs of this root unit, or which ran it as a dependency
+    pub unit: Option<String>,
+    pub status: Option<RunStatus>,
+    /// Runs started on or after this time, in seconds since the unix epoch
+    pub since: Option<u64>,
+    /// Runs started before this time, in seconds since the unix epoch
+    pub until: Option<u64>,
+}
+
+impl Filter {
+    fn matches(&self, run: &RunRecord) -> bool {
+        let started = run.started / 1000;
+
+        self.target.as_ref().is_none_or(|target| {
+            run.target == *target || run.ops.iter().any(|op| op.target == *target)
+        })
+        && self.unit.as_ref().is_none_or(|unit| {
+            run.unit == *unit || run.ops.iter().any(|op| op.name == *unit)
+        })
+        && self.status.is_none_or(|status| run.status == status)
+        && self.since.is_none_or(|since| started >= since)
+        && self.until.is_none_or(|until| started < until)
+    }
+}
+
+/// Reads a UTC date in the form YYYY-MM-DD as seconds since the unix epoch, at the start
+/// of the day, or the start of the next when `end` is set
+pub fn parse_date(date: &str, end: bool) -> Result<u64> {
+    let invalid = || anyhow!("Invalid date {}, dates must be in the form of YYYY-MM-DD", date);
+
+    let parts: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let year: i64 = year.parse().map_err(|_| invalid())?;
+    let month: u32 = month.parse().map_err(|_| invalid())?;
+    let day: u32 = day.parse().map_err(|_| invalid())?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    let days = days_from_civil(year, month, day) + end as i64;
+    u64::try_from(days * 86400).map_err(|_| invalid())
+}
+
+/// Lists runs in the journal which match the filter, oldest first
+pub fn list(journal: &PathBuf, filter: &Filter) -> Result<()> {
+    let runs: Vec<RunRecord> = read_journal(journal)?
+        .into_iter()
+        .filter(|run| filter.matches(run))
+        .collect();
+
+    if runs.is_empty() {
+        println!("No runs found");
+    }
+
+    for run in runs {
+        let status = match run.status {
+            RunStatus::Success => "success".green(),
+            RunStatus::Failed => "failed".red(),
+        };
+        println!(
+            "{}  {}  {:<10} {} {}@{}  {} ({})",
+            run.id.bold(),
+            format_time(run.started / 1000),
+            run.user,
+            run.operation,
+            root_tag(&run),
+            run.target,
+            status,
+            format_duration(run.duration_ms),
+        );
+    }
+
+    Ok(())
+}
+
+/// Shows everything a run did, with the output of each operation
+pub fn show(journal: &PathBuf, id: &str) -> Result<()> {
+    let run = read_journal(journal)?
+        .into_iter()
+        .find(|run| run.id == id)
+        .ok_or_else(|| anyhow!("No run {} found in the journal", id))?;
+
+    println!("{} {}", "Run".bold(), run.id.bold());
+    match run.claimed_user {
+        Some(ref claimed) => println!("User:     {} (claimed {})", run.user, claimed),
+        None => println!("User:     {}", run.user),
+    }
+    println!("Command:  {} {}@{}", run.operation, root_tag(&run), run.target);
+    println!("Started:  {} UTC", format_time(run.started / 1000));
+    println!("Duration: {}", format_duration(run.duration_ms));
+
+    for op in run.ops.iter() {
+        let result = match op.result.as_deref() {
+            Some("failed") => "failed".red(),
+            Some(result) => result.green(),
+            None => "unfinished".yellow(),
+        };
+        println!();
+        println!("[ {} | {}@{} ] {} ({})", op.operation, op.unit, op.target, result, format_duration(op.duration_ms));
+        for line in op.output.iter() {
+            println!("  {}", line);
+        }
+        if let Some(ref error) = op.error {
+            println!("  {}", error.red());
+        }
+    }
+
+    println!();
+    match run.status {
+        RunStatus::Success => println!("{}", "Success".green().bold()),
+        RunStatus::Failed => {
+            println!("{}", "Error".red().bold());
+            if let Some(ref error) = run.error {
+                println!("{}", error);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+fn root_tag(run: &RunRecord) -> String {
+    let args = run.args.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>();
+    format!("{}({})", run.unit, args.join(", "))
+}
+
+fn format_duration(ms: u64) -> String {
+    match ms {
+        0..=999 => format!("{}ms", ms),
+        1000..=59999 => format!("{:.1}s", ms as f64 / 1000.0),
+        _ => format!("{}m{}s", ms / 60000, ms % 60000 / 1000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::journal::OpRecord;
+    use std::collections::BTreeMap;
+
+    fn run(unit: &str, target: &str, status: RunStatus, started: u64, deps: &[&str]) -> RunRecord {
+        RunRecord {
+            id: format!("{}-{}", unit, started),
+            user: "admin".to_string(),
+            claimed_user: None,
+            operation: "apply".to_string(),
+            unit: unit.to_string(),
+            args: BTreeMap::new(),
+            target: target.to_string(),
+            started: started * 1000,
+            duration_ms: 10,
+            status,
+            error: None,
+            ops: deps.iter().map(|dep| OpRecord {
+                name: dep.to_string(),
+                unit: format!("{}()", dep),
+                target: target.to_string(),
+                operation: "check".to_string(),
+                result: Some("present".to_string()),
+                started: started * 1000,
+                duration_ms: 1,
+                output: Vec::new(),
+                error: None,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let day = parse_date("2026-10-18", false).unwrap();
+        assert_eq!(parse_date("2026-10-18", true).unwrap(), day + 86400);
+        assert!(parse_date("2026-13-01", false).is_err());
+        assert!(parse_date("yesterday", false).is_err());
+
+        let runs = [
+            run("site.sh", "ssh://db3", RunStatus::Success, day + 60, &["pkg.sh"]),
+            run("site.sh", "ssh://web1", RunStatus::Failed, day - 60, &[]),
+            run("db.sh", "ssh://db3", RunStatus::Failed, day + 86400, &[]),
+        ];
+        let matching = |filter: Filter| -> Vec<usize> {
+            runs.iter().enumerate().filter(|(_, run)| filter.matches(run)).map(|(i, _)| i).collect()
+        };
+
+        assert_eq!(matching(Filter::default()), vec![0, 1, 2]);
+        assert_eq!(matching(Filter { target: Some("ssh://db3".to_string()), ..Filter::default() }), vec![0, 2]);
+        assert_eq!(matching(Filter { unit: Some("pkg.sh".to_string()), ..Filter::default() }), vec![0]);
+        assert_eq!(matching(Filter { status: Some(RunStatus::Failed), ..Filter::default() }), vec![1, 2]);
+        assert_eq!(matching(Filter {
+            since: Some(day),
+            until: Some(parse_date("2026-10-18", true).unwrap()),
+            ..Filter::default()
+        }), vec![0]);
+    }
+}
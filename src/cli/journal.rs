@@ -0,0 +1,376 @@
+//! The journal keeps a record of every run, appended to a file in the state directory
+//! as each one finishes, so it can be shown later with `sysu history`.
+
+use crate::events::{Event, Observer, OpEvent};
+use crate::models::{Unit, Operation, OpCompletion, StdoutData};
+
+use anyhow::{Context, Result};
+use serde::{Serialize, Deserialize};
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether a run succeeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Success,
+    Failed,
+}
+
+/// A run of sysunit, with everything it did to each unit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub id: String,
+    /// Name of the user sysunit ran as, from its uid
+    pub user: String,
+    /// Who the environment says ran sysunit, from `$SUDO_USER` or `$USER`, when it isn't
+    /// `user`. Anyone can set these, so it's kept apart from the real user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claimed_user: Option<String>,
+    pub operation: String,
+    pub unit: String,
+    /// Args of the root unit as they're displayed, with secrets redacted
+    pub args: BTreeMap<String, String>,
+    pub target: String,
+    /// Milliseconds since the unix epoch
+    pub started: u64,
+    pub duration_ms: u64,
+    pub status: RunStatus,
+    pub error: Option<String>,
+    pub ops: Vec<OpRecord>,
+}
+
+/// An operation run on a unit, along with the output it gave
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpRecord {
+    pub name: String,
+    pub unit: String,
+    pub target: String,
+    pub operation: String,
+    /// How the operation finished, like `ok`, `present`, `absent` or `failed`, which is
+    /// unset if the run ended while it was underway
+    pub result: Option<String>,
+    pub started: u64,
+    pub duration_ms: u64,
+    pub output: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// Observes a run, and appends it to the journal when it ends
+pub struct Journal {
+    path: PathBuf,
+    run: RunRecord,
+    /// Id of the unit the last operation was run on, as it was in its events
+    last_unit: Option<String>,
+    /// Names of the args of the unit each operation was run on
+    op_arg_names: Vec<Vec<String>>,
+}
+
+/// Name of the user sysunit is running as, or their uid if it has no name
+fn real_user() -> String {
+    let uid = nix::unistd::getuid();
+    match nix::unistd::User::from_uid(uid) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    }
+}
+
+/// The user the environment claims ran sysunit, preferring the one sudo was run by,
+/// when it isn't the real user
+fn claimed_user(real: &str, sudo_user: Option<String>, user: Option<String>) -> Option<String> {
+    sudo_user
+        .or(user)
+        .filter(|claimed| !claimed.is_empty() && claimed != real)
+}
+
+/// Generates an id for a run from the time it started, which sorts in the order runs started
+pub fn new_run_id() -> String {
+    format!("{}-{:04x}", format_compact(now_ms() / 1000), rand::random::<u16>())
+}
+
+impl Journal {
+    pub fn new(path: PathBuf, operation: Operation, id: String) -> Self {
+        let started = now_ms();
+        let user = real_user();
+        let claimed_user = claimed_user(&user, std::env::var("SUDO_USER").ok(), std::env::var("USER").ok());
+
+        let run = RunRecord {
+            id,
+            user,
+            claimed_user,
+            operation: operation.to_string(),
+            unit: String::new(),
+            args: BTreeMap::new(),
+            target: String::new(),
+            started,
+            duration_ms: 0,
+            status: RunStatus::Success,
+            error: None,
+            ops: Vec::new(),
+        };
+
+        Self { path, run, last_unit: None, op_arg_names: Vec::new() }
+    }
+
+    fn handle_op(&mut self, unit: &Unit, record: OpRecord, op_ev: OpEvent) {
+        let unit_id = unit.get_id();
+        let same_op = self.last_unit.as_ref() == Some(&unit_id)
+            && self.run.ops.last().is_some_and(|last| last.operation == record.operation && last.result.is_none());
+        if !same_op {
+            self.run.ops.push(record);
+            self.op_arg_names.push(arg_names(unit));
+            self.last_unit = Some(unit_id);
+        }
+
+        let op = self.run.ops.last_mut().unwrap();
+        match op_ev {
+            OpEvent::Started => (),
+            OpEvent::Output(StdoutData::TextLine(line)) => op.output.push(line),
+            OpEvent::Output(StdoutData::Stderr(line)) => op.output.push(line),
+            OpEvent::Output(StdoutData::Binary(bytes)) => op.output.push(format!("<{} bytes of binary output>", bytes.len())),
+            OpEvent::Output(StdoutData::Message(message)) => op.output.push(format!("{} | {}", message.header, message.text)),
+            OpEvent::AdapterStderr(line) => op.output.push(line),
+            OpEvent::TransportingFile(file) => op.output.push(format!("Transporting {} to {}", file.src, file.dest)),
+            OpEvent::FileTransported(_) => (),
+            OpEvent::Complete(completion) => {
+                op.result = Some(match completion {
+                    OpCompletion::Check(true) => "present",
+                    OpCompletion::Check(false) => "absent",
+                    _ => "ok",
+                }.to_string());
+                op.duration_ms = now_ms().saturating_sub(op.started);
+            },
+            OpEvent::Error(msg) => {
+                op.result = Some("failed".to_string());
+                op.error = Some(msg);
+                op.duration_ms = now_ms().saturating_sub(op.started);
+            },
+        }
+    }
+
+    fn append(&mut self) -> Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        self.run.duration_ms = now_ms().saturating_sub(self.run.started);
+        let line = serde_json::to_string(&self.run)?;
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)
+            .context(format!("Failed to open journal {:?}", self.path))?;
+        file.write_all(format!("{}\n", line).as_bytes())
+            .context(format!("Failed to write journal {:?}", self.path))
+    }
+}
+
+impl Observer for Journal {
+    fn handle(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Op(unit, op, op_ev) => {
+                let record = OpRecord {
+                    name: unit.name.clone(),
+                    unit: unit.tag(),
+                    target: unit.target.to_string(),
+                    operation: op.to_string(),
+                    result: None,
+                    started: now_ms(),
+                    duration_ms: 0,
+                    output: Vec::new(),
+                    error: None,
+                };
+                self.handle_op(&unit, record, op_ev);
+            },
+            Event::Finished(unit) => {
+                // The root's args may only have been known as secret after its first
+                // operations, so those are labelled as it is now
+                let (tag, target, names) = (unit.tag(), unit.target.to_string(), arg_names(&unit));
+                for (op, op_arg_names) in self.run.ops.iter_mut().zip(self.op_arg_names.iter()) {
+                    if op.name == unit.name && op.target == target && *op_arg_names == names {
+                        op.unit = tag.clone();
+                    }
+                }
+
+                self.run.unit = unit.name.clone();
+                self.run.args = unit.args.values.iter().map(|(k, v)| (k.clone(), v.to_string())).collect();
+                self.run.target = unit.target.to_string();
+            },
+            Event::EngineSuccess => self.append()?,
+            Event::Error(msg) => {
+                self.run.status = RunStatus::Failed;
+                self.run.error = Some(msg);
+                self.append()?;
+            },
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads every run in the journal, oldest first
+pub fn read_journal(path: &PathBuf) -> Result<Vec<RunRecord>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("Failed to read journal {:?}", path)),
+    };
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| serde_json::from_str(line)
+            .context(format!("Failed to parse line {} of journal {:?}", i + 1, path)))
+        .collect()
+}
+
+fn arg_names(unit: &Unit) -> Vec<String> {
+    let mut names: Vec<String> = unit.args.values.keys().cloned().collect();
+    names.sort();
+    names
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Splits seconds since the unix epoch into a UTC date and time
+pub fn civil_time(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Converts days since the epoch into a proleptic Gregorian date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Gives the number of days since the unix epoch of a UTC date
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Formats seconds since the unix epoch as a UTC date and time
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day, hour, min, sec) = civil_time(secs);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, min, sec)
+}
+
+fn format_compact(secs: u64) -> String {
+    let (year, month, day, hour, min, sec) = civil_time(secs);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}", year, month, day, hour, min, sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ValueSet, Value, Target, EmitMessage, emit::Header};
+    use crate::testing::TempDir;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(1792355328), "2026-10-18 20:28:48");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(days_from_civil(2026, 10, 18) * 86400, 1792281600);
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+    }
+
+    #[test]
+    fn test_users() {
+        let real = real_user();
+        assert!(!real.is_empty());
+        let uid = nix::unistd::getuid();
+        if let Ok(Some(user)) = nix::unistd::User::from_uid(uid) {
+            assert_eq!(real, user.name);
+        }
+
+        let some = |name: &str| Some(name.to_string());
+        assert_eq!(claimed_user("root", some("alice"), some("root")), some("alice"));
+        assert_eq!(claimed_user("deploy", None, some("alice")), some("alice"));
+        assert_eq!(claimed_user("deploy", None, some("deploy")), None);
+        assert_eq!(claimed_user("deploy", some(""), None), None);
+        assert_eq!(claimed_user("deploy", None, None), None);
+    }
+
+    #[test]
+    fn test_journal() {
+        let dir = TempDir::new("journal");
+        let path = dir.join("journal.jsonl");
+
+        let mut args = ValueSet::new();
+        args.add_value("name", Value::String("git".to_string()));
+        let pkg = Arc::new(Unit::new("pkg.sh".to_string(), args, Target::default()));
+
+        // The failing run only learns its arg is secret after it has started
+        let mut redacted_args = ValueSet::new();
+        redacted_args.add_value("name", Value::Secret("git".to_string()));
+        let redacted = Arc::new(Unit::new("pkg.sh".to_string(), redacted_args, Target::default()));
+
+        for fail in [false, true] {
+            let mut journal = Journal::new(path.clone(), Operation::Apply, new_run_id());
+            let events = vec![
+                Event::Resolving,
+                Event::Op(pkg.clone(), Operation::Check, OpEvent::Started),
+                Event::Op(pkg.clone(), Operation::Check, OpEvent::Complete(OpCompletion::Check(false))),
+                Event::Op(pkg.clone(), Operation::Apply, OpEvent::Output(StdoutData::TextLine("installing".to_string()))),
+                Event::Op(pkg.clone(), Operation::Apply, OpEvent::Output(StdoutData::Message(EmitMessage {
+                    header: Header::build("value", Some("version")),
+                    text: "2.4".to_string(),
+                }))),
+                match fail {
+                    false => Event::Op(pkg.clone(), Operation::Apply, OpEvent::Complete(OpCompletion::Apply)),
+                    true => Event::Op(pkg.clone(), Operation::Apply, OpEvent::Error("exit 1".to_string())),
+                },
+                Event::Finished(if fail { redacted.clone() } else { pkg.clone() }),
+                match fail {
+                    false => Event::EngineSuccess,
+                    true => Event::Error("Failed to apply unit pkg.sh".to_string()),
+                },
+            ];
+            for event in events {
+                journal.handle(event).unwrap();
+            }
+        }
+
+        let runs = read_journal(&path).unwrap();
+        assert_eq!(runs.len(), 2);
+
+        let run = &runs[0];
+        assert_eq!(run.status, RunStatus::Success);
+        assert_eq!((run.unit.as_str(), run.args["name"].as_str(), run.target.as_str()), ("pkg.sh", "git", "local://localhost"));
+        assert_eq!(run.ops.len(), 2);
+        assert_eq!(run.ops[0].result.as_deref(), Some("absent"));
+        assert_eq!(run.ops[1].result.as_deref(), Some("ok"));
+        assert_eq!(run.ops[1].output, vec!["installing", "value.version | 2.4"]);
+
+        let run = &runs[1];
+        assert_eq!(run.status, RunStatus::Failed);
+        assert_eq!(run.error.as_deref(), Some("Failed to apply unit pkg.sh"));
+        assert_eq!(run.ops[1].error.as_deref(), Some("exit 1"));
+        assert_eq!(run.args["name"], "****");
+        assert!(run.ops.iter().all(|op| op.unit == "pkg.sh(name=****)"));
+        assert_ne!(runs[0].id, runs[1].id);
+        assert_eq!(runs[0].user, real_user());
+    }
+}
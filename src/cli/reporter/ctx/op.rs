@@ -75,7 +75,7 @@ impl Ctx {
         use V::*;
         match (&self.state, &op_e) {
             (Root, OpE::Started) => (),
-            (Root, OpE::Output(StdoutData::TextLine(_))) => {
+            (Root, OpE::Output(StdoutData::TextLine(_) | StdoutData::Binary(_) | StdoutData::Stderr(_))) => {
                 if self.v >= Verbose {
                     self.enter_state(Output);
                     self.handle_op_ev(op_e)
@@ -96,6 +96,12 @@ impl Ctx {
             (Output, OpE::Output(StdoutData::TextLine(o))) => {
                 self.out.ln(o);
             },
+            (Output, OpE::Output(StdoutData::Binary(o))) => {
+                self.out.ln(&format!("{} {}", "(binary)".dimmed(), String::from_utf8_lossy(o)));
+            },
+            (Output, OpE::Output(StdoutData::Stderr(o))) => {
+                self.out.ln(&format!("{}", o.red()));
+            },
             (EmitData, OpE::Output(StdoutData::Message(m))) => {
                 let header = match &m.header.field {
                     Some(s) => &format!("{}.{}", m.header.name, s),
@@ -107,6 +113,12 @@ impl Ctx {
                 self.enter_state(Root);
                 self.handle_op_ev(op_e);
             },
+            // Adapter errors are shown regardless of verbosity, since they usually mean
+            // the target couldn't be reached at all.  They can arrive in any state, such as
+            // ssh reporting the connection closed after an error.
+            (_, OpE::AdapterStderr(line)) => {
+                self.out.ln(&format!("{} {}", "adapter:".red().bold(), line));
+            },
             (Root, OpE::Complete(op_completion)) => {
                 match op_completion {
                     OpCompletion::Check(present) => {
@@ -124,8 +136,8 @@ impl Ctx {
             (TransportingFile, OpE::TransportingFile(f)) => {
                 self.out.ln(&format!("Transporting file: {} -> {}", f.src, f.dest));
             }
-            (TransportingFile, OpE::FileTransported(_)) => {
-                self.out.ln(&format!("{}", "OK".green().bold()));
+            (TransportingFile, OpE::FileTransported(f)) => {
+                self.out.ln(&format!("{} {}", "OK".green().bold(), f.dest));
             }
             (_, OpE::TransportingFile(_)) => {
                 self.enter_state(State::TransportingFile);
@@ -153,7 +165,7 @@ impl Ctx {
                 self.enter_state(Error);
                 self.out.indent_all(&format!("{}", msg.red()));
             }
-            // The error state can only handle error events
+            // The error state can only handle error events and adapter output
             (_, _) => unreachable!(),
         }
     }
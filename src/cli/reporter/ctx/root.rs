@@ -1,5 +1,5 @@
 use super::*;
-use crate::models::UnitArc;
+use crate::models::{UnitArc, Meta, Param, version::Version};
 
 pub struct Ctx {
     state: State,
@@ -12,6 +12,7 @@ enum State {
     Loading(load::Ctx),
     ExecutionPlan,
     Running(ex_section::Ctx),
+    Help,
     Final
 }
 
@@ -24,6 +25,7 @@ impl Display for State {
             Loading(_) => "Loading",
             ExecutionPlan => "ExecutionPlan",
             Running(_) => "Running",
+            Help => "Help",
             Final => "Final",
         })
     }
@@ -37,7 +39,7 @@ impl Ctx {
     pub fn handle(&mut self, ev: E) {
         use State::*;
         match (&mut self.state, &ev) {
-            (Root, E::Resolving) => self.enter_load(),
+            (Root, E::Resolving | E::Describing) => self.enter_load(),
             (Loading(ctx), E::Op(..)) => ctx.handle(ev),
             (Loading(load_ctx), E::Resolved(units)) => {
                 load_ctx.report_ok();
@@ -46,12 +48,36 @@ impl Ctx {
                 self.ex_plan(units);
 
             },
+            (ExecutionPlan, E::Resuming(run_id, units)) => {
+                self.out.ln(&format!("Completed in run {}:", run_id));
+                for (i, unit) in units.iter().enumerate() {
+                    self.out.ln(&format!("{}. {}@{}", i + 1, unit.tag(), unit.target));
+                }
+            },
+            (ExecutionPlan, E::Pruning(units)) => {
+                self.out.ln("Pruning:");
+                for (i, unit) in units.iter().enumerate() {
+                    self.out.ln(&format!("{}. {}@{}", i + 1, unit.tag(), unit.target));
+                }
+            },
             (ExecutionPlan, E::Op(..)) => {
                 self.out.dedent();
                 self.enter_running();
                 self.handle(ev)
             }
             (Running(ctx), E::Op(..)) => ctx.handle(ev),
+            (Loading(load_ctx), E::Described(unit, meta)) => {
+                load_ctx.report_ok();
+                self.out.dedent();
+                self.enter_state(Help);
+                self.describe(unit, meta);
+            },
+            (_, E::Bundled(path, count)) => {
+                self.out.ln(&format!("Bundled {} units into {}", count, path));
+            },
+            (_, E::Locked(path, count)) => {
+                self.out.ln(&format!("Locked {} units in {}", count, path));
+            },
             (Final, E::EngineSuccess) => {
                 self.out.ln(&format!("{}", "Success".green().bold()));
             },
@@ -59,11 +85,15 @@ impl Ctx {
                 self.out.ln(&format!("{}", "Error".red().bold()));
                 self.out.ln(msg);
             },
+            (_, E::Finished(_)) => (),
             (_, E::Debug(msg)) => {
                 if self.v >= V::Debug {
                     self.out.ln(msg);
                 }
             }
+            (_, E::Warning(msg)) => {
+                self.out.ln(&format!("{} {}", "Warning".yellow().bold(), msg));
+            },
             (_, E::EngineSuccess | E::Error(_)) => {
                 self.out.dedent();
                 self.enter_state(Final);
@@ -73,13 +103,43 @@ impl Ctx {
         }
     }
 
-    fn ex_plan(&self, units: &Vec<UnitArc>) {
-        fn unit_str(unit: &UnitArc) -> String {
-            format!("{}@{}", unit.tag(), unit.target)
+    fn ex_plan(&self, units: &[(UnitArc, Option<Version>)]) {
+        fn unit_str(unit: &UnitArc, version: &Option<Version>) -> String {
+            match version {
+                Some(version) => format!("{}@{} (version {})", unit.tag(), unit.target, version),
+                None => format!("{}@{}", unit.tag(), unit.target),
+            }
+        }
+
+        for (i, (unit, version)) in units.iter().enumerate() {
+            self.out.ln(&format!("{}. {}", i + 1, unit_str(unit, version)));
+        }
+    }
+
+    fn describe(&self, unit: &UnitArc, meta: &Meta) {
+        match meta.version {
+            Some(ref version) => self.out.ln(&format!("{} (version {})", unit.name.bold(), version)),
+            None => self.out.ln(&format!("{}", unit.name.bold())),
+        }
+        if let Some(ref desc) = meta.desc {
+            self.out.ln(desc);
+        }
+        if let Some(ref author) = meta.author {
+            self.out.ln(&format!("Author: {}", author));
+        }
+        self.params("Params", &meta.params);
+        self.params("Emits", &meta.emits);
+    }
+
+    /// Lists params with their types, constraints and defaults, with descriptions aligned after them
+    fn params(&self, title: &str, params: &[Param]) {
+        if params.is_empty() {
+            return;
         }
 
-        for (i, unit) in units.iter().enumerate() {
-            self.out.ln(&format!("{}. {}", i + 1, unit_str(&unit)));
+        self.out.ln(&format!("{}:", title));
+        for line in param_lines(params) {
+            self.out.ln(&line);
         }
     }
 
@@ -109,3 +169,37 @@ impl Ctx {
         self.enter_state(State::Loading(ctx));
     }
 }
+
+/// Lays out params one to a line, with descriptions lined up after the longest of them
+fn param_lines(params: &[Param]) -> Vec<String> {
+    let specs = params.iter().map(Param::to_string).collect::<Vec<_>>();
+    let width = specs.iter().map(|spec| spec.chars().count()).max().unwrap_or(0);
+
+    params.iter().zip(specs)
+        .map(|(param, spec)| match param.desc {
+            Some(ref desc) => format!("  {:width$}  {}", spec, desc.dimmed(), width = width),
+            None => format!("  {}", spec),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_params;
+
+    #[test]
+    fn test_param_lines() {
+        let mut params = parse_params("!name:string, port:int = 8080, env:string").unwrap();
+        params[0].desc = Some("Package to install".to_string());
+        params[1].desc = Some("Port to listen on".to_string());
+
+        // Descriptions line up after the longest spec, even one without a description
+        assert_eq!(param_lines(&params), vec![
+            format!("  !name:string   {}", "Package to install".dimmed()),
+            format!("  port:int=8080  {}", "Port to listen on".dimmed()),
+            "  env:string".to_string(),
+        ]);
+        assert!(param_lines(&[]).is_empty());
+    }
+}
@@ -12,6 +12,11 @@ mod shell_executor;
 mod runner;
 mod executor_pool;
 mod transport;
+mod bundle;
+mod lockfile;
+mod prompt;
+mod state;
+mod prune;
 
 pub use resolver::ResolvableNode;
 
@@ -19,23 +24,58 @@ use crate::models::{UnitArc, Operation};
 use crate::events::{Event, EventHandler, ObserverArc};
 
 use tracing::instrument;
-use std::{fmt, sync::Arc, collections::HashMap};
+use std::{fmt, sync::Arc, collections::{HashMap, BTreeMap}};
 
-use loader::Loader;
-use resolver::resolve;
+use loader::{Loader, Script, Verifier};
+use resolver::{resolve, DependencyFetcher};
+use bundle::write_bundle;
+use lockfile::Lockfile;
+use state::{StateStore, UnitStatus, GraphEntry, Checkpoint};
+use prune::{stale_entries, load_pruned};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_std::path::PathBuf;
 use runner::Runner;
 
+/// What the engine should do with the unit it is given
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Run an operation on the unit
+    Run(Operation),
+    /// Resolve the unit's dependency graph and write the scripts it uses to an archive
+    Bundle(PathBuf),
+    /// Resolve the unit's dependency graph and record the scripts it uses in a lockfile
+    Lock(PathBuf),
+    /// Describe the unit's params and other metadata
+    Help,
+}
+
 #[derive(Debug)]
 pub struct Opts {
     pub remove_deps: bool,
     pub debug: bool,
     pub search_paths: Vec<PathBuf>,
-    pub operation: Operation,
+    pub action: Action,
     pub unit: UnitArc,
     pub adapters: HashMap<String, String>,
+    /// When set, units may only run if their scripts match this lockfile
+    pub lockfile: Option<PathBuf>,
+    /// File of public keys that unit signatures are checked against
+    pub trusted_keys: Option<PathBuf>,
+    /// Refuse to load any unit script or unitfile without a valid signature
+    pub require_signed: bool,
+    /// Ask for required arguments of the root unit which weren't given
+    pub prompt: bool,
+    /// Directory where the units applied to each target are recorded
+    pub state_dir: Option<PathBuf>,
+    /// Remove units the root unit applied last time which are no longer in its graph
+    pub prune: bool,
+    /// Identifies this run in the journal and its checkpoint
+    pub run_id: String,
+    /// Id of a failed run to resume, skipping the units it completed
+    pub resume: Option<String>,
+    /// Check units completed by the resumed run again, rather than skipping them
+    pub recheck: bool,
 }
 
 #[derive(Clone)]
@@ -47,6 +87,7 @@ pub struct Context {
 pub struct Engine {
     runner: Runner,
     ev_handler: EventHandler,
+    state: Option<StateStore>,
     opts: Arc<Opts>,
 }
 
@@ -58,48 +99,85 @@ impl fmt::Debug for Engine {
 }
 
 impl Engine {
-    pub fn new(opts: Opts, observers: Vec<ObserverArc>) -> Engine {
-        let loader = Loader::from_search_paths(opts.search_paths.clone());
+    pub fn new(opts: Opts, observers: Vec<ObserverArc>) -> Result<Engine> {
         let ev_handler = EventHandler::new(observers);
+        let unit = opts.unit.clone();
+
+        // A run which can't be started is reported, and journalled, like one which failed
+        match Self::build(opts, ev_handler.clone()) {
+            Ok(engine) => Ok(engine),
+            Err(e) => {
+                // The error which stopped the run is the one to give back, even if
+                // reporting it fails as well
+                let _ = report_outcome(&ev_handler, unit, Some(&e));
+                Err(e)
+            },
+        }
+    }
+
+    fn build(opts: Opts, ev_handler: EventHandler) -> Result<Engine> {
+        let verifier = Verifier::load(opts.trusted_keys.as_ref(), opts.require_signed)?;
+        let loader = Loader::from_search_paths(opts.search_paths.clone(), verifier);
+        let lockfile = match opts.lockfile {
+            Some(ref path) => Some(Lockfile::load(path)?),
+            None => None,
+        };
+        // Only runs which apply or remove units record them, so nothing else needs the state
+        // directory to be writable
+        let records = matches!(opts.action, Action::Run(Operation::Apply | Operation::Remove));
+        let state = match opts.state_dir {
+            Some(ref dir) if records => Some(StateStore::open(dir)?),
+            None if opts.prune => return Err(anyhow!("Units can only be pruned when state is kept")),
+            _ => None,
+        };
         let opts = Arc::new(opts);
         let ctx = Context {
             opts: opts.clone(),
             ev_handler: ev_handler.clone(),
         };
-        let runner = Runner::new(loader, ctx);
+        let runner = Runner::new(loader, lockfile, ctx);
 
-        Engine {
+        Ok(Engine {
             ev_handler,
             runner,
+            state,
             opts,
-        }
+        })
     }
 
     pub async fn run(&mut self) -> Result<()> {
-        let op = self.opts.operation;
         let unit = self.opts.unit.clone();
 
-        let result = match op {
-            Operation::Check => self.run_unit(unit, op).await,
-            Operation::Apply => self.run_with_dependencies(unit, op).await,
-            Operation::Remove => {
-                if self.opts.remove_deps {
-                    self.run_with_dependencies(unit.clone(), op).await
-                } else {
-                    self.run_unit(unit, op).await
-                }
+        let result = match self.opts.action.clone() {
+            Action::Run(op) => match op {
+                Operation::Check => self.run_unit(unit, op).await,
+                Operation::Apply => self.run_with_dependencies(unit, op).await,
+                Operation::Remove => {
+                    if self.opts.remove_deps {
+                        self.run_with_dependencies(unit.clone(), op).await
+                    } else {
+                        self.run_unit(unit, op).await
+                    }
+                },
+                _ => panic!("Operation {:?} can't be run directly", op),
             },
-            _ => panic!("Operation {:?} can't be run directly", op),
+            Action::Bundle(path) => self.bundle(unit, &path).await,
+            Action::Lock(path) => self.lock(unit, &path).await,
+            Action::Help => self.describe(unit).await,
         };
 
-        self.runner.finalize().await?;
-
-        let finalization_event = match result {
-            Ok(_) => Event::EngineSuccess,
-            Err(ref e) => Event::Error(format!("{:#}", e)),
+        // A run whose executors can't be finalized is still reported, and failed if it
+        // hadn't already
+        let finalized = self.runner.finalize().await;
+        let result = match (result, &finalized) {
+            (Ok(_), Err(e)) => Err(anyhow!("Failed to finalize executors: {:#}", e)),
+            (result, _) => result,
         };
 
-        self.ev_handler.handle(finalization_event)?;
+        // The root is reported with its args as they were typed, once they have been
+        let root = self.runner.root().cloned().unwrap_or_else(|| self.opts.unit.clone());
+        report_outcome(&self.ev_handler, root, result.as_ref().err())?;
+        finalized?;
 
         // Any errors have been sent to the event handler for display, so we can
         // return OK upstream
@@ -109,17 +187,235 @@ impl Engine {
     async fn run_with_dependencies(&mut self, unit: UnitArc, op: Operation) -> Result<()> {
         self.ev_handler.handle(Event::Resolving)?;
 
-        let ordered_units = resolve(unit, &mut self.runner).await?;
+        // Args given as text are read as their declared types first, so the root is
+        // identified and recorded the same as a dependency on it would be
+        let unit = self.runner.type_root(unit).await?;
+
+        // A resumed run takes its order from the checkpoint of the failed one
+        let (ordered_units, mut checkpoint) = match (op, self.opts.resume.clone()) {
+            (Operation::Apply, Some(run_id)) => self.resume(&unit, &run_id).await?,
+            _ => {
+                let ordered_units = resolve(unit.clone(), &mut self.runner).await?;
+                let order = ordered_units.iter().map(|unit| self.graph_entry(unit)).collect();
+                (ordered_units, Checkpoint::new(&self.opts.run_id, &unit, order))
+            },
+        };
+        let skipped: Vec<UnitArc> = ordered_units.iter()
+            .filter(|unit| checkpoint.completed.contains_key(&unit.fingerprint()))
+            .cloned()
+            .collect();
+
+        // Units from the last apply which have left the graph are loaded with it, so any
+        // problem with them is found before anything runs
+        let stale = match op {
+            Operation::Apply => self.stale_units(&unit, &ordered_units)?,
+            _ => Vec::new(),
+        };
+        let pruning = load_pruned(stale, self.opts.prune, &mut self.runner).await?;
+        if self.opts.prune {
+            for entry in pruning.kept.iter() {
+                self.ev_handler.handle(Event::Warning(format!(
+                    "Unit {} can't be pruned since its secret args weren't recorded, it must be removed by hand",
+                    entry.name,
+                )))?;
+            }
+        }
+        let pruned = pruning.units;
+
+        self.ev_handler.handle(self.resolved_event(&ordered_units))?;
+
+        if let Some(ref run_id) = self.opts.resume {
+            self.ev_handler.handle(Event::Resuming(run_id.clone(), skipped.clone()))?;
+        }
+        if !pruned.is_empty() {
+            let units = pruned.iter().map(|(unit, _)| unit.clone()).collect();
+            self.ev_handler.handle(Event::Pruning(units))?;
+        }
+        for (stale_unit, graph) in pruned {
+            self.prune(stale_unit, graph).await?;
+        }
+
+        // The checkpoint is kept from the start, so a run which fails on its first unit can
+        // still be resumed
+        if let (Operation::Apply, Some(ref state)) = (op, &self.state) {
+            state.save_checkpoint(&checkpoint)?;
+        }
+
+        for unit in ordered_units.iter() {
+            if skipped.contains(unit) {
+                continue;
+            }
+            self.run_unit(unit.clone(), op).await?;
+
+            // Each unit is checkpointed as it completes, so a failed apply can be resumed
+            if let (Operation::Apply, Some(ref state)) = (op, &self.state) {
+                checkpoint.complete(unit, self.runner.get_emit_data(unit).unwrap());
+                state.save_checkpoint(&checkpoint)?;
+            }
+        }
 
-        self.ev_handler.handle(Event::Resolved(ordered_units.clone()))?;
+        if let (Operation::Apply, Some(ref state)) = (op, &self.state) {
+            state.remove_checkpoint(&checkpoint.id)?;
+            if let Some(ref run_id) = self.opts.resume {
+                state.remove_checkpoint(run_id)?;
+            }
 
-        for unit in ordered_units {
-            self.run_unit(unit, op).await?;
+            // Stale units which weren't pruned are still applied, so they're kept in the
+            // graph to be pruned later
+            let graph = pruning.kept.into_iter().chain(checkpoint.order).collect();
+            state.record_graph(&unit, graph)?;
         }
 
         Ok(())
     }
 
+    /// Loads the checkpoint of a failed run, and the units in the order it gave them.
+    /// Units it completed are given the values they emitted rather than being loaded,
+    /// unless they're to be checked again, and the rest are loaded as they were.
+    async fn resume(&mut self, root: &UnitArc, run_id: &str) -> Result<(Vec<UnitArc>, Checkpoint)> {
+        let state = self.state.as_ref()
+            .ok_or_else(|| anyhow!("Runs can only be resumed when state is kept"))?;
+        let previous = state.load_checkpoint(run_id)?;
+
+        if previous.root != root.fingerprint() {
+            return Err(anyhow!(
+                "Run {} applied {} with other arguments or on another target, so it can't be resumed with {}",
+                run_id,
+                previous.unit,
+                root.label(),
+            ));
+        }
+
+        let built: Result<Vec<UnitArc>> = previous.order.iter()
+            .map(|entry| match entry.fingerprint == previous.root {
+                true => Ok(root.clone()),
+                false => entry.to_unit().map(UnitArc::new),
+            })
+            .collect();
+        let ordered_units = match built {
+            Ok(units) => units,
+            // Units with secret args can't be built from the checkpoint, so the graph is
+            // resolved again to find them
+            Err(_) => resolve(root.clone(), &mut self.runner).await?,
+        };
+
+        let mut checkpoint = Checkpoint::new(&self.opts.run_id, root, previous.order.clone());
+        for unit in ordered_units.iter() {
+            match previous.emitted(unit)? {
+                Some(emitted) if !self.opts.recheck => {
+                    self.runner.restore(unit.clone(), emitted).await?;
+                    checkpoint.complete(unit, self.runner.get_emit_data(unit).unwrap());
+                },
+                _ => self.runner.prepare(unit.clone()).await?,
+            }
+        }
+
+        // Units which run again check the versions and emits of their dependencies, which
+        // may have been restored rather than loaded
+        for unit in ordered_units.iter().filter(|unit| !checkpoint.completed.contains_key(&unit.fingerprint())) {
+            self.runner.get_node_dependencies(unit.clone()).await?;
+        }
+
+        // Units loaded again are recorded as they are now
+        for entry in checkpoint.order.iter_mut() {
+            if let Some(unit) = ordered_units.iter().find(|unit| unit.fingerprint() == entry.fingerprint) {
+                if !checkpoint.completed.contains_key(&entry.fingerprint) {
+                    *entry = self.graph_entry(unit);
+                }
+            }
+        }
+
+        Ok((ordered_units, checkpoint))
+    }
+
+    /// Describes a unit for the graph of its root, with args its params declare secret redacted
+    fn graph_entry(&self, unit: &UnitArc) -> GraphEntry {
+        GraphEntry::from_unit(unit, &self.runner.get_args(unit).unwrap_or_default())
+    }
+
+    /// Provides the units in the graph the root unit applied last which aren't in its graph
+    /// now, in the order they ran.  Units in the graph of another root unit are left alone.
+    fn stale_units(&self, root: &UnitArc, ordered_units: &[UnitArc]) -> Result<Vec<GraphEntry>> {
+        let state = match self.state {
+            Some(ref state) => state,
+            None => return Ok(Vec::new()),
+        };
+
+        let held = state.units_of_other_roots(root)?;
+        Ok(stale_entries(state.previous_graph(root)?, ordered_units, &held))
+    }
+
+    /// Removes a unit which has left the graph, after checking its dependencies so any
+    /// values it captures from them are available
+    async fn prune(&mut self, unit: UnitArc, graph: Vec<UnitArc>) -> Result<()> {
+        for dep_unit in graph.iter().filter(|dep_unit| **dep_unit != unit) {
+            self.runner.check(dep_unit.clone()).await?;
+        }
+        self.run_unit(unit, Operation::Remove).await
+    }
+
+    /// Resolves the unit's dependencies, and writes the script for every unit in
+    /// the graph to a bundle archive
+    async fn bundle(&mut self, unit: UnitArc, path: &PathBuf) -> Result<()> {
+        let scripts = self.resolve_scripts(unit.clone()).await?;
+        let manifest = write_bundle(path, &unit.name, &scripts)?;
+
+        self.ev_handler.handle(Event::Bundled(path.to_string_lossy().to_string(), manifest.units.len()))?;
+
+        Ok(())
+    }
+
+    /// Resolves the unit's dependencies, and records the script for every unit in
+    /// the graph in a lockfile
+    async fn lock(&mut self, unit: UnitArc, path: &PathBuf) -> Result<()> {
+        let scripts = self.resolve_scripts(unit.clone()).await?;
+        let lockfile = Lockfile::from_scripts(&unit.name, &scripts);
+        lockfile.write(path)?;
+
+        self.ev_handler.handle(Event::Locked(path.to_string_lossy().to_string(), lockfile.units.len()))?;
+
+        Ok(())
+    }
+
+    /// Runs the unit's meta operation so its params can be shown
+    async fn describe(&mut self, unit: UnitArc) -> Result<()> {
+        self.ev_handler.handle(Event::Describing)?;
+
+        let meta = self.runner.describe(unit.clone()).await?;
+
+        self.ev_handler.handle(Event::Described(unit, meta))?;
+
+        Ok(())
+    }
+
+    /// Resolves the unit's dependency graph, providing the script each unit was loaded
+    /// from, keyed by its name tagged with the version it resolved to
+    async fn resolve_scripts(&mut self, unit: UnitArc) -> Result<BTreeMap<String, Script>> {
+        self.ev_handler.handle(Event::Resolving)?;
+
+        let unit = self.runner.type_root(unit).await?;
+        let ordered_units = resolve(unit, &mut self.runner).await?;
+
+        self.ev_handler.handle(self.resolved_event(&ordered_units))?;
+
+        let mut scripts = BTreeMap::new();
+        for dep_unit in ordered_units.iter() {
+            let script = self.runner.get_script(dep_unit)
+                .ok_or_else(|| anyhow!("Resolved unit {} was not loaded", dep_unit.label()))?;
+            scripts.insert(script.tagged_name(&dep_unit.name), script.clone());
+        }
+
+        Ok(scripts)
+    }
+
+    fn resolved_event(&self, units: &[UnitArc]) -> Event {
+        let units = units
+            .iter()
+            .map(|unit| (unit.clone(), self.runner.get_version(unit).cloned()))
+            .collect();
+        Event::Resolved(units)
+    }
+
     #[instrument]
     async fn run_unit(&mut self, unit: UnitArc, op: Operation) -> Result<()> {
         async fn do_op(runner: &mut Runner, unit: UnitArc, op: Operation) -> Result<()> {
@@ -143,7 +439,187 @@ impl Engine {
             Ok(())
         }
 
-        let result = do_op(&mut self.runner, unit.clone(), op).await;
-        return result;
+        do_op(&mut self.runner, unit.clone(), op).await?;
+        self.record(&unit, op)
+    }
+
+    /// Records that the unit is now applied or removed in the state store
+    fn record(&self, unit: &UnitArc, op: Operation) -> Result<()> {
+        let status = match op {
+            Operation::Apply => UnitStatus::Applied,
+            Operation::Remove => UnitStatus::Removed,
+            _ => return Ok(()),
+        };
+
+        if let Some(ref state) = self.state {
+            let script = self.runner.get_script(unit)
+                .unwrap_or_else(|| panic!("Recorded unit was not loaded: {:?}", unit));
+            let args = self.runner.get_args(unit).unwrap();
+            let emitted = self.runner.get_emit_data(unit).unwrap();
+            state.record(unit, status, script, &args, emitted)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reports that the engine is done with the root unit, and whether the run succeeded
+fn report_outcome(ev_handler: &EventHandler, root: UnitArc, error: Option<&anyhow::Error>) -> Result<()> {
+    ev_handler.handle(Event::Finished(root))?;
+
+    let finalization_event = match error {
+        None => Event::EngineSuccess,
+        Some(e) => Event::Error(format!("{:#}", e)),
+    };
+
+    ev_handler.handle(finalization_event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Observer, OpEvent};
+    use crate::models::{Unit, Target, Value, ValueSet, val::secret_salt};
+    use crate::parser::parse_version;
+    use crate::testing::TempDir;
+    use async_std::task::block_on;
+    use std::sync::Mutex;
+
+    /// Keeps every event the engine sends
+    #[derive(Default)]
+    struct Recorder(Vec<Event>);
+
+    impl Observer for Recorder {
+        fn handle(&mut self, event: Event) -> Result<()> {
+            self.0.push(event);
+            Ok(())
+        }
+    }
+
+    fn opts(dir: &TempDir, unit: Unit) -> Opts {
+        Opts {
+            remove_deps: false,
+            debug: false,
+            search_paths: vec![PathBuf::from(dir.path())],
+            action: Action::Run(Operation::Apply),
+            unit: Arc::new(unit),
+            adapters: HashMap::new(),
+            lockfile: None,
+            trusted_keys: None,
+            require_signed: false,
+            prompt: false,
+            state_dir: None,
+            prune: false,
+            run_id: "20261018T120000-0000".to_string(),
+            resume: None,
+            recheck: false,
+        }
+    }
+
+    fn run(opts: Opts, recorder: &Arc<Mutex<Recorder>>) -> Engine {
+        let mut engine = Engine::new(opts, vec![recorder.clone()]).unwrap();
+        block_on(engine.run()).unwrap();
+        engine
+    }
+
+    /// Provides the error the last run ended with, if any
+    fn last_outcome(recorder: &Arc<Mutex<Recorder>>) -> Option<String> {
+        recorder.lock().unwrap().0.iter().rev()
+            .find_map(|event| match event {
+                Event::EngineSuccess => Some(None),
+                Event::Error(e) => Some(Some(e.clone())),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn apply(dir: &TempDir, run_id: &str, resume: Option<&str>, recorder: &Arc<Mutex<Recorder>>) -> Engine {
+        let opts = Opts {
+            state_dir: Some(PathBuf::from(dir.join("state"))),
+            run_id: run_id.to_string(),
+            resume: resume.map(str::to_string),
+            ..opts(dir, Unit::new("top.sh".to_string(), ValueSet::new(), Target::default()))
+        };
+        run(opts, recorder)
+    }
+
+    #[test]
+    fn test_root_secret_args() {
+        let dir = TempDir::new("engine");
+        std::fs::write(dir.join("db.sh"), "meta() { params '!pw:secret'; }\ncheck() { echo \"$pw\" >/dev/null; }\n").unwrap();
+
+        // A secret given as text isn't shown before the root's params say it's secret,
+        // even by its meta op or the shell's trace of it
+        let mut args = ValueSet::new();
+        args.add_value("pw", Value::String("hunter2".to_string()));
+        let unit = Unit { raw_args: vec!["pw".to_string()], ..Unit::new("db.sh".to_string(), args, Target::default()) };
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        run(Opts { debug: true, ..opts(&dir, unit) }, &recorder);
+        assert_eq!(last_outcome(&recorder), None);
+
+        let events = &recorder.lock().unwrap().0;
+        assert!(events.iter().any(|event| matches!(event, Event::Op(unit, Operation::Meta, OpEvent::Started) if unit.tag() == "db.sh()")));
+        for event in events.iter() {
+            // Units are shown by their tags, in which secrets are redacted
+            let text = match event {
+                Event::Op(unit, _, op_event) => format!("{} {:?}", unit.tag(), op_event),
+                Event::Resolved(units) => units.iter().map(|(unit, _)| unit.tag()).collect(),
+                Event::Finished(unit) => unit.tag(),
+                event => format!("{:?}", event),
+            };
+            assert!(!text.contains("hunter2"), "secret shown by {}", text);
+        }
+    }
+    #[test]
+    fn test_state_only_opened_to_record() {
+        let dir = TempDir::new("engine");
+        std::fs::write(dir.join("pkg.sh"), "meta() { desc 'Installs a package'; }\n").unwrap();
+        // A file where the state directory should be, so it can't be opened
+        std::fs::write(dir.join("state"), "").unwrap();
+
+        let unit = || Unit::new("pkg.sh".to_string(), ValueSet::new(), Target::default());
+        let state_dir = Some(PathBuf::from(dir.join("state")));
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        run(Opts { action: Action::Help, state_dir: state_dir.clone(), ..opts(&dir, unit()) }, &recorder);
+        assert_eq!(last_outcome(&recorder), None);
+
+        assert!(Engine::new(Opts { state_dir, ..opts(&dir, unit()) }, vec![recorder.clone()]).is_err());
+        assert!(last_outcome(&recorder).unwrap().contains("Failed to create state directory"));
+    }
+
+    #[test]
+    fn test_resume_versioned_dep() {
+        let dir = TempDir::new("engine");
+        let (marker, log) = (dir.join("ok"), dir.join("log"));
+        let base = |version: &str| format!(
+            "meta() {{ version \"{}\"; emits 'path:string'; }}\ncheck() {{ echo base >> {}; emit_value path /opt/base; present; }}\n",
+            version,
+            log.display(),
+        );
+        std::fs::write(dir.join("base.sh"), base("1.2")).unwrap();
+        std::fs::write(dir.join("flaky.sh"), format!("apply() {{ [ -f {} ]; }}\n", marker.display())).unwrap();
+        std::fs::write(dir.join("top.sh"), "deps() { dep flaky.sh; dep 'base.sh:1.2'; }\ncheck() { present; }\n").unwrap();
+
+        // Every engine in the process hashes secrets with the same salt
+        std::fs::create_dir_all(dir.join("state")).unwrap();
+        std::fs::write(dir.join("state/salt"), format!("{:016x}\n", secret_salt())).unwrap();
+
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        apply(&dir, "20261018T120000-0001", None, &recorder);
+        assert!(last_outcome(&recorder).unwrap().contains("flaky.sh"));
+
+        // Dependencies restored from the checkpoint still have their versions checked
+        std::fs::write(dir.join("base.sh"), base("1.1")).unwrap();
+        apply(&dir, "20261018T120000-0002", Some("20261018T120000-0001"), &recorder);
+        assert!(last_outcome(&recorder).unwrap().contains("requires base.sh version 1.2, but version 1.1 was found"));
+
+        // and once they satisfy their dependents, they aren't run again
+        std::fs::write(dir.join("base.sh"), base("1.2")).unwrap();
+        std::fs::write(&marker, "").unwrap();
+        let engine = apply(&dir, "20261018T120000-0003", Some("20261018T120000-0001"), &recorder);
+        assert_eq!(last_outcome(&recorder), None);
+        let base_unit = Arc::new(Unit::new("base.sh".to_string(), ValueSet::new(), Target::default()));
+        assert_eq!(engine.runner.get_version(&base_unit), Some(&parse_version("1.2").unwrap()));
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "base\n");
     }
 }
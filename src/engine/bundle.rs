@@ -0,0 +1,144 @@
+//! Bundles package the unit scripts used by a dependency graph into a single
+//! tar archive, which can be placed on the search path of another machine.
+//! A manifest of content hashes is included so the loader can detect any
+//! members which were altered after the bundle was written.
+
+use anyhow::{Result, Context};
+use async_std::path::PathBuf;
+use flate2::{write::GzEncoder, Compression};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use super::loader::{Script, Manifest, ManifestEntry, MANIFEST_NAME, SIGNATURE_EXT, is_gzipped, member_path};
+
+/// Writes the given scripts, keyed by unit name tagged with any version, to an archive
+/// at `path`.  The archive is gzipped if `path` ends in `.tar.gz` or `.tgz`.
+pub fn write_bundle(path: &PathBuf, root: &str, scripts: &BTreeMap<String, Script>) -> Result<Manifest> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut units = Vec::new();
+
+    for (name, script) in scripts.iter() {
+        // Versioned scripts keep their tag, so they're still selected by version from the bundle
+        let name = member_path(std::path::Path::new(name))?;
+        append_member(&mut builder, &name, script.body.as_bytes())?;
+        // Signatures are carried along, so the bundle can be loaded with --require-signed
+        if let Some(ref signature) = script.signature {
+            append_member(&mut builder, &format!("{}.{}", name, SIGNATURE_EXT), signature)?;
+        }
+        units.push(ManifestEntry { name, hash: script.hash() });
+    }
+
+    let manifest = Manifest { root: root.to_string(), units };
+    append_member(&mut builder, MANIFEST_NAME, &serde_json::to_vec_pretty(&manifest)?)?;
+
+    let tar_bytes = builder.into_inner()?;
+    let bytes = if is_gzipped(path) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tar_bytes)?;
+        encoder.finish()?
+    } else {
+        tar_bytes
+    };
+
+    std::fs::write(path, bytes).context(format!("Failed to write bundle: {:?}", path))?;
+
+    Ok(manifest)
+}
+
+fn append_member(builder: &mut tar::Builder<Vec<u8>>, name: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    // Members are written with a fixed mtime so identical graphs produce identical bundles
+    header.set_mtime(0);
+    header.set_cksum();
+    builder.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::loader::{Loader, Verifier};
+    use crate::models::version::{VersionSpecification, Comparator};
+    use crate::parser::parse_version;
+    use crate::testing::TempDir;
+    use async_std::task::block_on;
+
+    fn script(body: &str) -> Script {
+        Script { origin: "test".to_string(), body: body.to_string(), version: None, signature: None }
+    }
+
+    fn versioned(body: &str, version: &str) -> Script {
+        Script { version: Some(parse_version(version).unwrap()), ..script(body) }
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let dir = TempDir::new("bundle");
+        let path = PathBuf::from(dir.join("units.tar.gz"));
+
+        let mut scripts = BTreeMap::new();
+        scripts.insert("foo.sh".to_string(), script("apply() touch /tmp/foo;\n"));
+        scripts.insert("./lib/bar.sh".to_string(), script("apply() touch /tmp/bar;\n"));
+        scripts.insert("units.sysu/baz.sh".to_string(), script("check() present;\n"));
+
+        let manifest = write_bundle(&path, "foo.sh", &scripts).unwrap();
+        assert_eq!(manifest.units.len(), 3);
+        assert_eq!(manifest.units[0].name, "lib/bar.sh");
+
+        let loader = Loader::from_search_paths(vec![path], Verifier::default());
+        let foo = block_on(loader.load("foo.sh", None)).unwrap();
+        assert_eq!(foo.body, "apply() touch /tmp/foo;\n");
+        let bar = block_on(loader.load("./lib/bar.sh", None)).unwrap();
+        assert_eq!(bar.body, "apply() touch /tmp/bar;\n");
+        let baz = block_on(loader.load("units.sysu/baz.sh", None)).unwrap();
+        assert_eq!(baz.body, "check() present;\n");
+        assert!(block_on(loader.load("missing.sh", None)).is_err());
+    }
+
+    #[test]
+    fn test_bundle_versions() {
+        let dir = TempDir::new("bundle");
+        let path = PathBuf::from(dir.join("units.tar"));
+
+        // Two deps on one unit which resolved to different versions are both bundled
+        let (v1, v2) = (versioned("echo 1\n", "1"), versioned("echo 2\n", "2"));
+        let mut scripts = BTreeMap::new();
+        scripts.insert(v1.tagged_name("pkg.sh"), v1);
+        scripts.insert(v2.tagged_name("pkg.sh"), v2);
+
+        let manifest = write_bundle(&path, "pkg.sh", &scripts).unwrap();
+        let names: Vec<&str> = manifest.units.iter().map(|unit| unit.name.as_str()).collect();
+        assert_eq!(names, vec!["pkg.sh@1", "pkg.sh@2"]);
+
+        let loader = Loader::from_search_paths(vec![path], Verifier::default());
+        let spec = VersionSpecification { comparator: Some(Comparator::LessThan), version: parse_version("2").unwrap() };
+        assert_eq!(block_on(loader.load("pkg.sh", Some(&spec))).unwrap().body, "echo 1\n");
+        assert_eq!(block_on(loader.load("pkg.sh", None)).unwrap().body, "echo 2\n");
+    }
+
+    #[test]
+    fn test_bundle_signatures() {
+        use ed25519_dalek::{SigningKey, Signer};
+
+        let dir = TempDir::new("bundle");
+        let path = PathBuf::from(dir.join("units.tar"));
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let signed = script("apply() touch /tmp/foo;\n");
+        let signed = Script { signature: Some(key.sign(signed.body.as_bytes()).to_bytes().to_vec()), ..signed };
+
+        let mut scripts = BTreeMap::new();
+        scripts.insert("foo.sh".to_string(), signed);
+        scripts.insert("bar.sh".to_string(), script("apply() touch /tmp/bar;\n"));
+        write_bundle(&path, "foo.sh", &scripts).unwrap();
+
+        // Signed scripts can be loaded when signatures are required, but scripts without
+        // one, such as those from unitfiles, can't
+        let loader = Loader::from_search_paths(vec![path], Verifier::new(vec![key.verifying_key()], true));
+        assert_eq!(block_on(loader.load("foo.sh", None)).unwrap().body, "apply() touch /tmp/foo;\n");
+        let err = block_on(loader.load("bar.sh", None)).unwrap_err();
+        assert!(format!("{:#}", err).contains("Missing signature"));
+    }
+}
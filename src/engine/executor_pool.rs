@@ -1,7 +1,8 @@
 //! Spawns and manages executors that run scripts on targets
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use async_std::sync::Mutex;
 use crate::models::Target;
 
 use anyhow::Result;
@@ -39,7 +40,7 @@ impl ExecutorPool {
             match Arc::try_unwrap(executor) {
                 Ok(mutex) => {
                     // Now get the inner executor
-                    let executor = mutex.into_inner().unwrap();
+                    let executor = mutex.into_inner();
                     executor.finalize().await?;
                 }
                 Err(_) => {
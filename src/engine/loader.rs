@@ -2,51 +2,132 @@
 
 use async_std::path::PathBuf;
 use anyhow::{Result, anyhow};
+use sha1::{Sha1, Digest};
 use std::collections::HashMap;
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use async_std::sync::Mutex;
+
+use crate::models::version::{Version, VersionSpecification};
+use crate::parser::parse_version;
 
 mod unitfile;
+mod archive;
+mod signature;
 use unitfile::UnitFile;
+use archive::{Archive, is_archive};
+pub use signature::SIGNATURE_EXT;
+
+pub use archive::{Manifest, ManifestEntry, MANIFEST_NAME, is_gzipped, member_path};
+pub use signature::Verifier;
 
 type NodeArc = Arc<Mutex<Node>>;
 type NodeResult = Result<Option<NodeArc>>;
 
+/// The body of a unit script, along with the file it was read from
+#[derive(Debug, Clone)]
+pub struct Script {
+    pub origin: String,
+    pub body: String,
+    /// Version the script was tagged with in its file name or unitfile header
+    pub version: Option<Version>,
+    /// Detached signature of the script's own file.  Scripts from unitfiles have none,
+    /// since their signature covers the whole unitfile.
+    pub signature: Option<Vec<u8>>,
+}
+
+impl Script {
+    /// Provides the SHA1sum of the script body
+    pub fn hash(&self) -> String {
+        content_hash(self.body.as_bytes())
+    }
+
+    /// Tags a unit name with the version of the script it resolved to, so scripts for
+    /// different versions of one unit can be told apart
+    pub fn tagged_name(&self, name: &str) -> String {
+        match self.version {
+            Some(ref version) => format!("{}@{}", name, version),
+            None => name.to_string(),
+        }
+    }
+}
+
+/// Provides the SHA1sum of file contents, used to detect changes to units
+pub fn content_hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(bytes))
+}
+
 /// Finds and loads scripts from the set of search paths its instantiated with
 pub struct Loader {
     search_paths: Vec<NodeArc>,
 }
 
 impl Loader {
-    pub fn from_search_paths(search_paths: Vec<PathBuf>) -> Self {
+    pub fn from_search_paths(search_paths: Vec<PathBuf>, verifier: Verifier) -> Self {
+        let verifier = Arc::new(verifier);
         let search_paths = search_paths
             .into_iter()
-            .map(|p| Node::Directory(Dir::new(p)).into() )
+            .map(|p| {
+                if is_archive(&p) {
+                    Node::Archive(Archive::new(p, verifier.clone())).into()
+                } else {
+                    Node::Directory(Dir::new(p, verifier.clone())).into()
+                }
+            })
             .collect::<Vec<NodeArc>>();
         Self { search_paths }
     }
 
-    pub async fn load(&self, loc: &str) -> Result<String> {
-        match self.search(loc).await? {
-            Some(node) => {
-                let locked_node = node.lock().unwrap();
-                match &*locked_node {
-                    Node::Script(script) => Ok(script.clone()),
-                    _ => Err(anyhow!("{} is not a unit script", loc)),
-                }
-            },
-            None => Err(anyhow!("Could not find script at location: {}", loc)),
+    /// Loads the script at the given location.  Several versions of a unit may sit side by
+    /// side, tagged like `nginx.sh@2.1.0`, in which case the one selected depends on the
+    /// version specification given.
+    pub async fn load(&self, loc: &str, spec: Option<&VersionSpecification>) -> Result<Script> {
+        let path = PathBuf::from(loc);
+        let name = path.file_name().and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Invalid unit location: {}", loc))?;
+        let parent = path.parent().map(PathBuf::from).unwrap_or_default();
+        let mut unmatched_versions = Vec::new();
+
+        for search_path in &self.search_paths {
+            let parent_node = match self.search_node(search_path.clone(), &parent).await? {
+                Some(node) => node,
+                None => continue,
+            };
+
+            let mut locked_parent = parent_node.lock().await;
+            let entries = locked_parent.entry_names().await?;
+            let (entry, version) = match select_entry(&entries, name, spec) {
+                Some(selected) => selected,
+                None => {
+                    unmatched_versions.extend(entries.iter().filter_map(|entry| match split_version(entry) {
+                        (entry_name, Some(version)) if entry_name == name => Some(version.to_string()),
+                        _ => None,
+                    }));
+                    continue;
+                },
+            };
+
+            let node = locked_parent.search(&entry).await?
+                .ok_or_else(|| anyhow!("Could not find script at location: {}", loc))?;
+            let locked_node = node.lock().await;
+            return match &*locked_node {
+                Node::Script(script) => Ok(Script { version, ..script.clone() }),
+                _ => Err(anyhow!("{} is not a unit script", loc)),
+            };
         }
-    }
 
-    async fn search(&self, loc: &str) -> NodeResult {
-        for node in &self.search_paths {
-            let path = PathBuf::from(loc);
-            if let Some(node) = self.search_node(node.clone(), &path).await? {
-                return Ok(Some(node));
-            }
+        unmatched_versions.sort();
+        unmatched_versions.dedup();
+
+        match spec {
+            Some(spec) if !unmatched_versions.is_empty() => Err(anyhow!(
+                "No version of {} matches {}, found versions: {}",
+                loc,
+                spec,
+                unmatched_versions.join(", "),
+            )),
+            _ => Err(anyhow!("Could not find script at location: {}", loc)),
         }
-        Ok(None)
     }
 
     async fn search_node(&self, node: NodeArc, path: &PathBuf) -> NodeResult {
@@ -55,7 +136,7 @@ impl Loader {
         for component in path.iter() {
             let name = component.to_str().map(String::from);
             cur_node = if let Some(name) = name {
-                let mut locked_node = cur_node.lock().unwrap();
+                let mut locked_node = cur_node.lock().await;
                 if let Some(node) = locked_node.search(&name).await? {
                     node
                 } else {
@@ -71,24 +152,75 @@ impl Loader {
     }
 }
 
-/// Loading is modeled as a graph traversal. A Node can be a directory which
-/// contains scripts and unitfiles, unitfiles, which contain scripts, and scripts
+/// Loading is modeled as a graph traversal. A Node can be a directory or archive
+/// which contains scripts and unitfiles, unitfiles, which contain scripts, and scripts
 /// which should be terminal nodes.
 #[derive(Debug)]
 enum Node {
     Directory(Dir),
+    Archive(Archive),
     UnitFile(UnitFile),
-    Script(String),
+    Script(Script),
 }
 
 impl Node {
     pub async fn search(&mut self, loc: &str) -> NodeResult {
         match self {
             Node::Directory(dir) => dir.search(loc).await,
+            Node::Archive(archive) => archive.search(loc).await,
             Node::UnitFile(uf) => get_script_from_unitfile(uf, loc).await,
             Node::Script(_) => todo!("errr script cant be searched"),
         }
     }
+
+    /// Lists the names of the entries which can be searched for within this node
+    pub async fn entry_names(&mut self) -> Result<Vec<String>> {
+        match self {
+            Node::Directory(dir) => dir.entry_names().await,
+            Node::Archive(archive) => archive.entry_names(),
+            Node::UnitFile(uf) => Ok(uf.names()),
+            Node::Script(_) => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Picks the entry a unit name refers to.  With a version specification, the highest
+/// matching version is chosen, falling back to the untagged entry whose version is checked
+/// once it's loaded.  Without one, the untagged entry is preferred over the highest version.
+fn select_entry(entries: &[String], name: &str, spec: Option<&VersionSpecification>) -> Option<(String, Option<Version>)> {
+    let untagged = entries.iter().find(|entry| *entry == name).map(|entry| (entry.clone(), None));
+
+    let highest = entries
+        .iter()
+        .filter_map(|entry| match split_version(entry) {
+            (entry_name, Some(version)) if entry_name == name => Some((entry, version)),
+            _ => None,
+        })
+        .filter(|(_, version)| spec.is_none_or(|spec| spec.matches(version)))
+        .max_by_key(|(_, version)| version.components())
+        .map(|(entry, version)| (entry.clone(), Some(version)));
+
+    match spec {
+        Some(_) => highest.or(untagged),
+        None => untagged.or(highest),
+    }
+}
+
+/// Splits a tagged entry name like `nginx.sh@2.1.0` into the unit name and its version
+fn split_version(entry: &str) -> (&str, Option<Version>) {
+    match entry.split_once('@') {
+        Some((name, version)) => match parse_version(version) {
+            Ok(version) => (name, Some(version)),
+            Err(_) => (entry, None),
+        },
+        None => (entry, None),
+    }
+}
+
+/// Provides the extension of a unit's file, ignoring any version tag
+fn unit_extension(path: &PathBuf) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    split_version(name).0.rsplit_once('.').map(|(_, ext)| ext)
 }
 
 impl From<Node> for NodeArc {
@@ -104,34 +236,50 @@ fn node_res(node: Node) -> NodeResult {
 #[derive(Debug)]
 struct Dir {
     location: PathBuf,
+    verifier: Arc<Verifier>,
     children: HashMap<String, NodeArc>,
 }
 
 impl Dir {
-    pub fn new(location: PathBuf) -> Self {
-        Self { location, children: HashMap::new(), }
+    pub fn new(location: PathBuf, verifier: Arc<Verifier>) -> Self {
+        Self { location, verifier, children: HashMap::new(), }
     }
 
     pub async fn search(&mut self, name: &str) -> NodeResult {
-        if let Some(node) = self.children.get(&name.to_string()) {
+        if let Some(node) = self.children.get(name) {
             return Ok(Some(node.clone()));
         }
 
+        let node = self.load_child(name).await?;
+
+        if let Some(ref node) = node {
+            self.children.insert(name.to_string(), node.clone());
+        }
+
+        Ok(node)
+    }
+
+    async fn load_child(&self, name: &str) -> NodeResult {
         let path = self.location.join(name);
 
         if path.is_dir().await {
-            return node_res(Node::Directory(Dir::new(path)))
+            return node_res(Node::Directory(Dir::new(path, self.verifier.clone())))
         };
 
         if path.is_file().await {
-            let ext = path.extension().map(|e| e.to_str()).unwrap_or(None);
-            return match ext {
+            if is_archive(&path) {
+                return node_res(Node::Archive(Archive::new(path, self.verifier.clone())));
+            }
+
+            return match unit_extension(&path) {
                 Some("sysu") => {
-                    let unitfile = UnitFile::load(path.clone()).await?;
+                    let (bytes, _) = self.read_verified(&path).await?;
+                    let unitfile = UnitFile::from_bytes(path.clone(), &bytes).await?;
                     node_res(Node::UnitFile(unitfile))
                 },
                 Some("sh") => {
-                    let script = load_script(&path)?;
+                    let (bytes, signature) = self.read_verified(&path).await?;
+                    let script = script_from_bytes(&path, bytes, signature)?;
                     node_res(Node::Script(script))
                 },
                 _ => Err(anyhow!("Invalid file extension on file: {}", display_path(&path))),
@@ -140,11 +288,44 @@ impl Dir {
 
         Ok(None)
     }
+
+    pub async fn entry_names(&self) -> Result<Vec<String>> {
+        if !self.location.is_dir().await {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.location)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Reads a file, checking it against the detached signature beside it, which is
+    /// provided along with it
+    async fn read_verified(&self, path: &PathBuf) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        use std::fs;
+
+        let bytes = fs::read(path)?;
+        let sig_path = PathBuf::from(format!("{}.{}", display_path(path), SIGNATURE_EXT));
+        let signature = if sig_path.is_file().await {
+            Some(fs::read(&sig_path)?)
+        } else {
+            None
+        };
+
+        self.verifier.verify(&display_path(path), &bytes, signature.as_deref())?;
+        Ok((bytes, signature))
+    }
 }
 
-fn load_script(path: &PathBuf) -> Result<String> {
-    use std::fs;
-    fs::read_to_string(path).map_err(|e| e.into())
+fn script_from_bytes(path: &PathBuf, bytes: Vec<u8>, signature: Option<Vec<u8>>) -> Result<Script> {
+    let body = String::from_utf8(bytes)
+        .map_err(|_| anyhow!("Unit script is not valid UTF-8: {}", display_path(path)))?;
+    Ok(Script { origin: display_path(path), body, version: None, signature })
 }
 
 fn display_path(path: &PathBuf) -> String {
@@ -153,7 +334,47 @@ fn display_path(path: &PathBuf) -> String {
 
 async fn get_script_from_unitfile(uf: &mut UnitFile, loc: &str) -> NodeResult {
     match uf.get(loc).await {
-        Some(script) => Ok(Some(Node::Script(script).into())),
+        Some(body) => Ok(Some(Node::Script(Script { origin: uf.display_path(), body, version: None, signature: None }).into())),
         None => Err(anyhow!("Could not find script: {} in unitfile: {}", loc, uf.display_path())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_version;
+    use crate::models::version::Comparator;
+
+    fn spec(comparator: Option<Comparator>, version: &str) -> VersionSpecification {
+        VersionSpecification { comparator, version: parse_version(version).unwrap() }
+    }
+
+    #[test]
+    fn test_select_entry() {
+        let entries = ["nginx.sh", "nginx.sh@1.4.0", "nginx.sh@2.1.0", "nginx.sh@2.3", "nginx.sh.sig", "other.sh@9"]
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect::<Vec<String>>();
+
+        let selected = |spec: Option<&VersionSpecification>| select_entry(&entries, "nginx.sh", spec).unwrap().0;
+
+        assert_eq!(selected(None), "nginx.sh");
+        assert_eq!(selected(Some(&spec(None, "2"))), "nginx.sh@2.3");
+        assert_eq!(selected(Some(&spec(Some(Comparator::LessThan), "2.2"))), "nginx.sh@2.1.0");
+        assert_eq!(selected(Some(&spec(None, "1"))), "nginx.sh@1.4.0");
+        // Falls back to the untagged script, whose declared version is checked later
+        assert_eq!(selected(Some(&spec(Some(Comparator::GreaterThan), "3"))), "nginx.sh");
+
+        let tagged_only = &entries[1..];
+        assert_eq!(select_entry(tagged_only, "nginx.sh", None).unwrap().0, "nginx.sh@2.3");
+        assert!(select_entry(tagged_only, "nginx.sh", Some(&spec(None, "3"))).is_none());
+        assert!(select_entry(tagged_only, "missing.sh", None).is_none());
+    }
+
+    #[test]
+    fn test_unit_extension() {
+        assert_eq!(unit_extension(&PathBuf::from("lib/nginx.sh@2.1.0")), Some("sh"));
+        assert_eq!(unit_extension(&PathBuf::from("units.sysu")), Some("sysu"));
+        assert_eq!(unit_extension(&PathBuf::from("README")), None);
+    }
+}
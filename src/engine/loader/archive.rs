@@ -0,0 +1,296 @@
+//! Tar archives, optionally gzipped, can be placed on the search path or inside
+//! of a searched directory.  They're read into memory the first time they are
+//! searched, and from there on behave just like directories.
+
+use async_std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, Context};
+use flate2::read::GzDecoder;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Component;
+use std::sync::Arc;
+
+use super::{Node, NodeArc, NodeResult, UnitFile, Verifier, SIGNATURE_EXT, content_hash, display_path, script_from_bytes, unit_extension};
+
+/// Name of the member which lists the content hashes of the other members
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+type Members = Arc<HashMap<String, Vec<u8>>>;
+
+/// Describes the units packaged into an archive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub root: String,
+    pub units: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub hash: String,
+}
+
+impl Manifest {
+    /// Ensures each member listed in the manifest is present and unaltered, and that no
+    /// unit was added to the archive without being listed
+    fn verify(&self, members: &HashMap<String, Vec<u8>>) -> Result<()> {
+        for entry in self.units.iter() {
+            match members.get(&entry.name) {
+                Some(bytes) if content_hash(bytes) == entry.hash => continue,
+                Some(_) => return Err(anyhow!("Archive member {} does not match its manifest hash", entry.name)),
+                None => return Err(anyhow!("Archive member {} is listed in the manifest but missing", entry.name)),
+            }
+        }
+
+        for name in members.keys() {
+            let is_unit = matches!(unit_extension(&PathBuf::from(name)), Some("sysu") | Some("sh"));
+            if is_unit && !self.units.iter().any(|entry| entry.name == *name) {
+                return Err(anyhow!("Archive member {} is not listed in the manifest", name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    location: PathBuf,
+    /// Path of the directory within the archive this node represents, either
+    /// empty for the archive root or ending with a slash
+    prefix: String,
+    members: Option<Members>,
+    verifier: Arc<Verifier>,
+    children: HashMap<String, NodeArc>,
+}
+
+impl Archive {
+    pub fn new(location: PathBuf, verifier: Arc<Verifier>) -> Self {
+        Self { location, prefix: String::new(), members: None, verifier, children: HashMap::new() }
+    }
+
+    pub async fn search(&mut self, name: &str) -> NodeResult {
+        if let Some(node) = self.children.get(name) {
+            return Ok(Some(node.clone()));
+        }
+
+        let members = self.members()?;
+
+        let node = if name == "." {
+            Some(self.subdir(members, self.prefix.clone()).into())
+        } else {
+            self.load_child(members, name).await?
+        };
+
+        if let Some(ref node) = node {
+            self.children.insert(name.to_string(), node.clone());
+        }
+
+        Ok(node)
+    }
+
+    async fn load_child(&self, members: Members, name: &str) -> NodeResult {
+        let member_name = format!("{}{}", self.prefix, name);
+        let path = self.location.join(&member_name);
+
+        if let Some(bytes) = members.get(&member_name) {
+            let ext = unit_extension(&path);
+            let signature = members.get(&format!("{}.{}", member_name, SIGNATURE_EXT));
+            if matches!(ext, Some("sysu") | Some("sh")) {
+                self.verifier.verify(&display_path(&path), bytes, signature.map(|s| &s[..]))?;
+            }
+
+            return match ext {
+                Some("sysu") => {
+                    let unitfile = UnitFile::from_bytes(path, bytes).await?;
+                    Ok(Some(Node::UnitFile(unitfile).into()))
+                },
+                Some("sh") => {
+                    let script = script_from_bytes(&path, bytes.clone(), signature.cloned())?;
+                    Ok(Some(Node::Script(script).into()))
+                },
+                _ => Err(anyhow!("Invalid file extension on archive member: {}", display_path(&path))),
+            };
+        }
+
+        let dir_prefix = format!("{}/", member_name);
+        if members.keys().any(|k| k.starts_with(&dir_prefix)) {
+            return Ok(Some(self.subdir(members, dir_prefix).into()));
+        }
+
+        Ok(None)
+    }
+
+    /// Lists the members and directories directly within this node's prefix
+    pub fn entry_names(&mut self) -> Result<Vec<String>> {
+        let members = self.members()?;
+        let mut names = members
+            .keys()
+            .filter_map(|key| key.strip_prefix(&self.prefix))
+            .map(|rest| rest.split('/').next().unwrap_or(rest).to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn subdir(&self, members: Members, prefix: String) -> Node {
+        Node::Archive(Archive {
+            location: self.location.clone(),
+            prefix,
+            members: Some(members),
+            verifier: self.verifier.clone(),
+            children: HashMap::new(),
+        })
+    }
+
+    fn members(&mut self) -> Result<Members> {
+        if self.members.is_none() {
+            let members = read_archive(&self.location)
+                .context(format!("Failed to read archive: {}", display_path(&self.location)))?;
+            self.members = Some(Arc::new(members));
+        }
+
+        Ok(self.members.clone().unwrap())
+    }
+}
+
+/// Whether the given path should be loaded as an archive, based on its name
+pub fn is_archive(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.ends_with(".tar") || is_gzipped(path)
+}
+
+/// Whether the given archive path should be gzip compressed, based on its name
+pub fn is_gzipped(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// Normalizes a path within an archive to slash-delimited relative form, so
+/// `./foo/bar.sh` and `foo/bar.sh` refer to the same member
+pub fn member_path(path: &std::path::Path) -> Result<String> {
+    let mut parts = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => match part.to_str() {
+                Some(part) => parts.push(part),
+                None => return Err(anyhow!("Archive path is not valid unicode: {:?}", path)),
+            },
+            Component::CurDir => continue,
+            _ => return Err(anyhow!("Archive path must be relative and inside the archive: {:?}", path)),
+        }
+    }
+
+    Ok(parts.join("/"))
+}
+
+fn read_archive(location: &PathBuf) -> Result<HashMap<String, Vec<u8>>> {
+    let file = std::fs::File::open(location)?;
+    let reader: Box<dyn Read> = if is_gzipped(location) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut members = HashMap::new();
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = member_path(&entry.path()?)?;
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes)?;
+        members.insert(name, bytes);
+    }
+
+    if let Some(manifest) = members.get(MANIFEST_NAME) {
+        let manifest: Manifest = serde_json::from_slice(manifest)
+            .context("Failed to parse archive manifest")?;
+        manifest.verify(&members)?;
+    }
+
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::loader::Loader;
+    use crate::testing::TempDir;
+    use async_std::task::block_on;
+
+    fn write_tar(path: &std::path::Path, members: &[(&str, &str)]) {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        std::fs::write(path, builder.into_inner().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_archive_in_directory() {
+        let dir = TempDir::new("archive");
+        write_tar(&dir.join("units.tar"), &[
+            ("./foo.sh", "echo foo\n"),
+            ("lib/units.sysu", "# [ bar.sh ]\necho bar\n"),
+        ]);
+
+        let loader = Loader::from_search_paths(vec![PathBuf::from(dir.path())], Verifier::default());
+
+        let foo = block_on(loader.load("units.tar/foo.sh", None)).unwrap();
+        assert_eq!(foo.body, "echo foo\n");
+        assert!(foo.origin.ends_with("units.tar/foo.sh"));
+
+        let bar = block_on(loader.load("units.tar/lib/units.sysu/bar.sh", None)).unwrap();
+        assert_eq!(bar.body.trim(), "echo bar");
+
+        assert!(block_on(loader.load("units.tar/lib", None)).is_err());
+    }
+
+    #[test]
+    fn test_manifest_mismatch() {
+        let dir = TempDir::new("archive");
+        let manifest = format!(
+            r#"{{ "root": "foo.sh", "units": [{{ "name": "foo.sh", "hash": "{}" }}] }}"#,
+            content_hash(b"echo foo\n"),
+        );
+        write_tar(&dir.join("units.tar"), &[
+            ("foo.sh", "echo tampered\n"),
+            (MANIFEST_NAME, &manifest),
+        ]);
+
+        let loader = Loader::from_search_paths(vec![PathBuf::from(dir.join("units.tar"))], Verifier::default());
+        let err = block_on(loader.load("foo.sh", None)).unwrap_err();
+        assert!(format!("{:#}", err).contains("does not match its manifest hash"));
+    }
+
+    #[test]
+    fn test_manifest_unlisted_member() {
+        let dir = TempDir::new("archive");
+        let manifest = format!(
+            r#"{{ "root": "foo.sh", "units": [{{ "name": "foo.sh", "hash": "{}" }}] }}"#,
+            content_hash(b"echo foo\n"),
+        );
+        write_tar(&dir.join("units.tar"), &[
+            ("foo.sh", "echo foo\n"),
+            ("lib/extra.sh", "echo extra\n"),
+            (MANIFEST_NAME, &manifest),
+        ]);
+
+        let loader = Loader::from_search_paths(vec![PathBuf::from(dir.join("units.tar"))], Verifier::default());
+        let err = block_on(loader.load("foo.sh", None)).unwrap_err();
+        assert!(format!("{:#}", err).contains("lib/extra.sh is not listed in the manifest"));
+    }
+}
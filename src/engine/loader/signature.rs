@@ -0,0 +1,163 @@
+//! Unit scripts and unitfiles can be signed with ed25519 keys, with the signature
+//! placed beside them in a `.sig` file.  When trusted keys are configured, any
+//! signature present must be valid, and with `require_signed` every file must
+//! carry one before it can be loaded.
+
+use anyhow::{Result, anyhow, Context};
+use async_std::path::PathBuf;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+
+/// Extension appended to a file's name to find its detached signature
+pub const SIGNATURE_EXT: &str = "sig";
+
+/// DER prefix of an ed25519 SubjectPublicKeyInfo, which is how openssl writes
+/// public keys in PEM files
+const SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+#[derive(Debug, Default)]
+pub struct Verifier {
+    trusted_keys: Vec<VerifyingKey>,
+    require_signed: bool,
+}
+
+impl Verifier {
+    pub fn new(trusted_keys: Vec<VerifyingKey>, require_signed: bool) -> Self {
+        Self { trusted_keys, require_signed }
+    }
+
+    /// Reads trusted keys from the given file, if any
+    pub fn load(keys_path: Option<&PathBuf>, require_signed: bool) -> Result<Self> {
+        let trusted_keys = match keys_path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .context(format!("Failed to read trusted keys: {:?}", path))?;
+                parse_keys(&text).context(format!("Failed to parse trusted keys: {:?}", path))?
+            },
+            None => Vec::new(),
+        };
+
+        if require_signed && trusted_keys.is_empty() {
+            return Err(anyhow!("Signed units are required, but no trusted keys are configured"));
+        }
+
+        Ok(Self::new(trusted_keys, require_signed))
+    }
+
+    /// Checks a file's contents against its detached signature, if it has one
+    pub fn verify(&self, location: &str, contents: &[u8], signature: Option<&[u8]>) -> Result<()> {
+        let signature = match signature {
+            Some(signature) => signature,
+            None if self.require_signed => return Err(anyhow!("Missing signature for {}", location)),
+            None => return Ok(()),
+        };
+
+        // Without any keys to check against, signatures are informational only
+        if self.trusted_keys.is_empty() {
+            return Ok(());
+        }
+
+        let signature = parse_signature(signature)
+            .context(format!("Invalid signature file for {}", location))?;
+
+        if self.trusted_keys.iter().any(|key| key.verify_strict(contents, &signature).is_ok()) {
+            Ok(())
+        } else {
+            Err(anyhow!("Signature for {} does not match any trusted key", location))
+        }
+    }
+}
+
+/// Parses public keys, one base64 encoded key per line.  Keys may be either raw
+/// 32 byte keys or DER encoded, so PEM files written by openssl can be used as-is.
+/// Blank lines, comments and PEM armor lines are skipped.
+pub fn parse_keys(text: &str) -> Result<Vec<VerifyingKey>> {
+    let mut keys = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with("-----") {
+            continue;
+        }
+
+        let bytes = BASE64.decode(line).context(format!("Key is not valid base64: {}", line))?;
+        let raw = match bytes.strip_prefix(&SPKI_PREFIX[..]) {
+            Some(raw) => raw,
+            None => &bytes[..],
+        };
+        let raw: [u8; PUBLIC_KEY_LENGTH] = raw.try_into()
+            .map_err(|_| anyhow!("Key is not an ed25519 public key: {}", line))?;
+
+        keys.push(VerifyingKey::from_bytes(&raw)?);
+    }
+
+    Ok(keys)
+}
+
+/// Signatures are accepted either as the raw 64 bytes, or base64 encoded
+fn parse_signature(bytes: &[u8]) -> Result<Signature> {
+    let raw = if bytes.len() == SIGNATURE_LENGTH {
+        bytes.to_vec()
+    } else {
+        let text = std::str::from_utf8(bytes)?;
+        BASE64.decode(text.trim())?
+    };
+
+    let raw: [u8; SIGNATURE_LENGTH] = raw.try_into()
+        .map_err(|_| anyhow!("Signature must be {} bytes", SIGNATURE_LENGTH))?;
+
+    Ok(Signature::from_bytes(&raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{SigningKey, Signer};
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = signing_key();
+        let contents = b"apply() touch /tmp/foo;";
+        let signature = key.sign(contents).to_bytes();
+        let verifier = Verifier::new(vec![key.verifying_key()], false);
+
+        assert!(verifier.verify("foo.sh", contents, Some(&signature)).is_ok());
+        assert!(verifier.verify("foo.sh", contents, Some(BASE64.encode(signature).as_bytes())).is_ok());
+        assert!(verifier.verify("foo.sh", contents, None).is_ok());
+
+        let err = verifier.verify("foo.sh", b"apply() rm -rf /;", Some(&signature)).unwrap_err();
+        assert!(err.to_string().contains("does not match any trusted key"));
+
+        let other_key = SigningKey::from_bytes(&[8u8; 32]);
+        let other_signature = other_key.sign(contents).to_bytes();
+        assert!(verifier.verify("foo.sh", contents, Some(&other_signature)).is_err());
+    }
+
+    #[test]
+    fn test_require_signed() {
+        let verifier = Verifier::new(vec![signing_key().verifying_key()], true);
+        let err = verifier.verify("foo.sh", b"check() :;", None).unwrap_err();
+        assert_eq!(err.to_string(), "Missing signature for foo.sh");
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let key = signing_key().verifying_key();
+        let mut spki = SPKI_PREFIX.to_vec();
+        spki.extend_from_slice(key.as_bytes());
+
+        let text = format!(
+            "# raw key\n{}\n\n-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            BASE64.encode(key.as_bytes()),
+            BASE64.encode(&spki),
+        );
+
+        let keys = parse_keys(&text).unwrap();
+        assert_eq!(keys, vec![key, key]);
+
+        assert!(parse_keys("bm90IGEga2V5").is_err());
+    }
+}
@@ -4,7 +4,6 @@
 use futures::io::{AsyncRead, AsyncBufReadExt};
 use futures::stream::StreamExt;
 use async_std::io::BufReader;
-use async_std::fs::File;
 use async_std::path::PathBuf;
 use std::collections::HashMap;
 use anyhow::Result;
@@ -18,8 +17,10 @@ pub struct UnitFile {
 }
 
 impl UnitFile {
-    pub async fn load(path: PathBuf) -> Result<Self> {
-        let units = parse_defs(File::open(&path).await?).await?;
+    /// Parses a unitfile which has already been read into memory, so its
+    /// signature can be checked first
+    pub async fn from_bytes(path: PathBuf, bytes: &[u8]) -> Result<Self> {
+        let units = parse_defs(bytes).await?;
         Ok(Self { path, units })
     }
 
@@ -27,6 +28,10 @@ impl UnitFile {
         self.units.get(name).cloned()
     }
 
+    pub fn names(&self) -> Vec<String> {
+        self.units.keys().cloned().collect()
+    }
+
     pub fn display_path(&self) -> String {
         self.path.to_str().unwrap_or("<invalid unicode>").to_string()
     }
@@ -0,0 +1,118 @@
+//! Lockfiles record the script each unit in a dependency graph was loaded from,
+//! so later runs can refuse to execute any unit which has changed since it was
+//! locked.
+
+use anyhow::{Result, anyhow, Context};
+use async_std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+use super::loader::Script;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub root: String,
+    pub units: Vec<LockedUnit>,
+}
+
+/// A unit name, tagged with any version it resolved to, along with the origin and
+/// content hash of its script
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockedUnit {
+    pub name: String,
+    pub origin: String,
+    pub hash: String,
+}
+
+impl Lockfile {
+    pub fn from_scripts(root: &str, scripts: &BTreeMap<String, Script>) -> Self {
+        let units = scripts
+            .iter()
+            .map(|(name, script)| LockedUnit {
+                name: name.clone(),
+                origin: script.origin.clone(),
+                hash: script.hash(),
+            })
+            .collect();
+
+        Self { root: root.to_string(), units }
+    }
+
+    pub fn load(path: &PathBuf) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Failed to read lockfile: {:?}", path))?;
+        serde_json::from_str(&contents)
+            .context(format!("Failed to parse lockfile: {:?}", path))
+    }
+
+    pub fn write(&self, path: &PathBuf) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, contents + "\n")
+            .context(format!("Failed to write lockfile: {:?}", path))
+    }
+
+    /// Ensures the script loaded for a unit is the one recorded in the lockfile
+    pub fn verify(&self, name: &str, script: &Script) -> Result<()> {
+        let name = script.tagged_name(name);
+        let locked = self.units.iter().find(|unit| unit.name == name)
+            .ok_or_else(|| anyhow!("Unit {} is not in the lockfile", name))?;
+
+        let hash = script.hash();
+        if locked.hash != hash {
+            return Err(anyhow!(
+                "Unit {} loaded from {} has hash {}, but the lockfile expects {} (locked from {})",
+                name,
+                script.origin,
+                hash,
+                locked.hash,
+                locked.origin,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(body: &str) -> Script {
+        Script { origin: "/units/foo.sh".to_string(), body: body.to_string(), version: None, signature: None }
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut scripts = BTreeMap::new();
+        scripts.insert("foo.sh".to_string(), script("apply() touch /tmp/foo;"));
+        let lockfile = Lockfile::from_scripts("foo.sh", &scripts);
+
+        assert!(lockfile.verify("foo.sh", &script("apply() touch /tmp/foo;")).is_ok());
+
+        let err = lockfile.verify("foo.sh", &script("apply() rm -rf /;")).unwrap_err();
+        assert!(err.to_string().contains("but the lockfile expects"));
+
+        let err = lockfile.verify("bar.sh", &script("apply() touch /tmp/foo;")).unwrap_err();
+        assert!(err.to_string().contains("not in the lockfile"));
+    }
+
+    #[test]
+    fn test_verify_versions() {
+        let versioned = |body: &str, version: &str| Script {
+            version: Some(crate::parser::parse_version(version).unwrap()),
+            ..script(body)
+        };
+
+        // Deps on one unit which resolved to different versions are locked separately
+        let mut scripts = BTreeMap::new();
+        for script in [versioned("echo 1", "1"), versioned("echo 2", "2")] {
+            scripts.insert(script.tagged_name("pkg.sh"), script);
+        }
+        let lockfile = Lockfile::from_scripts("pkg.sh", &scripts);
+
+        assert!(lockfile.verify("pkg.sh", &versioned("echo 1", "1")).is_ok());
+        assert!(lockfile.verify("pkg.sh", &versioned("echo 2", "2")).is_ok());
+        assert!(lockfile.verify("pkg.sh", &versioned("echo 2", "1")).is_err());
+        assert!(lockfile.verify("pkg.sh", &script("echo 1")).is_err());
+    }
+}
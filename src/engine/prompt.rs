@@ -0,0 +1,36 @@
+//! Asks for the values of required params which weren't given, when sysunit is run
+//! from a terminal
+
+use crate::models::{Param, Value, ValueType};
+use crate::parser::parse_typed_value;
+
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, Write};
+
+/// Prompts for a param's value on the terminal, without echoing secrets
+pub fn prompt_for(param: &Param) -> Result<Value> {
+    // Only required params are asked for, so there's no need to mark them as such
+    let spec = param.to_string();
+    let spec = spec.trim_start_matches('!');
+    let prompt = match param.desc {
+        Some(ref desc) => format!("{} ({}): ", spec, desc),
+        None => format!("{}: ", spec),
+    };
+
+    let text = match param.value_type {
+        ValueType::Secret => rpassword::prompt_password(&prompt)?,
+        _ => {
+            let mut stderr = std::io::stderr();
+            stderr.write_all(prompt.as_bytes())?;
+            stderr.flush()?;
+
+            let mut line = String::new();
+            if std::io::stdin().lock().read_line(&mut line)? == 0 {
+                return Err(anyhow!("No value was given for {}", param.name));
+            }
+            line.trim_end_matches(['\r', '\n']).to_string()
+        },
+    };
+
+    parse_typed_value(&text, &param.value_type).context(format!("Invalid value for argument {}", param.name))
+}
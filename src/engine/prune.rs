@@ -0,0 +1,109 @@
+//! Works out which units a root unit applied last time have since left its graph, and
+//! loads them so they can be removed in the reverse of the order they ran in.
+use anyhow::Result;
+
+use std::collections::HashSet;
+
+use crate::models::UnitArc;
+use super::resolver::{resolve, DependencyFetcher};
+use super::state::GraphEntry;
+
+/// Units to remove from the target, and those to keep in the root's graph
+pub struct Pruning {
+    /// Stale units in the order they're removed, each with the graph it resolves to
+    pub units: Vec<(UnitArc, Vec<UnitArc>)>,
+    /// Stale units which are still applied, so they can be pruned by a later run
+    pub kept: Vec<GraphEntry>,
+}
+
+/// Provides the entries of the previous graph which aren't in the graph now, in the order
+/// they ran.  Units held by the graph of another root unit are left alone.
+pub fn stale_entries(previous: Vec<GraphEntry>, current: &[UnitArc], held: &HashSet<String>) -> Vec<GraphEntry> {
+    let current: HashSet<String> = current.iter().map(|unit| unit.fingerprint()).collect();
+    previous
+        .into_iter()
+        .filter(|entry| !current.contains(&entry.fingerprint) && !held.contains(&entry.fingerprint))
+        .collect()
+}
+
+/// Builds and loads the stale units along with their dependencies if they're to be pruned,
+/// dependents first so they're removed before what they depend on.  Units with secret args
+/// can't be built again, so they're kept along with any which aren't pruned.
+pub async fn load_pruned<F>(stale: Vec<GraphEntry>, prune: bool, fetcher: &mut F) -> Result<Pruning>
+where
+    F: DependencyFetcher<UnitArc>,
+{
+    if !prune {
+        return Ok(Pruning { units: Vec::new(), kept: stale });
+    }
+
+    let mut units = Vec::new();
+    let mut kept = Vec::new();
+    for entry in stale.into_iter().rev() {
+        let unit: UnitArc = match entry.to_unit() {
+            Ok(unit) => unit.into(),
+            Err(_) => {
+                kept.insert(0, entry);
+                continue;
+            },
+        };
+        let graph = resolve(unit.clone(), fetcher).await?;
+        units.push((unit, graph));
+    }
+    Ok(Pruning { units, kept })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use crate::engine::resolver::test_graph::{UnitGraph, unit};
+    use crate::models::Value;
+
+    fn graph(node: &UnitArc) -> Vec<UnitArc> {
+        match node.name.as_str() {
+            "conf.sh" => vec![unit("pkg.sh", "vim")],
+            "pkg.sh" => vec![unit("base.sh", "")],
+            _ => vec![],
+        }
+    }
+
+    fn entry(unit: &UnitArc) -> GraphEntry {
+        GraphEntry::from_unit(unit, &unit.args)
+    }
+
+    #[test]
+    fn test_prune() {
+        let (base, git, vim, conf) = (unit("base.sh", ""), unit("pkg.sh", "git"), unit("pkg.sh", "vim"), unit("conf.sh", ""));
+        let site = unit("site.sh", "");
+        let previous = [&base, &git, &vim, &conf, &site].into_iter().map(entry).collect::<Vec<_>>();
+
+        // Units held by another root aren't stale, even when they've left this graph
+        let held = HashSet::from([git.fingerprint()]);
+        let stale = stale_entries(previous, &[base.clone(), site.clone()], &held);
+        let names: Vec<String> = stale.iter().map(|entry| entry.args["name"].clone()).collect();
+        assert_eq!(names, vec!["\"vim\"", "\"\""]);
+
+        // Without --prune, stale units are kept for later
+        let pruning = block_on(load_pruned(stale.clone(), false, &mut UnitGraph(graph))).unwrap();
+        assert!(pruning.units.is_empty());
+        assert_eq!(pruning.kept.len(), 2);
+
+        // Dependents are removed first, each loaded with its graph
+        let pruning = block_on(load_pruned(stale, true, &mut UnitGraph(graph))).unwrap();
+        let order: Vec<UnitArc> = pruning.units.iter().map(|(unit, _)| unit.clone()).collect();
+        assert_eq!(order, vec![conf.clone(), vim.clone()]);
+        assert_eq!(pruning.units[0].1, vec![base.clone(), vim.clone(), conf.clone()]);
+        assert!(pruning.kept.is_empty());
+
+        // A unit with a secret arg can't be built again, so it's kept rather than failing
+        let mut secret_args = vim.args.clone();
+        secret_args.add_value("name", Value::Secret("vim".to_string()));
+        let secret = GraphEntry::from_unit(&vim, &secret_args);
+        let pruning = block_on(load_pruned(vec![secret, entry(&conf)], true, &mut UnitGraph(graph))).unwrap();
+        assert_eq!(pruning.units.len(), 1);
+        assert_eq!(pruning.units[0].0, conf);
+        assert_eq!(pruning.kept.len(), 1);
+        assert_eq!(pruning.kept[0].fingerprint, vim.fingerprint());
+    }
+}
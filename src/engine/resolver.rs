@@ -56,6 +56,11 @@ pub async fn resolve<'a, T, L>(initial_node: T, loader: &'a mut L) -> Result<Vec
     while let Some(node) = visit_stack.pop() {
         let state = node_states.get(&node.get_id()).unwrap();
 
+        // A node listed more than once may already have been finished by an earlier entry
+        if matches!(state, NodeState::Visited) {
+            continue;
+        }
+
         if matches!(state, NodeState::Visiting) {
             ordered_nodes.push(node.clone());
             node_states.insert(node.get_id(), NodeState::Visited);
@@ -88,7 +93,6 @@ pub async fn resolve<'a, T, L>(initial_node: T, loader: &'a mut L) -> Result<Vec
                 // If this node has been loaded but not yet visited, we add it to the stack
                 // so its children will also be loaded
                 Some(NodeState::Unvisited) => {
-                    node_states.insert(dep.get_id(), NodeState::Visiting);
                     visit_stack.push(dep);
                 }
                 // If this node is being lazy-loaded for the first time, we mark for an initial
@@ -105,12 +109,44 @@ pub async fn resolve<'a, T, L>(initial_node: T, loader: &'a mut L) -> Result<Vec
 }
 
 
+/// A graph of units for the tests of modules which resolve them
+#[cfg(test)]
+pub(super) mod test_graph {
+    use super::DependencyFetcher;
+    use crate::models::{Unit, UnitArc, Target, Value, ValueSet};
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    /// Builds a unit on the local target, with its `name` arg set to `pkg`
+    pub fn unit(name: &str, pkg: &str) -> UnitArc {
+        unit_on(name, pkg, Target::default())
+    }
+
+    pub fn unit_on(name: &str, pkg: &str, target: Target) -> UnitArc {
+        let mut args = ValueSet::new();
+        args.add_value("name", Value::String(pkg.to_string()));
+        Arc::new(Unit::new(name.to_string(), args, target))
+    }
+
+    /// Stands in for loading units, giving the dependencies of each from a function
+    pub struct UnitGraph<F>(pub F);
+
+    impl<F: FnMut(&UnitArc) -> Vec<UnitArc>> DependencyFetcher<UnitArc> for UnitGraph<F> {
+        async fn get_node_dependencies(&mut self, node: UnitArc) -> Result<Vec<UnitArc>> {
+            Ok((self.0)(&node))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fmt::Display;
     use std::collections::HashMap;
     use futures::executor::block_on;
+    use super::test_graph::{UnitGraph, unit_on};
+    use crate::models::{Unit, UnitArc, Target, ValueSet};
+    use std::sync::Arc;
 
     #[derive(Debug, Clone, Eq, PartialEq, Hash)]
     struct Node {
@@ -207,4 +243,26 @@ mod tests {
         let result: Vec<String> = result.iter().map(|node| node.id.clone()).collect();
         assert_eq!(result, expected);
     }
+
+    fn site_graph(node: &UnitArc) -> Vec<UnitArc> {
+        let on = |host: &str| Target::new("ssh", None, host);
+        match node.name.as_str() {
+            "site.sh" => vec![unit_on("pkg.sh", "git", on("web1")), unit_on("pkg.sh", "git", on("web2")), unit_on("pkg.sh", "git", on("web1"))],
+            "pkg.sh" => vec![unit_on("base.sh", "git", on(&node.target.host))],
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn test_multi_target() {
+        let root = Arc::new(Unit::new("site.sh".to_string(), ValueSet::new(), Target::default()));
+        let result = block_on(resolve(root, &mut UnitGraph(site_graph))).unwrap();
+
+        // The same unit on two hosts is two nodes, but repeats on one host are merged
+        let result: Vec<String> = result.iter()
+            .map(|unit| format!("{}@{}", unit.name, unit.target.host))
+            .collect();
+        let expected = vec!["base.sh@web1", "pkg.sh@web1", "base.sh@web2", "pkg.sh@web2", "site.sh@localhost"];
+        assert_eq!(result, expected);
+    }
 }
@@ -3,34 +3,82 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use anyhow::{Result, anyhow, Context};
 
-use crate::models::{Operation, Unit, UnitArc, Dependencies, Meta, ValueSet};
-use crate::events::{OpEvent, OpEventHandler};
+use crate::models::{Operation, Unit, UnitArc, Dependencies, Dependency, Meta, Value, ValueSet, ValueType, CaptureDefinition};
+use crate::models::version::{Version, VersionSpecification};
+use crate::events::{Event, OpEvent, OpEventHandler};
+use crate::parser::parse_typed_value;
 use super::unit_execution::UnitExecution;
 use super::Context as EngineContext;
 use super::executor_pool::ExecutorPool;
 use super::transport::transport_file;
+use super::lockfile::Lockfile;
+use super::prompt::prompt_for;
 
 use super::{
-    loader::Loader,
+    loader::{Loader, Script},
     resolver::DependencyFetcher,
 };
 
 pub struct Runner {
     ctx: EngineContext,
     loader: Loader,
+    lockfile: Option<Lockfile>,
     executor_pool: ExecutorPool,
     unit_executions: HashMap<UnitArc, UnitExecution>,
+    /// The root unit, with any args given as text read as their declared types
+    root: Option<UnitArc>,
+    /// Execution whose meta was run to type the root unit's args, kept so it's only run once
+    root_execution: Option<UnitExecution>,
 }
 
 impl Runner {
-    pub fn new(loader: Loader, ctx: EngineContext) -> Runner {
+    pub fn new(loader: Loader, lockfile: Option<Lockfile>, ctx: EngineContext) -> Runner {
         Runner {
             ctx,
             loader,
+            lockfile,
             executor_pool: ExecutorPool::new(),
             unit_executions: HashMap::new(),
+            root: None,
+            root_execution: None,
         }
     }
+
+    /// Reads the root unit's args as the types of its params, running its meta operation to
+    /// find them.  The returned unit is the one to run, so the root's identity matches that
+    /// of a dependency on it given the same values.
+    pub async fn type_root(&mut self, unit: UnitArc) -> Result<UnitArc> {
+        let script = self.load_script(&unit).await?;
+        let mut execution = UnitExecution::new(script).await?;
+
+        // Which args are secret isn't known until meta has run, so they're left out of its
+        // events.  Hooks aren't given args during meta anyway.
+        let meta = self.get_meta(&without_args(&unit), &mut execution).await?;
+        let mut args = typed_args(&unit, meta)?;
+
+        // Ask for required arguments which weren't given, if there's someone to ask, so the
+        // answers identify the root like any other args
+        if self.ctx.opts.prompt {
+            for param in meta.params.iter() {
+                if param.required && args.get(&param.name).is_none() {
+                    args.add_value(&param.name, prompt_for(param)?);
+                }
+            }
+        }
+        self.ctx.ev_handler.add_secrets(&args);
+        self.root_execution = Some(execution);
+
+        let root = Arc::new(Unit {
+            name: unit.name.clone(),
+            args,
+            raw_args: Vec::new(),
+            target: unit.target.clone(),
+            version: unit.version.clone(),
+            alias: unit.alias.clone(),
+        });
+        self.root = Some(root.clone());
+        Ok(root)
+    }
     
     pub async fn get_deps(&mut self, unit: UnitArc) -> Result<&Dependencies> {
         let op_ev_handler = self.ctx.ev_handler.get_op_handler(unit.clone(), Operation::Meta);
@@ -39,7 +87,7 @@ impl Runner {
         execution.get_deps(executor_arc, op_ev_handler.clone()).await
             .map_err(|e| {
                 op_ev_handler.handle(OpEvent::Error(e.to_string())).unwrap();
-                anyhow!("Failed to get deps for unit {} on target {}", &unit.name, &unit.target)
+                anyhow!("Failed to get deps for unit {} on target {}", unit.label(), &unit.target)
             })
     }
     
@@ -50,7 +98,7 @@ impl Runner {
         execution.remove(executor_arc, op_ev_handler.clone()).await
             .map_err(|e| {
                 op_ev_handler.handle(OpEvent::Error(e.to_string())).unwrap();
-                anyhow!("Failed to remove unit {} on target {}", &unit.name, &unit.target)
+                anyhow!("Failed to remove unit {} on target {}", unit.label(), &unit.target)
             })
     }
     
@@ -61,7 +109,7 @@ impl Runner {
         execution.apply(executor_arc, op_ev_handler.clone()).await
             .map_err(|e| {
                 op_ev_handler.handle(OpEvent::Error(e.to_string())).unwrap();
-                anyhow!("Failed to apply unit {} on target {}", &unit.name, &unit.target)
+                anyhow!("Failed to apply unit {} on target {}", unit.label(), &unit.target)
             })
     }
 
@@ -80,10 +128,42 @@ impl Runner {
         execution.check(executor_arc, op_ev_handler.clone()).await
             .map_err(|e| {
                 op_ev_handler.handle(OpEvent::Error(e.to_string())).unwrap();
-                anyhow!("Failed to check unit {} on target {}", &unit.name, &unit.target)
+                anyhow!("Failed to check unit {} on target {}", unit.label(), &unit.target)
             })
     }
 
+    /// Provides the root unit, once its args have been typed
+    pub fn root(&self) -> Option<&UnitArc> {
+        self.root.as_ref()
+    }
+
+    /// Provides the version a unit was loaded with, if it has been loaded and has one
+    pub fn get_version(&self, unit: &UnitArc) -> Option<&Version> {
+        self.unit_executions.get(unit).and_then(|execution| execution.version())
+    }
+
+    /// Provides the script a unit was loaded from, if it has been loaded
+    pub fn get_script(&self, unit: &UnitArc) -> Option<&Script> {
+        self.unit_executions.get(unit).map(|execution| &execution.script)
+    }
+
+    /// Provides the args a unit was given, read as the types of its params, if it has been loaded
+    pub fn get_args(&self, unit: &UnitArc) -> Option<ValueSet> {
+        let execution = self.unit_executions.get(unit)?;
+        let mut args = ValueSet::new();
+        for name in unit.args.values.keys() {
+            if let Some(value) = execution.args.get(name) {
+                args.add_value(name, value.clone());
+            }
+        }
+        Some(args)
+    }
+
+    /// Provides the values a unit has emitted so far, if it has been loaded
+    pub fn get_emit_data(&self, unit: &UnitArc) -> Option<&ValueSet> {
+        self.unit_executions.get(unit).map(|execution| &execution.emit_data)
+    }
+
     async fn get_unit_execution(&mut self, unit: UnitArc) -> &mut UnitExecution {
         match self.unit_executions.get_mut(&unit) {
             Some(execution) => execution,
@@ -96,7 +176,7 @@ impl Runner {
     async fn transport_files(&mut self, unit: &UnitArc, deps: &Dependencies, op_ev_handler: OpEventHandler) -> Result<()> {
         for file in deps.files.iter() {
             op_ev_handler.handle(OpEvent::TransportingFile(file.clone())).unwrap();
-            transport_file(&file, &unit.target).await?;
+            transport_file(file, &unit.target).await?;
             op_ev_handler.handle(OpEvent::FileTransported(file.clone())).unwrap();
         }
 
@@ -109,25 +189,36 @@ impl Runner {
     // Without these args being injected in phases, the unit would need for its dependencies
     // to have executed... before the deps operation.  This is recursive.
     async fn build_args_for(&self, unit: UnitArc, meta: &Meta) -> Result<ValueSet> {
+        let mut args = typed_args(&unit, meta)?;
+
         // Check that all required parameters have corresponding arguments
 
         for param in meta.params.iter() {
-            if param.required && !unit.args.values.contains_key(param.name.as_str()) {
+            if param.required && !args.values.contains_key(param.name.as_str()) {
                 return Err(anyhow!("Missing required parameter: {}", param.name.as_str()));
             }
         }
 
-        for (key, value) in unit.args.values.iter() {
+        for (key, value) in args.values.iter() {
             let param = meta.params.iter().find(|param| param.name == *key);
             match param {
                 Some(param) if value.get_type() != param.value_type => {
                     return Err(anyhow!("Argument {} is of type, {} not {} as expected", key, value.get_type(), param.value_type));
                 },
+                Some(param) => param.check(value).map_err(|e| anyhow!("Argument {}", e))?,
                 None => return Err(anyhow!("Parameter {} is provided, but not accepted", key)),
-                _ => continue,
             };
         }
-        Ok(unit.args.clone())
+
+        // Params with defaults are always set, so hooks don't need to handle them being unset
+        for param in meta.params.iter() {
+            if let (Some(default), None) = (&param.default, args.get(&param.name)) {
+                args.add_value(&param.name, default.clone());
+            }
+        }
+
+        self.ctx.ev_handler.add_secrets(&args);
+        Ok(args)
     }
 
     pub async fn finalize(&mut self) -> Result<()> {
@@ -142,63 +233,218 @@ impl Runner {
 
         // Check that all captures are present and of the correct type and add them to the args
         for dep in deps.units.iter() {
-            let target = match dep.target {
-                Some(ref target) => target.clone(),
-                None => unit.target.clone(),
-            };
-            let dep_unit: UnitArc = Arc::new(Unit::new(dep.name.clone(), dep.args.clone(), target));
-
-            // Assume the unit has already been run and has emit values
-            let emitted_values = match self.unit_executions.get(&dep_unit).map(|execution| &execution.emit_data) {
-                Some(data) => data,
-                None => panic!("Can't get emit values, unit not initialized: {:?}", unit),
-            };
+            // Captures from a dependency which fans out are collected into lists, in the
+            // order of its instances
+            let mut collected: HashMap<String, Vec<Value>> = HashMap::new();
+            if dep.each.is_some() {
+                for capture in dep.captures.iter() {
+                    if matches!(capture.value_type, ValueType::List | ValueType::Map) {
+                        return Err(anyhow!("Capture {} from {} can't be a {}, since each collects it into a list", capture.name, dep.name, capture.value_type));
+                    }
+                    let alias = capture.alias.clone().unwrap_or_else(|| capture.name.clone());
+                    collected.insert(alias, Vec::new());
+                }
+            }
 
-            for capture in dep.captures.iter() {
-                let value = emitted_values
-                    .get(&capture.name)
-                    .ok_or_else(|| {
-                        anyhow!("Unit {} expected its dependency {} to emit a value for capture: {}", unit.name, dep_unit.name, capture.name)
-                    })?;
-
-                // Default the alias to the capture's name if not specified
-                let alias = match &capture.alias {
-                    Some(a) => a.clone(),
-                    None => capture.name.clone(),
+            for dep_unit in dep_units(&unit, dep) {
+                // Assume the unit has already been run and has emit values
+                let emitted_values = match self.unit_executions.get(&dep_unit).map(|execution| &execution.emit_data) {
+                    Some(data) => data,
+                    None => panic!("Can't get emit values, unit not initialized: {:?}", unit),
                 };
 
-                if value.get_type() != capture.value_type {
-                    return Err(anyhow!(
-                        "Capture {:?} from {:?} is of type {:?} not {:?} as expected",
-                        capture.name,
-                        dep_unit.name,
-                        value.get_type(),
-                        capture.value_type
-                    ));
+                for capture in dep.captures.iter() {
+                    // Optional captures which weren't emitted take their default, or are left unset
+                    let value = match (emitted_values.get(&capture.name), &capture.default) {
+                        (Some(value), _) => value.clone().into_type(&capture.value_type),
+                        (None, _) if capture.required => return Err(anyhow!(
+                            "Unit {} expected its dependency {} to emit a value for capture: {}", unit.label(), dep_unit.label(), capture.name
+                        )),
+                        (None, Some(default)) => default.clone(),
+                        (None, None) => continue,
+                    };
+
+                    // Default the alias to the capture's name if not specified
+                    let alias = match &capture.alias {
+                        Some(a) => a.clone(),
+                        None => capture.name.clone(),
+                    };
+
+                    if value.get_type() != capture.value_type {
+                        return Err(anyhow!(
+                            "Capture {:?} from {:?} is of type {:?} not {:?} as expected",
+                            capture.name,
+                            dep_unit.label(),
+                            value.get_type(),
+                            capture.value_type
+                        ));
+                    }
+
+                    if dep.each.is_some() {
+                        collected.entry(alias).or_default().push(value);
+                    } else {
+                        captures.add_value(&alias, value);
+                    }
                 }
+            }
 
-                captures.add_value(&alias, value.clone());
+            for (alias, values) in collected {
+                captures.add_value(&alias, Value::List(values));
             }
         }
 
+        self.ctx.ev_handler.add_secrets(&captures);
         Ok(captures)
     }
 
-    /// Initializes a unit, running its meta and deps operations
-    async fn load_unit(&mut self, unit: UnitArc) -> Result<&UnitExecution> {
-        let script = self.loader.load(&unit.name).await?;
+    /// Ensures the version a dependency declares in its meta satisfies the specification
+    /// given by its dependent, loading the dependency if needed
+    async fn check_version(&mut self, unit: &UnitArc, dep_unit: UnitArc, version_spec: &VersionSpecification) -> Result<()> {
+        let execution = if let Some(execution) = self.unit_executions.get(&dep_unit) {
+            execution
+        } else {
+            self.load_unit(dep_unit.clone()).await
+                .context(format!("Failed to load unit {}", dep_unit.label()))?
+        };
+
+        match execution.version() {
+            Some(version) if version_spec.matches(version) => Ok(()),
+            Some(version) => Err(anyhow!(
+                "Unit {} requires {} version {}, but version {} was found",
+                unit.label(),
+                dep_unit.label(),
+                version_spec,
+                version,
+            )),
+            None => Err(anyhow!(
+                "Unit {} requires {} version {}, but it does not declare a version",
+                unit.label(),
+                dep_unit.label(),
+                version_spec,
+            )),
+        }
+    }
+
+    /// Ensures the values a unit captures from a dependency are declared in the dependency's
+    /// emits, so a mismatch fails before anything runs
+    async fn check_captures(&mut self, unit: &UnitArc, dep_unit: UnitArc, captures: &[CaptureDefinition]) -> Result<()> {
+        let execution = if let Some(execution) = self.unit_executions.get(&dep_unit) {
+            execution
+        } else {
+            self.load_unit(dep_unit.clone()).await
+                .context(format!("Failed to load unit {}", dep_unit.label()))?
+        };
+
+        // Dependencies which don't declare their emits can only be checked once they've run
+        let meta = match execution.meta {
+            Some(ref meta) if !meta.emits.is_empty() => meta,
+            _ => return Ok(()),
+        };
+
+        for capture in captures {
+            match meta.declared_emit(&capture.name) {
+                None => return Err(anyhow!(
+                    "Unit {} captures {} from {}, which does not declare it in its emits",
+                    unit.label(),
+                    capture.name,
+                    dep_unit.label(),
+                )),
+                Some(emit) if emit.value_type != capture.value_type => return Err(anyhow!(
+                    "Unit {} captures {} from {} as {}, but it is declared as {}",
+                    unit.label(),
+                    capture.name,
+                    dep_unit.label(),
+                    capture.value_type,
+                    emit.value_type,
+                )),
+                _ => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Initializes a unit if it hasn't been already, running its meta and deps operations
+    /// without checking its dependencies, which are expected to be initialized before it
+    pub async fn prepare(&mut self, unit: UnitArc) -> Result<()> {
+        if !self.unit_executions.contains_key(&unit) {
+            self.load_unit(unit.clone()).await
+                .context(format!("Failed to load unit {}", unit.label()))?;
+        }
+        Ok(())
+    }
+
+    /// Gives a unit which completed in an earlier run the values it emitted then, so units
+    /// which depend on it can capture them without it being run again.  Its meta is run so
+    /// the version and emits it declares can be checked by its dependents.
+    pub async fn restore(&mut self, unit: UnitArc, emitted: ValueSet) -> Result<()> {
+        if !self.unit_executions.contains_key(&unit) {
+            let mut execution = UnitExecution::new(self.load_script(&unit).await?).await?;
+            self.get_meta(&unit, &mut execution).await?;
+            self.unit_executions.insert(unit.clone(), execution);
+        }
+        let execution = self.unit_executions.get_mut(&unit).unwrap();
+        execution.emit_data = emitted;
+        self.ctx.ev_handler.add_secrets(&execution.emit_data);
+        Ok(())
+    }
+
+    /// Runs a unit's meta operation without building its arguments, so it can be
+    /// described even if arguments it requires weren't given
+    pub async fn describe(&mut self, unit: UnitArc) -> Result<Meta> {
+        let script = self.load_script(&unit).await?;
         let mut execution = UnitExecution::new(script).await?;
+        let meta = self.get_meta(&without_args(&unit), &mut execution).await?;
+        Ok(meta.clone())
+    }
+
+    async fn load_script(&mut self, unit: &UnitArc) -> Result<Script> {
+        let script = self.loader.load(&unit.name, unit.version.as_ref()).await?;
+        self.ctx.ev_handler.handle(Event::Debug(format!("Loaded unit {} from {}", unit.name, script.origin)))?;
+
+        // In locked mode, refuse to run any script which has drifted from the lockfile
+        if let Some(ref lockfile) = self.lockfile {
+            lockfile.verify(&unit.name, &script)?;
+        }
+        Ok(script)
+    }
+
+    /// Runs the unit's meta operation to get its metadata
+    async fn get_meta<'a>(&mut self, unit: &UnitArc, execution: &'a mut UnitExecution) -> Result<&'a Meta> {
         let executor_arc = self.executor_pool.get_executor(&unit.target, self.ctx.clone()).await?;
-        
-        // Run the units meta operation to get its metadata
-        let meta = {
-            let op_ev_handler = self.ctx.ev_handler.get_op_handler(unit.clone(), Operation::Meta);
-            execution.get_meta(executor_arc.clone(), op_ev_handler.clone()).await
-                .map_err(|e| {
-                    op_ev_handler.handle(OpEvent::Error(e.to_string())).unwrap();
-                    anyhow!("Failed to get meta for unit {} on target {}", &unit.name, &unit.target)
-                })?
+        let op_ev_handler = self.ctx.ev_handler.get_op_handler(unit.clone(), Operation::Meta);
+        execution.get_meta(executor_arc, op_ev_handler.clone()).await
+            .map_err(|e| {
+                op_ev_handler.handle(OpEvent::Error(e.to_string())).unwrap();
+                anyhow!("Failed to get meta for unit {} on target {}", unit.label(), &unit.target)
+            })
+    }
+
+    /// Initializes a unit, running its meta and deps operations
+    async fn load_unit(&mut self, unit: UnitArc) -> Result<&UnitExecution> {
+        let preloaded = match self.root {
+            Some(ref root) if *root == unit => self.root_execution.take(),
+            _ => None,
         };
+        let mut execution = match preloaded {
+            Some(execution) => execution,
+            None => UnitExecution::new(self.load_script(&unit).await?).await?,
+        };
+        let (tagged_version, origin) = (execution.script.version.clone(), execution.script.origin.clone());
+        let meta = self.get_meta(&unit, &mut execution).await?;
+        let executor_arc = self.executor_pool.get_executor(&unit.target, self.ctx.clone()).await?;
+
+        // A version tagged on the script's file must agree with any declared in its meta
+        if let (Some(tagged), Some(declared)) = (&tagged_version, &meta.version) {
+            if tagged != declared {
+                return Err(anyhow!(
+                    "Unit {} was loaded from {} tagged with version {}, but declares version {}",
+                    unit.label(),
+                    origin,
+                    tagged,
+                    declared,
+                ));
+            }
+        }
 
         // Sets the arguments given for the unit on its execution so they can be used for
         // following operations
@@ -220,20 +466,76 @@ impl DependencyFetcher<UnitArc> for Runner {
             execution
         } else {
             self.load_unit(unit.clone()).await
-                .context(format!("Failed to load unit {}", unit.name))?
+                .context(format!("Failed to load unit {}", unit.label()))?
         };
 
-        let units = execution.deps.as_ref().unwrap().units
+        let deps = execution.deps.as_ref().unwrap().units
             .iter()
-            .map(|dep| {
-                let target = match dep.target {
-                    Some(ref target) => target.clone(),
-                    None => unit.target.clone(),
-                };
-                let unit = Unit::new(dep.name.clone(), dep.args.clone(), target);
-                Arc::new(unit)
+            .flat_map(|dep| {
+                dep_units(&unit, dep)
+                    .into_iter()
+                    .map(|dep_unit| (dep_unit, dep.version.clone(), dep.captures.clone()))
             })
-            .collect();
+            .collect::<Vec<_>>();
+
+        let mut units = Vec::new();
+        for (dep_unit, version_spec, captures) in deps {
+            if let Some(version_spec) = version_spec {
+                self.check_version(&unit, dep_unit.clone(), &version_spec).await?;
+            }
+            if !captures.is_empty() {
+                self.check_captures(&unit, dep_unit.clone(), &captures).await?;
+            }
+            units.push(dep_unit);
+        }
+
         Ok(units)
     }
 }
+
+/// Provides the unit with its args left out, for running meta before it's known which of
+/// them are secret
+fn without_args(unit: &Unit) -> UnitArc {
+    Arc::new(Unit {
+        name: unit.name.clone(),
+        args: ValueSet::new(),
+        raw_args: Vec::new(),
+        target: unit.target.clone(),
+        version: unit.version.clone(),
+        alias: unit.alias.clone(),
+    })
+}
+
+/// Reads a unit's args as the types of its params.  Raw args are parsed from their text,
+/// others converted, and undeclared ones are left for the caller to reject.
+fn typed_args(unit: &Unit, meta: &Meta) -> Result<ValueSet> {
+    let mut args = unit.args.clone();
+    for param in meta.params.iter() {
+        let value = match (unit.raw_args.contains(&param.name), unit.args.get(&param.name)) {
+            (true, Some(Value::String(text))) => parse_typed_value(text, &param.value_type)
+                .context(format!("Invalid value for argument {}", param.name))?,
+            // Strings given by dependents only become secret once they reach a secret param
+            (_, Some(value)) => value.clone().into_type(&param.value_type),
+            (_, None) => continue,
+        };
+        args.add_value(&param.name, value);
+    }
+    Ok(args)
+}
+
+/// Builds the units a dependency stands for, one for each instance if it fans out
+fn dep_units(unit: &UnitArc, dep: &Dependency) -> Vec<UnitArc> {
+    let target = match dep.target {
+        Some(ref target) => target.clone(),
+        None => unit.target.clone(),
+    };
+
+    dep.instances()
+        .into_iter()
+        .map(|args| Arc::new(Unit {
+            version: dep.version.clone(),
+            alias: dep.alias.clone(),
+            ..Unit::new(dep.name.clone(), args, target.clone())
+        }))
+        .collect()
+}
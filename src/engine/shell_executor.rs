@@ -3,11 +3,15 @@
 use anyhow::{anyhow, Result};
 use crate::engine::shell_executor::adapter::build_command;
 use crate::{
-    models::{Target, Operation, ValueSet, Dependencies, Meta, OpCompletion},
+    models::{Target, Operation, Param, Value, ValueSet, Dependencies, Meta, OpCompletion},
     events::{Event, OpEventHandler, OpEvent},
 };
 
 use async_process::ChildStdout;
+use futures::{AsyncBufReadExt, StreamExt, io::BufReader};
+use stdout_data::StderrLine;
+
+use std::collections::HashMap;
 
 use super::Context as EngineContext;
 
@@ -36,8 +40,26 @@ impl ShellExecutor {
     ) -> Result<Self> {
         let command = build_command(target, &ctx.opts)?;
         let mut subprocess = Subprocess::init(command)?;
-        let stdout_parser = stdout_data::StdoutDataProducer::new(subprocess.take_stdout());
-        let msg_stream = MessageStream::new(stdout_parser);
+        // Messages are framed with a nonce so output from units can't be mistaken for them
+        let nonce = format!("{:016x}", rand::random::<u64>());
+        let stdout_parser = stdout_data::StdoutDataProducer::new(subprocess.take_stdout(), nonce.clone());
+
+        // Stderr is read as it's written, so errors can be reported when they happen.  It's
+        // drained until the adapter closes it, so the adapter never blocks on a full pipe.
+        let (stderr_tx, stderr_rx) = async_std::channel::unbounded();
+        let mut stderr = BufReader::new(subprocess.take_stderr());
+        let stderr_nonce = nonce.clone();
+        async_std::task::spawn(async move {
+            let mut line = Vec::new();
+            while let Ok(n_bytes_read) = stderr.read_until(b'\n', &mut line).await {
+                if n_bytes_read == 0 {
+                    break;
+                }
+                let _ = stderr_tx.send(StderrLine::parse(&line, &stderr_nonce)).await;
+                line.clear();
+            }
+        });
+        let msg_stream = MessageStream::new(stdout_parser, stderr_rx);
 
         let mut executor = ShellExecutor {
             target: target.clone(),
@@ -46,7 +68,7 @@ impl ShellExecutor {
             msg_stream,
         };
 
-        executor.send_stdin(SHELL_SLUG).await?;
+        executor.send_stdin(&format!("_sysu_nonce={}\n{}", nonce, SHELL_SLUG)).await?;
 
         Ok(executor)
     }
@@ -71,29 +93,29 @@ impl ShellExecutor {
             })
     }
     
-    pub async fn check(&mut self, op_ev_handler: OpEventHandler, script: &str, args: &ValueSet) -> Result<(bool, ValueSet)> {
+    pub async fn check(&mut self, op_ev_handler: OpEventHandler, script: &str, args: &ValueSet, emits: &[Param]) -> Result<(bool, ValueSet)> {
         self.run_op(Operation::Check, script, args).await?;
-        self.msg_stream.get_check_values(op_ev_handler.clone()).await
+        self.msg_stream.get_check_values(op_ev_handler.clone(), emits).await
             .and_then(|(present, values)| {
                 op_ev_handler.handle(OpEvent::Complete(OpCompletion::Check(present)))?;
                 Ok((present, values))
             })
     }
     
-    pub async fn apply(&mut self, op_ev_handler: OpEventHandler, script: &str, args: &ValueSet) -> Result<ValueSet> {
+    pub async fn apply(&mut self, op_ev_handler: OpEventHandler, script: &str, args: &ValueSet, emits: &[Param]) -> Result<ValueSet> {
         op_ev_handler.handle(OpEvent::Started)?;
         self.run_op(Operation::Apply, script, args).await?;
-        self.msg_stream.get_apply_values(op_ev_handler.clone()).await
+        self.msg_stream.get_apply_values(op_ev_handler.clone(), emits).await
             .and_then(|values| {
                 op_ev_handler.handle(OpEvent::Complete(OpCompletion::Apply))?;
                 Ok(values)
             })
     }
     
-    pub async fn remove(&mut self, op_ev_handler: OpEventHandler, script: &str, args: &ValueSet) -> Result<ValueSet> {
+    pub async fn remove(&mut self, op_ev_handler: OpEventHandler, script: &str, args: &ValueSet, emits: &[Param]) -> Result<ValueSet> {
         op_ev_handler.handle(OpEvent::Started)?;
         self.run_op(Operation::Remove, script, args).await?;
-        self.msg_stream.get_remove_values(op_ev_handler.clone()).await
+        self.msg_stream.get_remove_values(op_ev_handler.clone(), emits).await
             .and_then(|values| {
                 op_ev_handler.handle(OpEvent::Complete(OpCompletion::Remove))?;
                 Ok(values)
@@ -103,25 +125,32 @@ impl ShellExecutor {
 
     /// Runs an operation from the given script
     async fn run_op(&mut self, op: Operation, script: &str, args: &ValueSet) -> Result<()> {
-        let argstr = args_str(args);
+        let argstr = args_str(args)?;
         let set_str = if self.ctx.opts.debug {
             "set -e -u -x\n"
         } else {
             "set -e -u\n"
         };
-        self.send_stdin(&format!("(\n{}\n{}\n{}\n{}\n)\n _emit status $? \n", set_str, script, argstr, op.to_string())).await?;
+        let hook = format!("(\n{}\n{}\n{}\n{}\n)", set_str, script, argstr, op);
+        self.send_stdin(&hook_command(&hook)).await?;
 
         Ok(())
     }
 
     /// Close pipes to the shell subprocess and wait for it to exit
     pub async fn finalize(mut self) -> Result<()> {
-        use async_std::io::ReadExt;
-        let mut stderr = String::new();
         let subprocess_string = self.subprocess.command_string();
-        self.msg_stream.finalize()?;
+        let stderr_rx = self.msg_stream.finalize()?;
         self.subprocess.close_stdin()?;
-        self.subprocess.get_stderr().read_to_string(&mut stderr).await?;
+        let stderr = stderr_rx
+            .filter_map(|line| async move {
+                match line {
+                    StderrLine::Hook(text) | StderrLine::Adapter(text) => Some(text),
+                    StderrLine::Sync => None,
+                }
+            })
+            .collect::<Vec<_>>().await
+            .join("\n");
 
         let status_code = self.subprocess.finalize().await?;
 
@@ -129,7 +158,7 @@ impl ShellExecutor {
             return Err(anyhow!("adapter exited with status code {} {}", status_code, stderr));
         }
 
-        self.ctx.ev_handler.handle(Event::Debug(format!("Executor {:?} exited", subprocess_string)))?;
+        self.ctx.ev_handler.handle(Event::Debug(format!("Executor {:?} for {} exited", subprocess_string, self.target)))?;
         Ok(())
     }
 
@@ -138,10 +167,253 @@ impl ShellExecutor {
     }
 }
 
-fn args_str(args: &ValueSet) -> String {
+/// Wraps a hook in the command which runs it.  The hook's stdout goes straight through on
+/// fd 4, while its stderr is piped through _sysu_stderr onto the shell's stderr.  Its exit
+/// status is captured on fd 5, since a pipeline would lose it.  Once it's done, _sysu_sync
+/// marks the end of its stderr and its status is emitted.
+fn hook_command(hook: &str) -> String {
+    format!(
+        "{{ _sysu_status=$( {{ {{ {} 2>&1 1>&4 4>&- 5>&- 6>&-; echo $? >&5; }} | _sysu_stderr; }} 5>&1 ); }} 4>&1\n _sysu_sync\n _emit status $_sysu_status \n",
+        hook,
+    )
+}
+
+/// Builds shell assignments for args and captures.  Values are single quoted so they're
+/// never expanded by the shell, whatever they contain.
+///
+/// Lists and maps are set as newline separated items or keys, so they can be looped
+/// over, along with a variable for each item.  `pkgs=[git, tmux]` sets `pkgs`,
+/// `pkgs_count`, `pkgs_0` and `pkgs_1`, and `env={A=1}` sets `env` to its keys and `env_A`.
+fn args_str(args: &ValueSet) -> Result<String> {
+    let mut keys = args.values.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    // Lists and maps are also split into a variable for each item, which mustn't
+    // overwrite another arg, or an item of another list or map
+    let mut assigned: HashMap<String, String> = HashMap::new();
+    for key in keys.iter() {
+        if !is_shell_identifier(key) {
+            return Err(anyhow!("Cannot pass {} to a unit, it is not a valid shell variable name", key));
+        }
+        assigned.insert(key.to_string(), key.to_string());
+    }
+    let mut item = |key: &str, name: String, value: &str| -> Result<String> {
+        match assigned.insert(name.clone(), key.to_string()) {
+            Some(other) if other == name => Err(anyhow!("Cannot pass {} to a unit, its {} variable would overwrite the {} arg", key, name, other)),
+            Some(other) => Err(anyhow!("Cannot pass {} to a unit, its {} variable would overwrite an item of {}", key, name, other)),
+            None => Ok(format!("{}={}\n", name, quote(value))),
+        }
+    };
+
     let mut argstr = String::new();
-    for (key, value) in args.values.iter() {
-        argstr.push_str(&format!("{}=\"{}\"\n", key, value));
+    for key in keys {
+        match &args.values[key] {
+            Value::List(items) => {
+                let joined = items.iter().map(reveal).collect::<Vec<_>>().join("\n");
+                argstr.push_str(&format!("{}={}\n", key, quote(&joined)));
+                argstr.push_str(&item(key, format!("{}_count", key), &items.len().to_string())?);
+                for (i, entry) in items.iter().enumerate() {
+                    argstr.push_str(&item(key, format!("{}_{}", key, i), &reveal(entry))?);
+                }
+            },
+            Value::Map(entries) => {
+                let names = entries.keys().cloned().collect::<Vec<_>>().join("\n");
+                argstr.push_str(&format!("{}={}\n", key, quote(&names)));
+                for (entry_key, entry) in entries.iter() {
+                    if !entry_key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        return Err(anyhow!("Cannot pass {} to a unit, its key {} can't be used in a shell variable name", key, entry_key));
+                    }
+                    argstr.push_str(&item(key, format!("{}_{}", key, entry_key), &reveal(entry))?);
+                }
+            },
+            value => argstr.push_str(&format!("{}={}\n", key, quote(&reveal(value)))),
+        }
+    }
+    Ok(argstr)
+}
+
+/// Secrets are only redacted when they're displayed, hooks need them as they are
+fn reveal(value: &Value) -> String {
+    match value {
+        Value::Secret(secret) => secret.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Quotes a string for POSIX sh.  Nothing is special within single quotes, so the
+/// only thing to escape is a single quote itself, by closing the quotes around it.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Names which can be assigned as shell variables, excluding those the shell slug
+/// reserves for itself
+pub fn is_shell_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_ok = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    starts_ok
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("_sysu")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::process::Command;
+    use crate::testing::TempDir;
+
+    /// Runs the assignments for the given values through /bin/sh, then prints each of
+    /// them back out so they can be compared with what went in
+    fn round_trip(values: &[(&str, &str)]) -> Vec<String> {
+        let mut args = ValueSet::new();
+        for (key, value) in values {
+            args.add_value(key, Value::String(value.to_string()));
+        }
+
+        let mut script = args_str(&args).unwrap();
+        for (key, _) in values {
+            script.push_str(&format!("printf '%s\\000' \"${}\"\n", key));
+        }
+
+        let output = Command::new("/bin/sh").arg("-c").arg(&script).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        stdout.split_terminator('\0').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_args_round_trip() {
+        let dir = TempDir::new("quote");
+        let canary = dir.join("pwned");
+        let touch = format!("touch {}", canary.display());
+
+        let hostile = [
+            ("double", "say \"hi\""),
+            ("single", "it's 'quoted'"),
+            ("dollar", "$HOME ${PATH} $0"),
+            ("subst", &format!("$({})", touch)),
+            ("backtick", &format!("`{}`", touch)),
+            ("semicolon", &format!("x; {}", touch)),
+            ("backslash", "C:\\dir\\ \\n \\'"),
+            ("newline", "first\nsecond\n"),
+            ("glob", "* ? [a-z]"),
+            ("empty", ""),
+            ("unicode", "héllo wörld ✓"),
+        ];
+
+        let result = round_trip(&hostile);
+        let expected: Vec<String> = hostile.iter().map(|(_, v)| v.to_string()).collect();
+        assert_eq!(result, expected);
+        assert!(!canary.exists(), "a value was executed by the shell");
+    }
+
+    #[test]
+    fn test_collection_args() {
+        let mut args = ValueSet::new();
+        args.add_value("pkgs", Value::List(vec![
+            Value::String("git".to_string()),
+            Value::String("it's $(x)".to_string()),
+        ]));
+        args.add_value("env", Value::Map(BTreeMap::from([
+            ("A".to_string(), Value::Int(1)),
+            ("B_2".to_string(), Value::String("two words".to_string())),
+        ])));
+        args.add_value("token", Value::Secret("it's secret".to_string()));
+
+        let script = args_str(&args).unwrap() + r#"
+            printf '%s|' "$pkgs_count" "$pkgs_0" "$pkgs_1" "$env_A" "$env_B_2" "$token"
+            for key in $env; do printf '%s,' "$key"; done
+            printf '%s' "$pkgs" | wc -l | tr -d ' '
+        "#;
+        let output = Command::new("/bin/sh").arg("-c").arg(&script).output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout, "2|git|it's $(x)|1|two words|it's secret|A,B_2,1\n");
+
+        let mut args = ValueSet::new();
+        args.add_value("env", Value::Map(BTreeMap::from([("a-b".to_string(), Value::Int(1))])));
+        assert!(args_str(&args).is_err());
+
+        // Each item's variable mustn't overwrite another arg, or another collection's items
+        let list = Value::List(vec![Value::String("git".to_string())]);
+        let mut args = ValueSet::new();
+        args.add_value("pkgs", list.clone());
+        args.add_value("pkgs_count", Value::Int(3));
+        assert_eq!(args_str(&args).unwrap_err().to_string(), "Cannot pass pkgs to a unit, its pkgs_count variable would overwrite the pkgs_count arg");
+
+        let mut args = ValueSet::new();
+        args.add_value("env_PATH", Value::String("/bin".to_string()));
+        args.add_value("env", Value::Map(BTreeMap::from([("PATH".to_string(), Value::Int(1))])));
+        assert!(args_str(&args).is_err());
+
+        let mut args = ValueSet::new();
+        args.add_value("a_b", list);
+        args.add_value("a", Value::Map(BTreeMap::from([("b_0".to_string(), Value::Int(1))])));
+        assert_eq!(args_str(&args).unwrap_err().to_string(), "Cannot pass a_b to a unit, its a_b_0 variable would overwrite an item of a");
+    }
+
+    #[test]
+    fn test_emit_round_trip() {
+        use crate::models::ValueType;
+        use crate::parser::{parse_emitted_value, parse_undeclared_value};
+
+        // Each value is quoted by the slug as emit_value would, then read back as emitted
+        let literal = |value: &str| {
+            let script = format!("{}\n_sysu_literal \"$V\"", SHELL_SLUG);
+            let output = Command::new("/bin/sh").arg("-c").arg(&script).env("V", value).output().unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        for value in ["[WARN] disk [sda]", "{not a map", "say \"hi\"", "'single'", "C:\\dir\\n", "two\nlines\n", "b64:aGk=", "<<END", ""] {
+            let emitted = literal(value);
+            assert_eq!(parse_emitted_value(&emitted, &ValueType::String).unwrap(), Value::String(value.to_string()));
+            assert_eq!(parse_undeclared_value(&emitted).unwrap(), Value::String(value.to_string()));
+        }
+
+        // The declared type decides what's read, otherwise it's what the value looks like
+        assert_eq!(parse_emitted_value(&literal("0700"), &ValueType::String).unwrap(), Value::String("0700".to_string()));
+        assert_eq!(parse_emitted_value(&literal("[a, b]"), &ValueType::String).unwrap(), Value::String("[a, b]".to_string()));
+        assert_eq!(parse_undeclared_value(&literal("12")).unwrap(), Value::Int(12));
+        assert_eq!(
+            parse_emitted_value(&literal("[a, b]"), &ValueType::List).unwrap(),
+            Value::List(vec![Value::String("a".to_string()), Value::String("b".to_string())]),
+        );
+    }
+
+    #[test]
+    fn test_hook_stderr() {
+        // Stderr is framed apart from stdout, so neither splits the other's lines
+        let hook = "(\nprintf partial; echo err >&2; echo ' rest'; head -c 70000 /dev/zero | tr '\\0' x >&2; echo >&2; exit 3\n)";
+        let script = format!("_sysu_nonce=n0nce\n{}\n{}", SHELL_SLUG, hook_command(hook));
+        let output = Command::new("/bin/sh").arg("-c").arg(&script).output().unwrap();
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout, "partial rest\n\n\x01sysu2:n0nce:status:1\x023\x03");
+
+        let stderr: Vec<StderrLine> = output.stderr
+            .split_inclusive(|b| *b == b'\n')
+            .map(|line| StderrLine::parse(line, "n0nce"))
+            .collect();
+        assert_eq!(stderr, vec![
+            StderrLine::Hook("err".to_string()),
+            StderrLine::Hook("x".repeat(70000)),
+            StderrLine::Sync,
+        ]);
+    }
+
+    #[test]
+    fn test_args_str_rejects_bad_names() {
+        for name in ["foo;rm", "1abc", "a-b", "a.b", "", "$x", "_sysu_nonce"] {
+            let mut args = ValueSet::new();
+            args.add_value(name, Value::Bool(true));
+            assert!(args_str(&args).is_err(), "{} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_is_shell_identifier() {
+        for name in ["foo", "_foo", "FOO_BAR2", "x"] {
+            assert!(is_shell_identifier(name), "{} should be accepted", name);
+        }
     }
-    argstr
 }
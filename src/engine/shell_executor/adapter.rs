@@ -17,7 +17,7 @@ pub fn build_command(target: &Target, opts: &EngineOpts) -> Result<Command> {
             env: HashMap::new(),
         })
     } else if target.proto == "ssh" {
-        return Ok(Command {
+        Ok(Command {
             cmd: "ssh".into(),
             args: vec![target.user_host_string()],
             env: HashMap::new(),
@@ -26,7 +26,7 @@ pub fn build_command(target: &Target, opts: &EngineOpts) -> Result<Command> {
         if target.host != "localhost" {
             return Err(anyhow!("Local target must have host 'localhost'"))
         }
-        return Ok(Command {
+        Ok(Command {
             cmd: "/bin/sh".into(),
             args: Vec::new(),
             env: HashMap::new(),
@@ -40,7 +40,7 @@ pub fn build_command(target: &Target, opts: &EngineOpts) -> Result<Command> {
         args.push(target.host.clone());
         args.push("/bin/sh".into());
 
-        return Ok(Command {
+        Ok(Command {
             cmd: "podman".into(),
             args,
             env: HashMap::new()
@@ -1,22 +1,87 @@
 //! Parses emit messages and stdout lines from a unit's stdout
 use anyhow::{Result, anyhow, Context};
 
-use futures::io::AsyncRead;
+use async_std::channel::Receiver;
+use futures::{io::AsyncRead, future::{self, Either}};
 
-use crate::models::{EmitMessage, OpStatus, ValueSet, Meta, CheckPresence, StdoutData, FileDependency, Dependencies};
-use crate::parser::{parse_deps, parse_params, parse_value, parse_args};
+use crate::models::{EmitMessage, OpStatus, Param, Value, ValueSet, ValueType, Meta, CheckPresence, StdoutData, FileDependency, Dependencies};
+use crate::parser::{parse_deps, parse_params, parse_emitted_value, parse_undeclared_value, parse_args, parse_version};
 use crate::events::{OpEventHandler, OpEvent};
 
-use super::stdout_data::StdoutDataProducer;
+use super::stdout_data::{StdoutDataProducer, StderrLine};
+use super::is_shell_identifier;
 
 /// Provides a useful interface for reading messages from the emit channel
 pub struct MessageStream<R: AsyncRead + Unpin> {
     data_producer: StdoutDataProducer<R>,
+    /// Lines written to stderr by hooks and the adapter, which are reported as they arrive
+    stderr: Receiver<StderrLine>,
+    /// Adapter stderr seen so far, to explain the output stream ending early
+    stderr_lines: Vec<String>,
+    /// Whether all of the running hook's stderr has arrived
+    stderr_synced: bool,
 }
 
 impl<R: AsyncRead + Unpin> MessageStream<R> {
-    pub fn new(data_producer: StdoutDataProducer<R>) -> Self {
-        Self { data_producer }
+    pub fn new(data_producer: StdoutDataProducer<R>, stderr: Receiver<StderrLine>) -> Self {
+        Self { data_producer, stderr, stderr_lines: Vec::new(), stderr_synced: false }
+    }
+
+    /// Waits for the next piece of stdout data, reporting any stderr which arrives in
+    /// the meantime
+    async fn next_data(&mut self, ev_handler: &OpEventHandler) -> Result<Option<StdoutData>> {
+        loop {
+            if self.stderr.is_closed() && self.stderr.is_empty() {
+                return self.data_producer.next().await;
+            }
+
+            let data = Box::pin(self.data_producer.next());
+            let line = match future::select(data, self.stderr.recv()).await {
+                Either::Left((data, _)) => return data,
+                Either::Right((line, _)) => line,
+            };
+            if let Ok(line) = line {
+                self.stderr_line(line, ev_handler)?;
+            }
+        }
+    }
+
+    /// Waits for the rest of the hook's stderr, which arrives apart from its stdout, so
+    /// all of it is reported before the operation finishes
+    async fn sync_stderr(&mut self, ev_handler: &OpEventHandler) -> Result<()> {
+        while !self.stderr_synced {
+            match self.stderr.recv().await {
+                Ok(line) => self.stderr_line(line, ev_handler)?,
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds an error for output ending before the operation finished, including whatever
+    /// the adapter wrote to stderr, since that's usually the reason
+    async fn unexpected_eof(&mut self, ev_handler: &OpEventHandler) -> Result<anyhow::Error> {
+        while let Ok(line) = self.stderr.recv().await {
+            self.stderr_line(line, ev_handler)?;
+        }
+
+        if self.stderr_lines.is_empty() {
+            Ok(anyhow!("Unexpected EOF parsing output stream"))
+        } else {
+            Ok(anyhow!("Executor exited unexpectedly: {}", self.stderr_lines.join("\n")))
+        }
+    }
+
+    fn stderr_line(&mut self, line: StderrLine, ev_handler: &OpEventHandler) -> Result<()> {
+        match line {
+            StderrLine::Hook(text) => ev_handler.handle(OpEvent::Output(StdoutData::Stderr(text)))?,
+            StderrLine::Sync => self.stderr_synced = true,
+            StderrLine::Adapter(text) => {
+                ev_handler.handle(OpEvent::AdapterStderr(text.clone()))?;
+                self.stderr_lines.push(text);
+            },
+        }
+        Ok(())
     }
 
     /// When a unit script command is run, it will emit messages during the operation,
@@ -26,19 +91,24 @@ impl<R: AsyncRead + Unpin> MessageStream<R> {
     /// message, at which point it returns the status and the messages that were read.
     async fn drain_messages(&mut self, ev_handler: OpEventHandler) -> Result<(OpStatus, Vec<EmitMessage>)> {
         let mut messages = Vec::new();
+        self.stderr_synced = false;
 
         loop {
-            let message = self.data_producer.next().await?;
+            let message = self.next_data(&ev_handler).await?;
             match message {
-                None => return Err(anyhow!("Unexpected EOF parsing output stream")),
+                None => return Err(self.unexpected_eof(&ev_handler).await?),
                 Some(data) => {
                     ev_handler.handle(OpEvent::Output(data.clone()))?;
                     match data {
-                        StdoutData::TextLine(_) => (),
+                        StdoutData::TextLine(_) | StdoutData::Binary(_) | StdoutData::Stderr(_) => (),
                         StdoutData::Message(emit_message) => {
                             if emit_message.header.name == "status" {
                                 let status = OpStatus::from_code(&emit_message.text)?;
+                                self.sync_stderr(&ev_handler).await?;
                                 return Ok((status, messages));
+                            } else if emit_message.header.name == "sync" {
+                                // Adapters which merge stderr into stdout deliver it here instead
+                                self.stderr_synced = true;
                             } else {
                                 messages.push(emit_message);
                             }
@@ -86,6 +156,9 @@ impl<R: AsyncRead + Unpin> MessageStream<R> {
                 "unit" => {
                     let parsed_deps = parse_deps(&dep_msg.text).
                         context(format!("Failed to parse dependency: {}", &dep_msg.text))?;
+                    for capture in parsed_deps.iter().flat_map(|dep| dep.captures.iter()) {
+                        check_default(&capture.name, &capture.value_type, &capture.default)?;
+                    }
                     deps.units.extend(parsed_deps);
                 },
                 "file" => {
@@ -106,6 +179,8 @@ impl<R: AsyncRead + Unpin> MessageStream<R> {
         status.expect_ok()?;
 
         let mut meta = Meta::empty();
+        // Descriptions can be given before the params they describe, so they're applied last
+        let mut param_descs = Vec::new();
 
         for message in messages.iter() {
             let field = match message.header.field {
@@ -116,14 +191,47 @@ impl<R: AsyncRead + Unpin> MessageStream<R> {
             match field.as_str() {
                 "author" => meta.author = Some(message.text.clone()),
                 "desc" => meta.desc = Some(message.text.clone()),
-                "version" => meta.version = Some(message.text.clone()),
+                "version" => {
+                    meta.version = Some(parse_version(&message.text).
+                        context(format!("Failed to parse version: {}", &message.text))?);
+                },
                 "params" => {
                     meta.params = parse_params(&message.text).
                         context(format!("Failed to parse param: {}", &message.text))?;
+                    // Params are passed to hooks as shell variables, so they must be nameable as one
+                    if let Some(param) = meta.params.iter().find(|p| !is_shell_identifier(&p.name)) {
+                        return Err(anyhow!("Invalid parameter name {}, it must be a valid shell variable name", param.name));
+                    }
+                    for param in meta.params.iter_mut() {
+                        check_default(&param.name, &param.value_type, &param.default)?;
+                        param.validate()?;
+                    }
+                },
+                "param_desc" => {
+                    let (name, desc) = message.text.trim().split_once(' ')
+                        .ok_or_else(|| anyhow!("Parameter description must be in the form of NAME DESCRIPTION: {}", message.text))?;
+                    param_descs.push((name.to_string(), desc.trim().to_string()));
+                },
+                "emits" => {
+                    meta.emits = parse_params(&message.text).
+                        context(format!("Failed to parse emits: {}", &message.text))?;
+                    if let Some(emit) = meta.emits.iter().find(|e| e.default.is_some()) {
+                        return Err(anyhow!("Emit {} has a default, defaults can only be given to params and captures", emit.name));
+                    }
+                    for emit in meta.emits.iter_mut() {
+                        emit.validate()?;
+                    }
                 },
                 _ => return Err(anyhow!("Unexpected message type for meta operation: {:?}", message)),
             }
         }
+
+        for (name, desc) in param_descs {
+            match meta.params.iter_mut().find(|p| p.name == name) {
+                Some(param) => param.desc = Some(desc),
+                None => return Err(anyhow!("Description given for undeclared parameter {}", name)),
+            }
+        }
         Ok(meta)
     }
 
@@ -131,7 +239,7 @@ impl<R: AsyncRead + Unpin> MessageStream<R> {
     //
     // The check operation can emit values, and also emits a presence message to indicate if the
     // unit is present on the system. This defaults to false.
-    pub async fn get_check_values(&mut self, ev_handler: OpEventHandler) -> Result<(bool, ValueSet)> {
+    pub async fn get_check_values(&mut self, ev_handler: OpEventHandler, emits: &[Param]) -> Result<(bool, ValueSet)> {
         let (status, messages) = self.drain_messages(ev_handler).await?;
         status.expect_ok()?;
         let mut vset = ValueSet::new();
@@ -150,12 +258,7 @@ impl<R: AsyncRead + Unpin> MessageStream<R> {
                     present = Some(presence_bool)
                 },
                 "value" => {
-                    let value = parse_value(&message.text)
-                        .context(format!("Could not parse emitted value: {}", &message.text))?;
-                    let key = match message.header.field {
-                        Some(key) => key.clone(),
-                        None => return Err(anyhow!("Value message missing field")),
-                    };
+                    let (key, value) = emitted_value(&message, emits)?;
                     vset.add_value(&key, value);
                 },
                 _ => return Err(anyhow!("Unexpected message type for check operation: {:?}", message)),
@@ -167,36 +270,115 @@ impl<R: AsyncRead + Unpin> MessageStream<R> {
         Ok((check_presence, vset))
     }
 
-    pub async fn get_apply_values(&mut self, ev_handler: OpEventHandler) -> Result<ValueSet> {
-        let (status, vset) = self.get_values(ev_handler).await?;
+    pub async fn get_apply_values(&mut self, ev_handler: OpEventHandler, emits: &[Param]) -> Result<ValueSet> {
+        let (status, vset) = self.get_values(ev_handler, emits).await?;
         status.expect_ok()?;
         Ok(vset)
     }
 
-    pub async fn get_remove_values(&mut self, ev_handler: OpEventHandler) -> Result<ValueSet> {
-        let (status, vset) = self.get_values(ev_handler).await?;
+    pub async fn get_remove_values(&mut self, ev_handler: OpEventHandler, emits: &[Param]) -> Result<ValueSet> {
+        let (status, vset) = self.get_values(ev_handler, emits).await?;
         status.expect_ok()?;
         Ok(vset)
     }
 
-    async fn get_values(&mut self, ev_handler: OpEventHandler) -> Result<(OpStatus, ValueSet)> {
+    async fn get_values(&mut self, ev_handler: OpEventHandler, emits: &[Param]) -> Result<(OpStatus, ValueSet)> {
         let mut vset = ValueSet::new();
         let (status, drained_messages) = self.drain_messages_of_type("value", ev_handler).await?;
 
         for message in drained_messages {
-            let value = parse_value(&message.text).
-                map_err(|e| anyhow!("Could not parse emitted value: \"{}\": {}", &message.text, e))?;
-            let key = match message.header.field {
-                Some(key) => key.clone(),
-                None => return Err(anyhow!("Value message missing field")),
-            };
+            let (key, value) = emitted_value(&message, emits)?;
             vset.add_value(&key, value);
         }
 
         Ok((status, vset))
     }
 
-    pub fn finalize(self) -> Result<()> {
-        self.data_producer.finalize()
+    /// Closes stdout, handing back stderr so it can be read until the adapter exits
+    pub fn finalize(self) -> Result<Receiver<StderrLine>> {
+        self.data_producer.finalize()?;
+        Ok(self.stderr)
+    }
+}
+
+/// Reads a value message, as the type of its declared emit if the unit declares one
+fn emitted_value(message: &EmitMessage, emits: &[Param]) -> Result<(String, Value)> {
+    let key = match message.header.field {
+        Some(ref key) => key.clone(),
+        None => return Err(anyhow!("Value message missing field")),
+    };
+    let value = match emits.iter().find(|emit| emit.name == key) {
+        Some(emit) => parse_emitted_value(&message.text, &emit.value_type),
+        None => parse_undeclared_value(&message.text),
+    };
+    let value = value.map_err(|e| anyhow!("Could not parse emitted value: \"{}\": {}", &message.text, e))?;
+    Ok((key, value))
+}
+
+/// Ensures a default given for a param or capture is of its declared type
+fn check_default(name: &str, value_type: &ValueType, default: &Option<Value>) -> Result<()> {
+    match default {
+        Some(default) if default.get_type() != *value_type => Err(anyhow!(
+            "Default for {} is of type {}, not {} as declared", name, default.get_type(), value_type
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventHandler;
+    use crate::models::{Operation, Target, Unit};
+    use async_std::task::block_on;
+    use futures::io::Cursor;
+    use std::sync::Arc;
+
+    /// Reads the meta of a hook which emitted the given messages
+    fn read_meta(messages: &[(&str, &str)]) -> Result<Meta> {
+        let mut input = Vec::new();
+        for (key, text) in messages {
+            input.extend_from_slice(format!("\n\x01sysu2:n0nce:{}:{}\x02{}\x03", key, text.len(), text).as_bytes());
+        }
+        input.extend_from_slice(b"\n\x01sysu2:n0nce:status:1\x020\x03");
+
+        // The sender is dropped straight away, so there's no stderr to wait for
+        let (_, stderr) = async_std::channel::unbounded();
+        let mut stream = MessageStream::new(StdoutDataProducer::new(Cursor::new(input), "n0nce".to_string()), stderr);
+        let unit = Arc::new(Unit::new("pkg.sh".to_string(), ValueSet::new(), Target::default()));
+        block_on(stream.get_meta(EventHandler::new(vec![]).get_op_handler(unit, Operation::Meta)))
+    }
+
+    fn desc<'a>(meta: &'a Meta, name: &str) -> Option<&'a str> {
+        meta.params.iter().find(|param| param.name == name).unwrap().desc.as_deref()
+    }
+
+    #[test]
+    fn test_param_desc() {
+        // The name is split from the description at the first space
+        let meta = read_meta(&[
+            ("meta.params", "!name:string, port:int = 80, verbose:bool"),
+            ("meta.param_desc", "name The package to install, by its name"),
+            ("meta.param_desc", "  port   Port to listen on "),
+        ]).unwrap();
+        assert_eq!(desc(&meta, "name"), Some("The package to install, by its name"));
+        assert_eq!(desc(&meta, "port"), Some("Port to listen on"));
+        assert_eq!(desc(&meta, "verbose"), None);
+
+        // Descriptions can come before the params they describe
+        let meta = read_meta(&[
+            ("meta.param_desc", "name The package to install"),
+            ("meta.params", "!name:string"),
+        ]).unwrap();
+        assert_eq!(desc(&meta, "name"), Some("The package to install"));
+    }
+
+    #[test]
+    fn test_param_desc_errors() {
+        let err = read_meta(&[("meta.params", "!name:string"), ("meta.param_desc", "nmae The package")]).unwrap_err();
+        assert_eq!(err.to_string(), "Description given for undeclared parameter nmae");
+
+        let err = read_meta(&[("meta.params", "!name:string"), ("meta.param_desc", "name")]).unwrap_err();
+        assert!(err.to_string().starts_with("Parameter description must be in the form of NAME DESCRIPTION"));
     }
 }
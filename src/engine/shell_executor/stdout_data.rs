@@ -1,29 +1,35 @@
 use tracing::instrument;
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use futures::io::AsyncRead;
 use async_std::io::ReadExt;
 
 use crate::models::StdoutData;
-use crate::parser::{StreamingResult, parse_stdout_data};
+use crate::parser::{StreamingResult, parse_stdout_data, stdout_data::output_data};
+
+const READ_SIZE: usize = 8192;
 
 /// Parses output from a unit into text lines and messages
 pub struct StdoutDataProducer<R: AsyncRead + Unpin> {
     read_fd: R,
-    buf: String,
+    buf: Vec<u8>,
+    /// Offset of the first unparsed byte in buf, consumed data is only dropped from
+    /// the front of the buffer before reading more, rather than after every parse
+    pos: usize,
+    /// Identifies messages emitted by the executor's shell slug
+    nonce: String,
 }
 
 impl<R: AsyncRead + Unpin> StdoutDataProducer<R> {
-    pub fn new(read_fd: R) -> Self {
-        Self { read_fd, buf: String::new() }
+    pub fn new(read_fd: R, nonce: String) -> Self {
+        Self { read_fd, buf: Vec::new(), pos: 0, nonce }
     }
     // Reads the next bit of data from stdout FD
     #[instrument(skip(self))]
     pub async fn next(&mut self) -> Result<Option<StdoutData>> {
         use StreamingResult::*;
 
-        let buf_len = self.buf.len();
         // first, fill the buf it is empty
-        if buf_len == 0 && !self.read_more().await? {
+        if self.pos == self.buf.len() && !self.read_more().await? {
             // buf is empty, pipe is closed, and we're not in the middle of parsing a
             // message so we can exit normally.
             return Ok(None);
@@ -31,17 +37,23 @@ impl<R: AsyncRead + Unpin> StdoutDataProducer<R> {
 
         loop {
             // try to parse what's in the buffer
-            match parse_stdout_data(&self.buf) {
+            let unparsed = &self.buf[self.pos..];
+            match parse_stdout_data(unparsed, &self.nonce) {
                 Complete(Ok((remaining, message))) => {
-                    self.buf = remaining.to_string();
+                    self.pos += unparsed.len() - remaining.len();
                     return Ok(Some(message));
                 },
-                Complete(Err(e)) => return Err(e.context(format!("Error parsing emit message: {}", self.buf))),
+                Complete(Err(e)) => {
+                    let unparsed = String::from_utf8_lossy(unparsed);
+                    return Err(e.context(format!("Error parsing emit message: {}", unparsed)))
+                },
                 Incomplete => {
                     if !self.read_more().await? {
-                        // If we can't read more data, but we're in the middle of parsing a message, we
-                        // have an unexpected EOF.
-                        return Err(anyhow!("Unexpected EOF parsing emit stream! buffer: {:?}", self.buf));
+                        // If the pipe closed in the middle of a line or something that looked like
+                        // a message, it can only be output
+                        let data = output_data(&self.buf[self.pos..]);
+                        self.pos = self.buf.len();
+                        return Ok(Some(data));
                     } else {
                         continue;
                     }
@@ -52,18 +64,17 @@ impl<R: AsyncRead + Unpin> StdoutDataProducer<R> {
 
     /// Reads more data into the buffer
     async fn read_more(&mut self) -> Result<bool> {
-        let mut tmp_buf = [0u8; 1024];
+        // Drop data which has already been parsed before growing the buffer
+        self.buf.drain(..self.pos);
+        self.pos = 0;
 
-        let n_bytes_read = self.read_fd.read(&mut tmp_buf).await?;
-
-        if n_bytes_read == 0 {
-            return Ok(false)
-        }
+        // Read into a separate chunk so the buffer is untouched if this future is dropped
+        // while it waits, which happens when stderr arrives first in MessageStream
+        let mut chunk = [0; READ_SIZE];
+        let n_bytes_read = self.read_fd.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..n_bytes_read]);
 
-        let as_str = String::from_utf8_lossy(&tmp_buf[..n_bytes_read]);
-
-        self.buf.push_str(&as_str);
-        Ok(true)
+        Ok(n_bytes_read > 0)
     }
 
     pub fn finalize(self) -> Result<()> {
@@ -71,3 +82,98 @@ impl<R: AsyncRead + Unpin> StdoutDataProducer<R> {
         Ok(())
     }
 }
+
+/// A line read from the executor's stderr
+#[derive(Debug, PartialEq)]
+pub enum StderrLine {
+    /// A line a hook wrote to stderr, framed by the shell slug
+    Hook(String),
+    /// Sent by the shell slug once all of a hook's stderr has been written
+    Sync,
+    /// Anything else, written by the adapter
+    Adapter(String),
+}
+
+impl StderrLine {
+    /// Reads a line, which is only taken as the slug's if it's framed with the executor's
+    /// nonce.  Lines which aren't UTF-8 are read lossily rather than dropped.
+    pub fn parse(line: &[u8], nonce: &str) -> Self {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let frame = match line.strip_prefix(format!("\x01sysu2:{}:", nonce).as_bytes()) {
+            Some(frame) => frame,
+            None => return StderrLine::Adapter(String::from_utf8_lossy(line).to_string()),
+        };
+
+        if frame == b"sync:0\x02\x03" {
+            return StderrLine::Sync;
+        }
+        // Lines are framed one at a time, so the text is whatever's between the delimiters
+        let text = frame.strip_prefix(b"stderr:")
+            .and_then(|frame| frame.strip_suffix(b"\x03"))
+            .and_then(|frame| frame.iter().position(|b| *b == b'\x02').map(|start| &frame[start + 1..]));
+        match text {
+            Some(text) => StderrLine::Hook(String::from_utf8_lossy(text).to_string()),
+            None => StderrLine::Adapter(String::from_utf8_lossy(line).to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::emit::Header;
+    use async_std::task::block_on;
+    use futures::io::Cursor;
+
+    /// Yields the wrapped bytes a few at a time, to split data across reads
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let len = buf.len().min(3);
+            std::pin::Pin::new(&mut self.0).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_split_reads() {
+        let mut input = "héllo wörld\n\x01sysu2:n0nce:value.name:8\x02jérôme\x03\n".as_bytes().to_vec();
+        input.extend_from_slice(b"\xff\xfe\nlast");
+
+        let mut producer = StdoutDataProducer::new(Trickle(Cursor::new(input)), "n0nce".to_string());
+        let mut data = Vec::new();
+        while let Some(next) = block_on(producer.next()).unwrap() {
+            data.push(next);
+        }
+
+        assert_eq!(data, vec![
+            StdoutData::TextLine("héllo wörld".to_string()),
+            StdoutData::Message(crate::models::EmitMessage {
+                header: Header::build("value", Some("name")),
+                text: "jérôme".to_string(),
+            }),
+            StdoutData::Binary(vec![0xff, 0xfe]),
+            StdoutData::TextLine("last".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_stderr_line() {
+        let parse = |line: &[u8]| StderrLine::parse(line, "n0nce");
+        assert_eq!(parse(b"\x01sysu2:n0nce:stderr:9\x02no such x\x03\n"), StderrLine::Hook("no such x".to_string()));
+        assert_eq!(parse(b"\x01sysu2:n0nce:stderr:2\x02\xff\xfe\x03\n"), StderrLine::Hook("\u{fffd}\u{fffd}".to_string()));
+        assert_eq!(parse(b"\x01sysu2:n0nce:sync:0\x02\x03\n"), StderrLine::Sync);
+        assert_eq!(parse(b"ssh: connect to host web1 port 22: Connection refused\r\n"), StderrLine::Adapter(
+            "ssh: connect to host web1 port 22: Connection refused".to_string(),
+        ));
+
+        // Frames without the executor's nonce can't pass for the slug's
+        assert_eq!(parse(b"\x01sysu2:other:sync:0\x02\x03\n"), StderrLine::Adapter("\x01sysu2:other:sync:0\x02\x03".to_string()));
+        assert_eq!(parse(b"\x01sysu2:n0nce:value.x:1\x02y\x03"), StderrLine::Adapter("\x01sysu2:n0nce:value.x:1\x02y\x03".to_string()));
+    }
+}
@@ -57,6 +57,10 @@ impl Subprocess {
         self.child.stderr.as_mut().unwrap()
     }
 
+    pub fn take_stderr(&mut self) -> ChildStderr {
+        self.child.stderr.take().unwrap()
+    }
+
     pub fn close_stdin(&mut self) -> Result<()> {
         self.child.stdin.take();
         Ok(())
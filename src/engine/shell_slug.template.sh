@@ -1,27 +1,57 @@
-exec 2>&1
+# Messages are written to fd 3, so they still reach sysunit if a hook redirects its output
+exec 3>&1
+# Hooks have their stderr framed by _sysu_stderr onto fd 6, the shell's own stderr, while
+# errors from the slug itself are output
+exec 6>&2 2>&1
 
 meta() : ;
 deps() : ;
 check() : ;
 apply() : ;
 rollback() : ;
-dep() _emit dep.unit $@;
-file() _emit dep.file $@;
-author() _emit meta.author $@;
-desc() _emit meta.desc $@;
-params() _emit meta.params $@;
-emits() _ emit meta.emits $@;
+dep() _emit dep.unit "$@";
+file() _emit dep.file "$@";
+author() _emit meta.author "$@";
+desc() _emit meta.desc "$@";
+version() _emit meta.version "$@";
+params() _emit meta.params "$@";
+param_desc() _emit meta.param_desc "$@";
+emits() _emit meta.emits "$@";
 present() _emit present true;
 
 emit_value() {
   local key="${1:?key must be provided to emit_value}"
   shift
-  _emit "value.${key}" "$@"
+  _emit "value.${key}" "$(_sysu_literal "$*")"
+}
+
+# Quotes an emitted value as a string, so it's received as exactly what was emitted.  It's
+# then read as the type its emit declares, or what it looks like if it isn't declared.
+_sysu_literal() {
+  local escaped
+  # The trailing dot keeps command substitution from dropping trailing newlines
+  escaped=$(printf '%s.' "$1" | sed 's/\\/\\\\/g; s/"/\\"/g')
+  printf '"%s"' "${escaped%.}"
 }
 
 _emit() {
   local key=${1:?key must be provided to _emit}
   shift
-  local val="$@";
-  printf "\n\001${key}\002${val}\003"
+  local val="$*"
+  local len=$(printf '%s' "$val" | wc -c | tr -d ' ')
+  printf '\n\001sysu2:%s:%s:%s\002%s\003' "$_sysu_nonce" "$key" "$len" "$val" >&3
+}
+
+# Frames each line read from a hook's stderr onto fd 6.  Nothing else writes there, so the
+# frames can't be interleaved with the hook's stdout, however long they are.
+_sysu_stderr() {
+  local line
+  while IFS= read -r line || [ -n "$line" ]; do
+    printf '\001sysu2:%s:stderr:%s\002%s\003\n' "$_sysu_nonce" "$(printf '%s' "$line" | wc -c | tr -d ' ')" "$line" >&6
+  done
+}
+
+# Marks the end of a hook's stderr, so all of it can be reported before its status
+_sysu_sync() {
+  printf '\001sysu2:%s:sync:0\002\003\n' "$_sysu_nonce" >&6
 }
@@ -0,0 +1,465 @@
+//! The state store remembers which units have been applied to or removed from each
+//! target across runs.  Each target has a JSON file in the state directory, holding a
+//! record for every unit instance sysunit has run there, keyed by its fingerprint,
+//! along with the graph of units each root unit applied there last.  Runs which are
+//! underway keep a checkpoint of their progress, so they can be resumed if they fail.
+//! A target's file is locked while it's changed, so runs sharing the state directory
+//! don't drop each other's records.
+
+use anyhow::{anyhow, Result, Context};
+use async_std::path::PathBuf;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::models::{Target, Unit, Value, ValueSet, val::{set_secret_salt, REDACTED}};
+use crate::parser::{parse_target, parse_value};
+use super::loader::Script;
+
+/// Whether a unit was last applied to or removed from its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitStatus {
+    Applied,
+    Removed,
+}
+
+/// What was last done with a unit instance on a target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnitRecord {
+    pub name: String,
+    /// Arguments the unit was given, as literals which parse back to the same type
+    pub args: BTreeMap<String, String>,
+    pub args_fingerprint: String,
+    pub script_origin: String,
+    pub script_hash: String,
+    /// Values the unit emitted, as literals
+    pub emitted: BTreeMap<String, String>,
+    pub status: UnitStatus,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// A unit in the graph of a root unit, with enough to build it again so it can be removed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GraphEntry {
+    pub fingerprint: String,
+    pub name: String,
+    pub target: String,
+    /// Arguments the unit was given as literals, with secrets redacted
+    pub args: BTreeMap<String, String>,
+    /// Names of args which were given as text, to be read as the type of their param
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub raw_args: Vec<String>,
+}
+
+impl GraphEntry {
+    /// Describes a unit, redacting args which its params declare as secret
+    pub fn from_unit(unit: &Unit, typed_args: &ValueSet) -> Self {
+        let args = unit.args.values.iter()
+            .map(|(k, v)| match typed_args.get(k) {
+                Some(Value::Secret(_)) => (k.clone(), REDACTED.to_string()),
+                _ => (k.clone(), v.literal()),
+            })
+            .collect();
+
+        Self {
+            fingerprint: unit.fingerprint(),
+            name: unit.name.clone(),
+            target: unit.target.to_string(),
+            args,
+            raw_args: unit.raw_args.clone(),
+        }
+    }
+
+    /// Builds the unit again, which can't be done if any of its args were secret
+    pub fn to_unit(&self) -> Result<Unit> {
+        let mut args = ValueSet::new();
+        for (k, literal) in self.args.iter() {
+            if literal == REDACTED {
+                return Err(anyhow!("Unit {} can't be built again, since its secret arg {} wasn't recorded", self.name, k));
+            }
+            args.add_value(k, parse_value(literal)?);
+        }
+
+        let target = parse_target(&self.target)?;
+        Ok(Unit { raw_args: self.raw_args.clone(), ..Unit::new(self.name.clone(), args, target) })
+    }
+}
+
+/// The records of every unit instance which has been run on a target
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TargetState {
+    pub target: String,
+    pub units: BTreeMap<String, UnitRecord>,
+    /// The units each root unit applied last, by the name of the root, in the order they ran
+    #[serde(default)]
+    pub roots: BTreeMap<String, Vec<GraphEntry>>,
+}
+
+/// Progress of an apply, kept while it's underway and left behind if it fails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub id: String,
+    /// Name and fingerprint of the root unit
+    pub unit: String,
+    pub root: String,
+    /// Units in the order they're run
+    pub order: Vec<GraphEntry>,
+    /// Units which have been run, by fingerprint, with the values they emitted as literals
+    pub completed: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Checkpoint {
+    pub fn new(id: &str, root: &Unit, order: Vec<GraphEntry>) -> Self {
+        Self {
+            id: id.to_string(),
+            unit: root.name.clone(),
+            root: root.fingerprint(),
+            order,
+            completed: BTreeMap::new(),
+        }
+    }
+
+    pub fn complete(&mut self, unit: &Unit, emitted: &ValueSet) {
+        self.completed.insert(unit.fingerprint(), literals(emitted));
+    }
+
+    /// Provides the values a unit emitted if it was completed, unless any were secret, since
+    /// they aren't kept and the unit must be checked again for them
+    pub fn emitted(&self, unit: &Unit) -> Result<Option<ValueSet>> {
+        let literals = match self.completed.get(&unit.fingerprint()) {
+            Some(literals) if !literals.values().any(|literal| literal == REDACTED) => literals,
+            _ => return Ok(None),
+        };
+
+        let mut emitted = ValueSet::new();
+        for (k, literal) in literals {
+            emitted.add_value(k, parse_value(literal)?);
+        }
+        Ok(Some(emitted))
+    }
+}
+
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    /// Opens the state directory, creating it if needed.  The salt secrets are hashed with
+    /// is kept there, so units with secret args keep their fingerprints between runs.
+    pub fn open(dir: &PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .context(format!("Failed to create state directory {:?}", dir))?;
+
+        let salt_path = dir.join("salt");
+        let salt = match std::fs::read_to_string(&salt_path) {
+            Ok(text) => u64::from_str_radix(text.trim(), 16)
+                .context(format!("Failed to parse secret salt in {:?}", salt_path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let salt = rand::random::<u64>();
+                write_private(&salt_path, &format!("{:016x}\n", salt))?;
+                salt
+            },
+            Err(e) => return Err(e).context(format!("Failed to read secret salt in {:?}", salt_path)),
+        };
+        set_secret_salt(salt)?;
+
+        Ok(Self { dir: dir.clone() })
+    }
+
+    /// Provides the records kept for a target, which are empty if nothing has been run on it
+    pub fn load(&self, target: &Target) -> Result<TargetState> {
+        let path = self.path_for(target);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .context(format!("Failed to parse state file {:?}", path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(TargetState { target: target.to_string(), ..TargetState::default() })
+            },
+            Err(e) => Err(e).context(format!("Failed to read state file {:?}", path)),
+        }
+    }
+
+    /// Records that a unit was applied or removed, along with the script it ran, its args
+    /// as read for its params, and the values it emitted
+    pub fn record(&self, unit: &Unit, status: UnitStatus, script: &Script, args: &ValueSet, emitted: &ValueSet) -> Result<()> {
+        let record = UnitRecord {
+            name: unit.name.clone(),
+            args: literals(args),
+            args_fingerprint: unit.args.fingerprint(),
+            script_origin: script.origin.clone(),
+            script_hash: script.hash(),
+            emitted: literals(emitted),
+            status,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        };
+        self.update(&unit.target, |state| {
+            state.units.insert(unit.fingerprint(), record);
+        })
+    }
+
+    /// Provides the graph of units the root unit applied last on its target
+    pub fn previous_graph(&self, root: &Unit) -> Result<Vec<GraphEntry>> {
+        let mut state = self.load(&root.target)?;
+        Ok(state.roots.remove(&root.name).unwrap_or_default())
+    }
+
+    /// Records the graph of units the root unit has applied on its target
+    pub fn record_graph(&self, root: &Unit, graph: Vec<GraphEntry>) -> Result<()> {
+        self.update(&root.target, |state| {
+            state.roots.insert(root.name.clone(), graph);
+        })
+    }
+
+    /// Provides the fingerprints of units in the graphs of every root unit on any target,
+    /// other than the one given
+    pub fn units_of_other_roots(&self, root: &Unit) -> Result<HashSet<String>> {
+        let root_target = root.target.to_string();
+        let mut fingerprints = HashSet::new();
+
+        let entries = std::fs::read_dir(&self.dir)
+            .context(format!("Failed to read state directory {:?}", self.dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let contents = std::fs::read_to_string(&path)?;
+            let state: TargetState = serde_json::from_str(&contents)
+                .context(format!("Failed to parse state file {:?}", path))?;
+
+            for (name, graph) in state.roots {
+                if state.target == root_target && name == root.name {
+                    continue;
+                }
+                fingerprints.extend(graph.into_iter().map(|entry| entry.fingerprint));
+            }
+        }
+
+        Ok(fingerprints)
+    }
+
+    pub fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let dir = self.dir.join("runs");
+        std::fs::create_dir_all(&dir)
+            .context(format!("Failed to create checkpoint directory {:?}", dir))?;
+        let contents = serde_json::to_string_pretty(checkpoint)?;
+        write_private(&self.checkpoint_path(&checkpoint.id), &(contents + "\n"))
+    }
+
+    pub fn load_checkpoint(&self, id: &str) -> Result<Checkpoint> {
+        let path = self.checkpoint_path(id);
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|_| anyhow!("No checkpoint was found for run {}, only runs which failed can be resumed", id))?;
+        serde_json::from_str(&contents)
+            .context(format!("Failed to parse checkpoint {:?}", path))
+    }
+
+    /// Drops the checkpoint of a run once it has finished
+    pub fn remove_checkpoint(&self, id: &str) -> Result<()> {
+        let path = self.checkpoint_path(id);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).context(format!("Failed to remove checkpoint {:?}", path))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn checkpoint_path(&self, id: &str) -> PathBuf {
+        let name: String = id.chars().filter(|c| c.is_alphanumeric() || *c == '-').collect();
+        self.dir.join("runs").join(format!("{}.json", name))
+    }
+
+    /// Loads a target's state, changes it and saves it again, holding the target's lock
+    /// throughout so another run can't save in between
+    fn update(&self, target: &Target, change: impl FnOnce(&mut TargetState)) -> Result<()> {
+        let _lock = self.lock(target)?;
+        let mut state = self.load(target)?;
+        change(&mut state);
+        self.save(target, &state)
+    }
+
+    /// Takes an exclusive lock on a target, held until it's dropped.  The lock is on a file
+    /// beside the state file, since that's replaced whenever it's saved.
+    fn lock(&self, target: &Target) -> Result<Flock<std::fs::File>> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = self.path_for(target).with_extension("lock");
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)
+            .context(format!("Failed to open lock file {:?}", path))?;
+        Flock::lock(file, FlockArg::LockExclusive)
+            .map_err(|(_, e)| anyhow!("Failed to lock {:?}: {}", path, e))
+    }
+
+    fn save(&self, target: &Target, state: &TargetState) -> Result<()> {
+        let contents = serde_json::to_string_pretty(state)?;
+        write_private(&self.path_for(target), &(contents + "\n"))
+    }
+
+    fn path_for(&self, target: &Target) -> PathBuf {
+        let name: String = target.to_string()
+            .replace("://", "_")
+            .chars()
+            .map(|c| if c.is_alphanumeric() || "@.-_".contains(c) { c } else { '_' })
+            .collect();
+        self.dir.join(format!("{}.json", name))
+    }
+}
+
+fn literals(values: &ValueSet) -> BTreeMap<String, String> {
+    values.values.iter().map(|(k, v)| (k.clone(), v.literal())).collect()
+}
+
+/// Writes a file readable only by its owner, replacing it whole so a run which is
+/// interrupted can't leave it half written
+fn write_private(path: &PathBuf, contents: &str) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::io::Write;
+
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .context(format!("Failed to write {:?}", tmp_path))?;
+    file.write_all(contents.as_bytes())?;
+    std::fs::rename(&tmp_path, path).context(format!("Failed to write {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Value;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_record() {
+        let dir = TempDir::new("state");
+        let store = StateStore { dir: dir.path().to_path_buf().into() };
+
+        let mut args = ValueSet::new();
+        args.add_value("name", Value::String("git".to_string()));
+        args.add_value("ports", Value::List(vec![Value::Int(80), Value::String("443".to_string())]));
+        let web = Unit::new("pkg.sh".to_string(), args.clone(), Target::new("ssh", Some("admin"), "web1"));
+        let local = Unit::new("pkg.sh".to_string(), args, Target::default());
+        let script = Script { origin: "/units/pkg.sh".to_string(), body: "apply() true;".to_string(), version: None, signature: None };
+
+        let mut emitted = ValueSet::new();
+        emitted.add_value("token", Value::Secret("hunter2".to_string()));
+        store.record(&web, UnitStatus::Applied, &script, &web.args, &emitted).unwrap();
+        store.record(&local, UnitStatus::Applied, &script, &local.args, &ValueSet::new()).unwrap();
+        store.record(&local, UnitStatus::Removed, &script, &local.args, &ValueSet::new()).unwrap();
+
+        // Each target has its own file, and a unit's record is replaced when it runs again
+        let web_state = store.load(&web.target).unwrap();
+        assert_eq!(web_state.target, "ssh://admin@web1");
+        let record = &web_state.units[&web.fingerprint()];
+        assert_eq!(record.status, UnitStatus::Applied);
+        assert_eq!(record.args["name"], "\"git\"");
+        assert_eq!(record.args["ports"], "[80, \"443\"]");
+        assert_eq!(record.emitted["token"], "****");
+        assert_eq!(record.script_hash, script.hash());
+
+        let local_state = store.load(&local.target).unwrap();
+        assert_eq!(local_state.units.len(), 1);
+        assert_eq!(local_state.units[&local.fingerprint()].status, UnitStatus::Removed);
+
+        assert!(store.load(&Target::new("ssh", None, "web2")).unwrap().units.is_empty());
+    }
+
+    #[test]
+    fn test_unreadable_state() {
+        let dir = TempDir::new("state");
+        let store = StateStore { dir: dir.path().to_path_buf().into() };
+
+        // A state file which can't be read is an error, rather than taken as empty and
+        // then saved over
+        let unit = Unit::new("pkg.sh".to_string(), ValueSet::new(), Target::default());
+        let script = Script { origin: "/units/pkg.sh".to_string(), body: "apply() true;".to_string(), version: None, signature: None };
+        std::fs::create_dir_all(store.path_for(&unit.target)).unwrap();
+        assert!(store.load(&unit.target).is_err());
+        assert!(store.record(&unit, UnitStatus::Applied, &script, &unit.args, &ValueSet::new()).is_err());
+        assert!(std::path::Path::new(store.path_for(&unit.target).as_os_str()).is_dir());
+    }
+
+    #[test]
+    fn test_graph() {
+        let dir = TempDir::new("state");
+        let store = StateStore { dir: dir.path().to_path_buf().into() };
+
+        let mut args = ValueSet::new();
+        args.add_value("name", Value::String("8080".to_string()));
+        args.add_value("ratio", Value::Float(1.0));
+        args.add_value("hosts", Value::Map(BTreeMap::from([("a".to_string(), Value::String("x y".to_string()))])));
+        let pkg = Unit::new("pkg.sh".to_string(), args, Target::new("ssh", Some("admin"), "web1"));
+        let site = Unit::new("site.sh".to_string(), ValueSet::new(), Target::default());
+        let other = Unit::new("other.sh".to_string(), ValueSet::new(), Target::default());
+
+        // Units built again from the graph keep their identity
+        let entry = GraphEntry::from_unit(&pkg, &pkg.args);
+        assert_eq!(entry.to_unit().unwrap(), pkg);
+
+        let mut typed_args = pkg.args.clone();
+        typed_args.add_value("name", Value::Secret("8080".to_string()));
+        let secret_entry = GraphEntry::from_unit(&pkg, &typed_args);
+        assert_eq!(secret_entry.args["name"], REDACTED);
+        assert!(secret_entry.to_unit().is_err());
+
+        store.record_graph(&site, vec![entry.clone(), GraphEntry::from_unit(&site, &ValueSet::new())]).unwrap();
+        store.record_graph(&other, vec![entry]).unwrap();
+
+        let graph = store.previous_graph(&site).unwrap();
+        assert_eq!(graph.len(), 2);
+        assert_eq!(graph[0].fingerprint, pkg.fingerprint());
+        assert!(store.previous_graph(&pkg).unwrap().is_empty());
+
+        let held = store.units_of_other_roots(&site).unwrap();
+        assert!(held.contains(&pkg.fingerprint()) && !held.contains(&site.fingerprint()));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = TempDir::new("state");
+        let store = StateStore { dir: dir.path().to_path_buf().into() };
+
+        let dir_unit = Unit::new("dir.sh".to_string(), ValueSet::new(), Target::default());
+        let vault = Unit::new("vault.sh".to_string(), ValueSet::new(), Target::default());
+        let site = Unit::new("site.sh".to_string(), ValueSet::new(), Target::default());
+        let order = [&dir_unit, &vault, &site].iter().map(|unit| GraphEntry::from_unit(unit, &ValueSet::new())).collect();
+
+        let mut checkpoint = Checkpoint::new("20261018T203504-641c", &site, order);
+        let mut emitted = ValueSet::new();
+        emitted.add_value("path", Value::String("/tmp/d".to_string()));
+        emitted.add_value("mode", Value::Int(700));
+        checkpoint.complete(&dir_unit, &emitted);
+        let mut secret = ValueSet::new();
+        secret.add_value("token", Value::Secret("hunter2".to_string()));
+        checkpoint.complete(&vault, &secret);
+        store.save_checkpoint(&checkpoint).unwrap();
+
+        let checkpoint = store.load_checkpoint("20261018T203504-641c").unwrap();
+        assert_eq!(checkpoint.root, site.fingerprint());
+        assert_eq!(checkpoint.order.len(), 3);
+
+        // Values emitted by completed units are given back as they were, but units which
+        // emitted secrets must be run again
+        let restored = checkpoint.emitted(&dir_unit).unwrap().unwrap();
+        assert_eq!(restored.get("path"), Some(&Value::String("/tmp/d".to_string())));
+        assert_eq!(restored.get("mode"), Some(&Value::Int(700)));
+        assert!(checkpoint.emitted(&vault).unwrap().is_none());
+        assert!(checkpoint.emitted(&site).unwrap().is_none());
+
+        store.remove_checkpoint("20261018T203504-641c").unwrap();
+        assert!(store.load_checkpoint("20261018T203504-641c").is_err());
+    }
+}
@@ -1,26 +1,30 @@
 //! Handles execution of operations on units
 use anyhow::Result;
 use crate::models::{
+    Param,
     ValueSet,
+    ValueType,
     Meta,
     Dependencies,
+    version::Version,
 };
 use crate::events::OpEventHandler;
 use super::executor_pool::ExecutorArc;
+use super::loader::Script;
 
 
 /// Manages execution of the given unit. Caches operation results so operations are not run multiple times.
 #[derive(Debug)]
 pub struct UnitExecution {
-    pub script: String,
+    pub script: Script,
     pub args: ValueSet,
     pub emit_data: ValueSet,
     pub deps: Option<Dependencies>,
-    meta: Option<Meta>,
+    pub meta: Option<Meta>,
 }
 
 impl UnitExecution {
-    pub async fn new(script: String) -> Result<UnitExecution> {
+    pub async fn new(script: Script) -> Result<UnitExecution> {
         Ok(UnitExecution {
             script,
             emit_data: ValueSet::new(),
@@ -30,6 +34,14 @@ impl UnitExecution {
         })
     }
 
+    /// Provides the unit's version, as declared in its meta or tagged on its script
+    pub fn version(&self) -> Option<&Version> {
+        self.meta
+            .as_ref()
+            .and_then(|meta| meta.version.as_ref())
+            .or(self.script.version.as_ref())
+    }
+
     /// Sets arguments on the unit that will be used for all proceeding operations
     pub async fn set_args(&mut self, args: &ValueSet) {
         self.args.merge(args);
@@ -41,8 +53,8 @@ impl UnitExecution {
         match self.meta {
             Some(ref meta) => Ok(meta),
             None => {
-                let mut executor = executor.lock().unwrap();
-                let meta = executor.get_meta(op_ev_handler, &self.script, &self.args).await?;
+                let mut executor = executor.lock().await;
+                let meta = executor.get_meta(op_ev_handler, &self.script.body, &self.args).await?;
                 self.meta = Some(meta);
                 Ok(self.meta.as_ref().unwrap())
             },
@@ -55,8 +67,8 @@ impl UnitExecution {
         match self.deps {
             Some(ref deps) => Ok(deps),
             None => {
-                let mut executor = executor.lock().unwrap();
-                let deps = executor.get_deps(op_ev_handler, &self.script, &self.args).await?;
+                let mut executor = executor.lock().await;
+                let deps = executor.get_deps(op_ev_handler, &self.script.body, &self.args).await?;
                 self.deps = Some(deps);
                 Ok(self.deps.as_ref().unwrap())
             },
@@ -64,23 +76,58 @@ impl UnitExecution {
     }
 
     pub async fn remove(&mut self, executor: ExecutorArc, op_ev_handler: OpEventHandler) -> Result<()> {
-        let mut executor = executor.lock().unwrap();
-        let emit_data = executor.remove(op_ev_handler, &self.script, &self.args).await?;
-        self.emit_data.merge(&emit_data);
-        Ok(())
+        let mut executor = executor.lock().await;
+        let emit_data = executor.remove(self.redact_emits(op_ev_handler), &self.script.body, &self.args, self.declared_emits()).await?;
+        self.merge_emit_data(&emit_data)
     }
 
     pub async fn apply(&mut self, executor: ExecutorArc, op_ev_handler: OpEventHandler) -> Result<()> {
-        let mut executor = executor.lock().unwrap();
-        let emit_data = executor.apply(op_ev_handler, &self.script, &self.args).await?;
-        self.emit_data.merge(&emit_data);
-        Ok(())
+        let mut executor = executor.lock().await;
+        let emit_data = executor.apply(self.redact_emits(op_ev_handler), &self.script.body, &self.args, self.declared_emits()).await?;
+        self.merge_emit_data(&emit_data)?;
+        self.check_required_emits()
     }
 
     pub async fn check(&mut self, executor: ExecutorArc, op_ev_handler: OpEventHandler) -> Result<bool> {
-        let mut executor = executor.lock().unwrap();
-        let (status, emit_data) = executor.check(op_ev_handler, &self.script, &self.args).await?;
-        self.emit_data.merge(&emit_data);
+        let mut executor = executor.lock().await;
+        let (status, emit_data) = executor.check(self.redact_emits(op_ev_handler), &self.script.body, &self.args, self.declared_emits()).await?;
+        self.merge_emit_data(&emit_data)?;
+        // A present unit won't be applied, so it must have emitted everything it's required to
+        if status {
+            self.check_required_emits()?;
+        }
         Ok(status)
     }
+
+    /// Emits the unit declares, which emitted values are read as the types of
+    fn declared_emits(&self) -> &[Param] {
+        self.meta.as_ref().map(|meta| &meta.emits[..]).unwrap_or_default()
+    }
+
+    /// Keeps values emitted by an operation, once they're checked against the unit's declared emits
+    fn merge_emit_data(&mut self, emit_data: &ValueSet) -> Result<()> {
+        if let Some(ref meta) = self.meta {
+            meta.check_emitted(emit_data)?;
+        }
+        self.emit_data.merge(emit_data);
+        Ok(())
+    }
+
+    /// Has values the unit declares as secret emits redacted as soon as they're emitted
+    fn redact_emits(&self, op_ev_handler: OpEventHandler) -> OpEventHandler {
+        let secret_emits = self.meta
+            .iter()
+            .flat_map(|meta| meta.emits.iter())
+            .filter(|emit| emit.value_type == ValueType::Secret)
+            .map(|emit| emit.name.clone())
+            .collect();
+        op_ev_handler.with_secret_emits(secret_emits)
+    }
+
+    fn check_required_emits(&self) -> Result<()> {
+        match self.meta {
+            Some(ref meta) => meta.check_required_emits(&self.emit_data),
+            None => Ok(()),
+        }
+    }
 }
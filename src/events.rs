@@ -1,8 +1,11 @@
-/// Events are emitted to oservers so the stages of engine
-/// and unit execution can be reported to the CLI, logging
-/// and telemetry.
+//! Events are emitted to oservers so the stages of engine
+//! and unit execution can be reported to the CLI, logging
+//! and telemetry.
 
-use crate::models::{UnitArc, Operation, OpCompletion, StdoutData, FileDependency};
+use crate::models::{UnitArc, Unit, Operation, OpCompletion, StdoutData, FileDependency, Meta, Value, ValueSet};
+use crate::models::val::REDACTED;
+use crate::models::version::Version;
+use crate::parser::parse_value;
 use std::sync::{Arc, Mutex};
 use anyhow::Result;
 
@@ -10,9 +13,27 @@ use anyhow::Result;
 #[derive(Clone, Debug)]
 pub enum Event {
     Resolving,
-    Resolved(Vec<UnitArc>),
+    /// Units in the order they'll be run, along with the version each was loaded at
+    Resolved(Vec<(UnitArc, Option<Version>)>),
+    /// A bundle was written to the given path with the given number of units
+    Bundled(String, usize),
+    /// A lockfile was written to the given path with the given number of units
+    Locked(String, usize),
+    /// A failed run is being resumed, skipping the units it completed
+    Resuming(String, Vec<UnitArc>),
+    /// Units applied last time which have left the graph, in the order they'll be removed
+    Pruning(Vec<UnitArc>),
+    /// A unit is being loaded so its metadata can be shown
+    Describing,
+    /// The metadata of a unit loaded to be shown with --help
+    Described(UnitArc, Meta),
     Op(UnitArc, Operation, OpEvent),
     Debug(String),
+    /// Something the user should know about which doesn't stop the run
+    Warning(String),
+    /// The engine is done with the root unit, sent before the outcome once any secrets
+    /// among its args are known
+    Finished(UnitArc),
     EngineSuccess,
     Error(String),
 }
@@ -22,6 +43,8 @@ pub enum Event {
 pub enum OpEvent {
     Started,
     Output(StdoutData),
+    /// A line the executor's adapter wrote to stderr, such as an ssh connection error
+    AdapterStderr(String),
     Complete(OpCompletion),
     TransportingFile(FileDependency),
     FileTransported(FileDependency),
@@ -39,14 +62,16 @@ pub type ObserverArc = Arc<Mutex<dyn Observer>>;
 #[derive(Clone)]
 pub struct EventHandler {
     observers: Vec<ObserverArc>,
+    redactor: Redactor,
 }
 
 impl EventHandler {
     pub fn new(observers: Vec<ObserverArc>) -> Self {
-        Self { observers }
+        Self { observers, redactor: Redactor::default() }
     }
 
     pub fn handle(&self, event: Event) -> Result<()> {
+        let event = self.redactor.redact(event);
         for observer in self.observers.iter() {
             observer.lock().unwrap().handle(event.clone())?;
         }
@@ -54,6 +79,13 @@ impl EventHandler {
         Ok(())
     }
 
+    /// Keeps the text of any secrets in the values from reaching observers from now on
+    pub fn add_secrets(&self, values: &ValueSet) {
+        for (_, secret) in values.secrets() {
+            self.redactor.add(secret);
+        }
+    }
+
     pub fn get_op_handler(&self, unit: UnitArc, op: Operation) -> OpEventHandler {
         OpEventHandler::new(self.clone(), unit, op)
     }
@@ -65,14 +97,165 @@ pub struct OpEventHandler {
     ev_handler: EventHandler,
     unit: UnitArc,
     op: Operation,
+    /// Values the unit emits which are declared secret
+    secret_emits: Vec<String>,
 }
 
 impl OpEventHandler {
     pub fn new(ev_handler: EventHandler, unit: UnitArc, op: Operation) -> Self {
-        Self { unit, ev_handler, op }
+        Self { unit, ev_handler, op, secret_emits: Vec::new() }
+    }
+
+    /// Treats the text of the named emitted values as secrets, as soon as they're emitted
+    pub fn with_secret_emits(self, secret_emits: Vec<String>) -> Self {
+        Self { secret_emits, ..self }
     }
 
     pub fn handle(&self, event: OpEvent) -> Result<()> {
+        if let OpEvent::Output(StdoutData::Message(ref message)) = event {
+            if message.header.name == "value" && message.header.field.as_ref().is_some_and(|f| self.secret_emits.contains(f)) {
+                // Both the emitted literal and the text it stands for are scrubbed
+                if let Ok(Value::String(text)) = parse_value(&message.text) {
+                    self.ev_handler.redactor.add(&text);
+                }
+                self.ev_handler.redactor.add(message.text.trim());
+            }
+        }
         self.ev_handler.handle(Event::Op(self.unit.clone(), self.op, event))
     }
 }
+
+/// Scrubs the text of secret values from events, so they aren't shown in output, even
+/// if a hook echoes them or they're traced by `set -x`
+#[derive(Clone, Default)]
+struct Redactor {
+    secrets: Arc<Mutex<Vec<String>>>,
+}
+
+impl Redactor {
+    fn add(&self, secret: &str) {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secret.is_empty() {
+            return;
+        }
+
+        // A secret with a single quote is split up wherever the shell quotes it, as in the
+        // assignments given to hooks or a `set -x` trace, so those forms are scrubbed too
+        let mut forms = vec![secret.to_string()];
+        if secret.contains('\'') {
+            forms.push(format!("'{}'", secret.replace('\'', "'\\''")));
+            forms.push(format!("'{}'", secret.replace('\'', "'\"'\"'")));
+        }
+        for form in forms {
+            if !secrets.contains(&form) {
+                secrets.push(form);
+            }
+        }
+        // Longer secrets go first, so one containing another is scrubbed whole
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    }
+
+    fn redact(&self, event: Event) -> Event {
+        let secrets = self.secrets.lock().unwrap();
+        if secrets.is_empty() {
+            return event;
+        }
+
+        let scrub = |text: &str| secrets.iter().fold(text.to_string(), |text, secret| text.replace(secret, REDACTED));
+        let scrub_unit = |unit: UnitArc| -> UnitArc {
+            if !unit.args.values.values().any(|v| secrets.iter().any(|s| v.to_string().contains(s))) {
+                return unit;
+            }
+            let mut args = ValueSet::new();
+            for (k, v) in unit.args.values.iter() {
+                args.add_value(k, scrub_value(v, &scrub));
+            }
+            Arc::new(Unit {
+                name: unit.name.clone(),
+                args,
+                raw_args: unit.raw_args.clone(),
+                target: unit.target.clone(),
+                version: unit.version.clone(),
+                alias: unit.alias.clone(),
+            })
+        };
+
+        match event {
+            Event::Op(unit, op, op_e) => {
+                let op_e = match op_e {
+                    OpEvent::Output(StdoutData::TextLine(text)) => OpEvent::Output(StdoutData::TextLine(scrub(&text))),
+                    OpEvent::Output(StdoutData::Stderr(text)) => OpEvent::Output(StdoutData::Stderr(scrub(&text))),
+                    OpEvent::Output(StdoutData::Binary(bytes)) => {
+                        OpEvent::Output(StdoutData::Binary(scrub(&String::from_utf8_lossy(&bytes)).into_bytes()))
+                    },
+                    OpEvent::Output(StdoutData::Message(mut message)) => {
+                        message.text = scrub(&message.text);
+                        OpEvent::Output(StdoutData::Message(message))
+                    },
+                    OpEvent::AdapterStderr(text) => OpEvent::AdapterStderr(scrub(&text)),
+                    OpEvent::Error(text) => OpEvent::Error(scrub(&text)),
+                    op_e => op_e,
+                };
+                Event::Op(scrub_unit(unit), op, op_e)
+            },
+            Event::Resolved(units) => Event::Resolved(
+                units.into_iter().map(|(unit, version)| (scrub_unit(unit), version)).collect()
+            ),
+            Event::Described(unit, meta) => Event::Described(scrub_unit(unit), meta),
+            Event::Pruning(units) => Event::Pruning(units.into_iter().map(scrub_unit).collect()),
+            Event::Resuming(run_id, units) => Event::Resuming(run_id, units.into_iter().map(scrub_unit).collect()),
+            Event::Finished(unit) => Event::Finished(scrub_unit(unit)),
+            Event::Debug(text) => Event::Debug(scrub(&text)),
+            Event::Warning(text) => Event::Warning(scrub(&text)),
+            Event::Error(text) => Event::Error(scrub(&text)),
+            event => event,
+        }
+    }
+}
+
+fn scrub_value(value: &Value, scrub: &impl Fn(&str) -> String) -> Value {
+    match value {
+        Value::String(s) => Value::String(scrub(s)),
+        Value::List(items) => Value::List(items.iter().map(|item| scrub_value(item, scrub)).collect()),
+        Value::Map(entries) => Value::Map(entries.iter().map(|(k, v)| (k.clone(), scrub_value(v, scrub))).collect()),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Target;
+
+    #[test]
+    fn test_redact() {
+        let redactor = Redactor::default();
+        redactor.add("hunter2");
+
+        let mut args = ValueSet::new();
+        args.add_value("password", Value::String("hunter2".to_string()));
+        let unit = Arc::new(Unit::new("db.sh".to_string(), args, Target::default()));
+        let line = StdoutData::TextLine("+ password=hunter2".to_string());
+
+        match redactor.redact(Event::Op(unit.clone(), Operation::Apply, OpEvent::Output(line))) {
+            Event::Op(unit, _, OpEvent::Output(StdoutData::TextLine(text))) => {
+                assert_eq!(text, "+ password=****");
+                assert_eq!(unit.tag(), "db.sh(password=****)");
+            },
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        match redactor.redact(Event::Error("bad password hunter2".to_string())) {
+            Event::Error(text) => assert_eq!(text, "bad password ****"),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        // Secrets with quotes are scrubbed as the shell quotes them when tracing
+        redactor.add("it's");
+        let traced = "+ password='it'\\''s' token='it'\"'\"'s'";
+        match redactor.redact(Event::Debug(traced.to_string())) {
+            Event::Debug(text) => assert_eq!(text, "+ password=**** token=****"),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
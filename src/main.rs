@@ -3,6 +3,8 @@ mod engine;
 mod models;
 mod parser;
 mod events;
+#[cfg(test)]
+mod testing;
 
 use async_std::task;
 use anyhow::Result;
@@ -11,7 +13,8 @@ use tracing_tree::HierarchicalLayer;
 use std::sync::{Arc, Mutex};
 
 use crate::engine::Engine;
-use crate::cli::{CLI, EngineLogger};
+use crate::cli::{Cli, EngineLogger};
+use crate::events::ObserverArc;
 
 fn main() -> Result<()> {
     task::block_on(run())
@@ -23,9 +26,17 @@ async fn run() -> Result<()> {
         .with(HierarchicalLayer::new(2));
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let cli = CLI::init()?;
+    let cli = Cli::init()?;
+    if cli.is_history() {
+        return cli.show_history();
+    }
+
     let engine_observer: Arc<Mutex<EngineLogger>> = Arc::new(Mutex::new(cli.get_engine_observer()?));
-    let mut engine = Engine::new(cli.get_engine_options()?, vec!(engine_observer));
+    let mut observers: Vec<ObserverArc> = vec!(engine_observer);
+    if let Some(journal) = cli.get_journal()? {
+        observers.push(Arc::new(Mutex::new(journal)));
+    }
+    let mut engine = Engine::new(cli.get_engine_options()?, observers)?;
 
     engine.run().await
 }
@@ -12,7 +12,7 @@ pub mod target;
 pub mod stdout_data;
 
 pub use unit::{Unit, UnitArc};
-pub use params::Param;
+pub use params::{Param, Constraint};
 pub use target::Target;
 pub use dep::{Dependencies, FileDependency, Dependency, CaptureDefinition};
 pub use val::{Value, ValueSet, ValueType};
@@ -1,6 +1,7 @@
 //! Representation of a dependency for a unit
 use anyhow::{Result, anyhow};
-use super::{ValueType, ValueSet, Target};
+use super::{Value, ValueType, ValueSet, Target};
+use super::version::VersionSpecification;
 
 #[derive(Debug, Clone)]
 pub struct Dependencies {
@@ -21,19 +22,44 @@ impl Dependencies {
 #[derive(Debug, Clone)]
 pub struct Dependency {
     pub name: String,
+    /// Version the dependency's unit must satisfy, if any
+    pub version: Option<VersionSpecification>,
     pub args: ValueSet,
+    /// Name of a list arg to fan out over, making a unit for each of its items
+    pub each: Option<String>,
+    /// Label to render the unit with, in place of its name and arguments
+    pub alias: Option<String>,
     pub captures: Vec<CaptureDefinition>,
     pub target: Option<Target>,
 }
 
+impl Dependency {
+    /// Provides the args for each unit the dependency stands for, which is one per item
+    /// of the list it fans out over, or just its args if it doesn't
+    pub fn instances(&self) -> Vec<ValueSet> {
+        match self.each.as_ref().map(|name| (name, self.args.get(name))) {
+            Some((name, Some(Value::List(items)))) => items.iter()
+                .map(|item| {
+                    let mut args = self.args.clone();
+                    args.add_value(name, item.clone());
+                    args
+                })
+                .collect(),
+            _ => vec![self.args.clone()],
+        }
+    }
+}
+
 /// Emitted values which should be captured from a dependency by the
 /// dependent unit
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct CaptureDefinition {
     pub name: String,
     pub value_type: ValueType,
     pub required: bool,
     pub alias: Option<String>,
+    /// Value used when an optional capture isn't emitted by the dependency
+    pub default: Option<Value>,
 }
 
 /// File that a unit depends on
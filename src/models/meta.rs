@@ -1,12 +1,18 @@
 //! Contains meta emitted from a unit
-use super::Param;
+use anyhow::{anyhow, Result};
 
-#[derive(Debug)]
+use super::{Param, ValueSet};
+use super::version::Version;
+
+#[derive(Debug, Clone)]
 pub struct Meta {
     pub author: Option<String>,
     pub desc: Option<String>,
-    pub version: Option<String>,
+    pub version: Option<Version>,
     pub params: Vec<Param>,
+    /// Values the unit emits, in the same form as params.  Units which don't declare
+    /// any can emit whatever they like.
+    pub emits: Vec<Param>,
 }
 
 impl Meta {
@@ -16,6 +22,74 @@ impl Meta {
             desc: None,
             version: None,
             params: Vec::new(),
+            emits: Vec::new(),
+        }
+    }
+
+    /// Finds the declaration for an emitted value, if the unit declares its emits
+    pub fn declared_emit(&self, name: &str) -> Option<&Param> {
+        self.emits.iter().find(|emit| emit.name == name)
+    }
+
+    /// Ensures emitted values are declared, and of the declared type
+    pub fn check_emitted(&self, values: &ValueSet) -> Result<()> {
+        if self.emits.is_empty() {
+            return Ok(());
+        }
+
+        for (name, value) in values.values.iter() {
+            match self.declared_emit(name) {
+                None => return Err(anyhow!("Value {} was emitted, but is not declared in emits", name)),
+                Some(emit) if emit.value_type != value.get_type() => {
+                    return Err(anyhow!("Emitted value {} is of type {}, not {} as declared", name, value.get_type(), emit.value_type));
+                },
+                Some(emit) => emit.check(value).map_err(|e| anyhow!("Emitted value {}", e))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensures every required emit has been emitted, once the unit has been applied
+    pub fn check_required_emits(&self, values: &ValueSet) -> Result<()> {
+        match self.emits.iter().find(|emit| emit.required && values.get(&emit.name).is_none()) {
+            Some(emit) => Err(anyhow!("Required value {} was not emitted", emit.name)),
+            None => Ok(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Value, ValueType};
+
+    fn meta() -> Meta {
+        Meta {
+            emits: vec![
+                Param { name: "id".to_string(), value_type: ValueType::String, required: true, default: None, constraint: None, desc: None },
+                Param { name: "count".to_string(), value_type: ValueType::Int, required: false, default: None, constraint: None, desc: None },
+            ],
+            ..Meta::empty()
+        }
+    }
+
+    #[test]
+    fn test_check_emitted() {
+        let meta = meta();
+        let mut values = ValueSet::new();
+        values.add_value("id", Value::String("debian".to_string()));
+        assert!(meta.check_emitted(&values).is_ok());
+        assert!(meta.check_required_emits(&values).is_ok());
+
+        values.add_value("count", Value::String("three".to_string()));
+        assert!(meta.check_emitted(&values).is_err());
+
+        let mut values = ValueSet::new();
+        values.add_value("other", Value::Int(1));
+        assert!(meta.check_emitted(&values).is_err());
+        assert!(meta.check_required_emits(&values).is_err());
+
+        // Without declared emits, anything goes
+        assert!(Meta::empty().check_emitted(&values).is_ok());
+    }
+}
@@ -1,10 +1,256 @@
 //! Parameters the specifications for arguments that can be received by units
 
-use super::val::ValueType;
+use std::fmt;
 
-#[derive(Debug, PartialEq, Eq)]
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use super::val::{Value, ValueType};
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Param {
     pub name: String,
     pub value_type: ValueType,
     pub required: bool,
+    /// Value given to the param when no argument is provided for it
+    pub default: Option<Value>,
+    /// Restricts the values the param accepts, beyond its type
+    pub constraint: Option<Constraint>,
+    /// Explains what the param is for, shown in unit help
+    pub desc: Option<String>,
+}
+
+impl Param {
+    /// Ensures the constraint makes sense for the param's type, and that its default meets it.
+    /// Patterns are compiled here, so they needn't be for every value checked.
+    pub fn validate(&mut self) -> Result<()> {
+        if let Some(ref mut constraint) = self.constraint {
+            constraint.validate_for(&self.value_type)
+                .map_err(|e| anyhow!("Invalid constraint for {}: {}", self.name, e))?;
+        }
+        if let Some(ref default) = self.default {
+            self.check(default)
+                .map_err(|e| anyhow!("Invalid default for {}: {}", self.name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Ensures a value meets the param's constraint.  Items of lists and values of maps
+    /// must each meet it.
+    pub fn check(&self, value: &Value) -> Result<()> {
+        let constraint = match self.constraint {
+            Some(ref constraint) => constraint,
+            None => return Ok(()),
+        };
+
+        let items: Vec<&Value> = match value {
+            Value::List(items) => items.iter().collect(),
+            Value::Map(entries) => entries.values().collect(),
+            value => vec![value],
+        };
+
+        match items.into_iter().find(|item| !constraint.matches(item)) {
+            Some(item) => Err(anyhow!("{} must be {}, got {}", self.name, constraint.describe(), item)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.required {
+            write!(f, "!")?;
+        }
+        write!(f, "{}:{}", self.name, self.value_type)?;
+        // Spaces keep a default from reading as part of a constraint like a pattern
+        match (&self.constraint, &self.default) {
+            (Some(constraint), Some(default)) => write!(f, " {} = {}", constraint, default),
+            (Some(constraint), None) => write!(f, " {}", constraint),
+            (None, Some(default)) => write!(f, "={}", default),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Restrictions on the values a param accepts
+#[derive(Debug, PartialEq, Clone)]
+pub enum Constraint {
+    /// The value must be one of those listed, like `in(read, write)`
+    OneOf(Vec<Value>),
+    /// A number within an inclusive range, like `1..65535`, either end may be left open
+    Range(Option<f64>, Option<f64>),
+    /// A string matching a regular expression, like `~ ^[a-z]+$`
+    Pattern(Pattern),
+}
+
+/// A regular expression, compiled once its param is validated
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub source: String,
+    regex: Option<Regex>,
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Self {
+        Pattern { source: source.to_string(), regex: None }
+    }
+
+    fn compile(&mut self) -> Result<()> {
+        self.regex = Some(Regex::new(&self.source)?);
+        Ok(())
+    }
+
+    fn is_match(&self, s: &str) -> bool {
+        match self.regex {
+            Some(ref regex) => regex.is_match(s),
+            // Only params which skipped validation get here
+            None => Regex::new(&self.source).map(|re| re.is_match(s)).unwrap_or(false),
+        }
+    }
+}
+
+// Patterns are equal when written the same, Regex has no equality of its own
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Constraint {
+    fn validate_for(&mut self, value_type: &ValueType) -> Result<()> {
+        // Lists and maps hold values of any type, so only scalars can be checked up front
+        let scalar = !matches!(value_type, ValueType::List | ValueType::Map);
+        match self {
+            Constraint::OneOf(values) => match values.iter().find(|v| scalar && v.get_type() != *value_type) {
+                Some(value) => Err(anyhow!("{} is not a {}", value, value_type)),
+                None => Ok(()),
+            },
+            Constraint::Range(..) if scalar && !matches!(value_type, ValueType::Int | ValueType::Float) => {
+                Err(anyhow!("ranges only apply to numbers, not {}", value_type))
+            },
+            Constraint::Pattern(_) if scalar && !matches!(value_type, ValueType::String | ValueType::Secret) => {
+                Err(anyhow!("patterns only apply to strings, not {}", value_type))
+            },
+            Constraint::Pattern(pattern) => pattern.compile(),
+            Constraint::Range(..) => Ok(()),
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Constraint::OneOf(values), value) => values.contains(value),
+            (Constraint::Range(min, max), Value::Int(i)) => in_range(*i as f64, min, max),
+            (Constraint::Range(min, max), Value::Float(f)) => in_range(*f as f64, min, max),
+            (Constraint::Pattern(pattern), Value::String(s) | Value::Secret(s)) => pattern.is_match(s),
+            _ => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Constraint::OneOf(values) => {
+                format!("one of {}", values.iter().map(Value::to_string).collect::<Vec<_>>().join(", "))
+            },
+            Constraint::Range(..) => format!("in the range {}", self),
+            Constraint::Pattern(pattern) => format!("a string matching {}", pattern),
+        }
+    }
+}
+
+fn in_range(n: f64, min: &Option<f64>, max: &Option<f64>) -> bool {
+    min.is_none_or(|min| n >= min) && max.is_none_or(|max| n <= max)
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constraint::OneOf(values) => {
+                let values = values.iter().map(Value::to_string).collect::<Vec<_>>();
+                write!(f, "in({})", values.join(", "))
+            },
+            Constraint::Range(min, max) => {
+                if let Some(min) = min {
+                    write!(f, "{}", min)?;
+                }
+                write!(f, "..")?;
+                if let Some(max) = max {
+                    write!(f, "{}", max)?;
+                }
+                Ok(())
+            },
+            Constraint::Pattern(pattern) => write!(f, "~ {}", pattern),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(value_type: ValueType, constraint: Constraint) -> Param {
+        Param { name: "p".to_string(), value_type, required: false, default: None, constraint: Some(constraint), desc: None }
+    }
+
+    #[test]
+    fn test_check() {
+        let mode = param(ValueType::String, Constraint::OneOf(vec![
+            Value::String("read".to_string()),
+            Value::String("write".to_string()),
+        ]));
+        assert!(mode.check(&Value::String("read".to_string())).is_ok());
+        assert!(mode.check(&Value::String("exec".to_string())).is_err());
+
+        let port = param(ValueType::Int, Constraint::Range(Some(1.0), Some(65535.0)));
+        assert!(port.check(&Value::Int(8080)).is_ok());
+        assert!(port.check(&Value::Int(0)).is_err());
+        assert!(port.check(&Value::Int(65536)).is_err());
+
+        let name = param(ValueType::String, Constraint::Pattern(Pattern::new("^[a-z0-9-]+$")));
+        assert!(name.check(&Value::String("web-1".to_string())).is_ok());
+        assert!(name.check(&Value::String("Web 1".to_string())).is_err());
+
+        // Each item in a list must meet the constraint
+        let names = param(ValueType::List, Constraint::Pattern(Pattern::new("^[a-z]+$")));
+        assert!(names.check(&Value::List(vec![Value::String("git".to_string())])).is_ok());
+        assert!(names.check(&Value::List(vec![Value::String("git".to_string()), Value::Int(1)])).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(param(ValueType::Bool, Constraint::Range(None, Some(1.0))).validate().is_err());
+        assert!(param(ValueType::Int, Constraint::Pattern(Pattern::new("^1"))).validate().is_err());
+        assert!(param(ValueType::String, Constraint::Pattern(Pattern::new("("))).validate().is_err());
+        assert!(param(ValueType::Int, Constraint::OneOf(vec![Value::String("a".to_string())])).validate().is_err());
+
+        let mut with_default = Param {
+            default: Some(Value::Int(0)),
+            ..param(ValueType::Int, Constraint::Range(Some(1.0), None))
+        };
+        assert!(with_default.validate().is_err());
+
+        // Validating compiles the pattern, which is then used for every check
+        let mut name = param(ValueType::String, Constraint::Pattern(Pattern::new("^[a-z]+$")));
+        name.validate().unwrap();
+        assert!(matches!(name.constraint, Some(Constraint::Pattern(Pattern { regex: Some(_), .. }))));
+        assert!(name.check(&Value::String("git".to_string())).is_ok());
+        assert_eq!(name.constraint, Some(Constraint::Pattern(Pattern::new("^[a-z]+$"))));
+    }
+
+    #[test]
+    fn test_display() {
+        let port = Param {
+            required: true,
+            default: Some(Value::Int(8080)),
+            ..param(ValueType::Int, Constraint::Range(Some(1.0), Some(65535.0)))
+        };
+        assert_eq!(port.to_string(), "!p:int 1..65535 = 8080");
+        let mode = param(ValueType::String, Constraint::OneOf(vec![Value::String("a b".to_string()), Value::Int(1)]));
+        assert_eq!(mode.to_string(), "p:string in(a b, 1)");
+    }
 }
@@ -1,9 +1,12 @@
 use super::emit::Message;
 
 /// Denotes data read from Stdout of a unit.  Can be either a text line, or an emit message
-/// which Sysunit must handle
+/// which Sysunit must handle.  Lines which aren't valid UTF-8 are kept as binary, and lines
+/// a hook wrote to stderr are framed by the shell slug so they can be kept apart.
 #[derive(Debug, PartialEq, Clone)]
 pub enum StdoutData {
     TextLine(String),
+    Binary(Vec<u8>),
+    Stderr(String),
     Message(Message),
 }
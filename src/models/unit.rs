@@ -4,6 +4,8 @@
 
 use super::val::ValueSet;
 use super::target::Target;
+use super::version::VersionSpecification;
+use sha1::{Sha1, Digest};
 use std::hash::{Hash, Hasher};
 use std::fmt;
 
@@ -20,22 +22,45 @@ pub struct Unit {
     pub name: String,
     /// The arguments provided for the unit's invocation
     pub args: ValueSet,
+    /// Names of args given as raw text, such as on the CLI, which are held as strings
+    /// until the unit's params are known and they can be read as the declared type
+    pub raw_args: Vec<String>,
     pub target: Target,
+    /// Version the unit was required to satisfy by its dependent.  This only guides which
+    /// script is loaded, and isn't part of the unit's identity.
+    pub version: Option<VersionSpecification>,
+    /// Label given to the unit by its dependent for rendering, also not part of its identity
+    pub alias: Option<String>,
 }
 
 impl Unit {
     pub fn new(name: String, args: ValueSet, target: Target) -> Unit {
-        Unit { name, args, target }
+        Unit { name, args, target, raw_args: Vec::new(), version: None, alias: None }
+    }
+
+    /// Canonical fingerprint of the unit's name, target and arguments.  Units with equal
+    /// fingerprints are the same unit, and share one execution.
+    pub fn fingerprint(&self) -> String {
+        let canonical = format!("{}\0{}\0{}", self.name, self.target, self.args.fingerprint());
+        format!("{:x}", Sha1::digest(canonical.as_bytes()))
     }
 
     pub fn get_id(&self) -> String {
-        format!("{}-{}", self.name, self.args.get_sig())
+        format!("{}@{}-{}", self.name, self.target.user_host_string(), self.fingerprint())
     }
 
     pub fn tag(&self) -> String {
         // render in the form of unit-name(arg1=val1, arg2=val2), limit content in (...) to 40
-        // chars by truncating the values
-        format!("{}({})", self.name, self.args.tag())
+        // chars by truncating the values.  Aliased units are rendered as alias (unit-name)
+        match self.alias {
+            Some(ref alias) => format!("{} ({})", alias, self.name),
+            None => format!("{}({})", self.name, self.args.tag()),
+        }
+    }
+
+    /// Short name for the unit in messages, its alias if it has one
+    pub fn label(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
     }
 }
 
@@ -66,3 +91,34 @@ impl ResolvableNode for UnitArc {
 }
 
 use crate::engine::ResolvableNode;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Value;
+
+    fn pkg(name: &str, host: &str) -> UnitArc {
+        let mut args = ValueSet::new();
+        args.add_value("name", Value::String(name.to_string()));
+        Arc::new(Unit::new("pkg.sh".to_string(), args, Target::new("ssh", None, host)))
+    }
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(pkg("git", "web1"), pkg("git", "web1"));
+        assert_eq!(pkg("git", "web1").get_id(), pkg("git", "web1").get_id());
+        assert_ne!(pkg("git", "web1"), pkg("git", "web2"));
+        assert_ne!(pkg("git", "web1"), pkg("vim", "web1"));
+
+        let mut local_user = Target::default();
+        local_user.user = Some("deploy".to_string());
+        let local = Unit::new("pkg.sh".to_string(), ValueSet::new(), Target::default());
+        let as_user = Unit::new("pkg.sh".to_string(), ValueSet::new(), local_user);
+        assert_ne!(local, as_user);
+
+        // Neither version nor alias are part of the identity
+        let mut aliased = Unit::new("pkg.sh".to_string(), ValueSet::new(), Target::default());
+        aliased.alias = Some("tools".to_string());
+        assert_eq!(local, aliased);
+    }
+}
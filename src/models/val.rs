@@ -6,7 +6,11 @@ use sha1::{Sha1, Digest};
 use anyhow::Result;
 
 use std::fmt;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+/// Shown in place of secret values
+pub const REDACTED: &str = "****";
 
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Clone)]
@@ -14,7 +18,10 @@ pub enum ValueType {
     String,
     Int,
     Bool,
-    Float
+    Float,
+    List,
+    Map,
+    Secret,
 }
 
 /// Denotes the type of a value
@@ -25,6 +32,9 @@ impl ValueType {
             "int" => Ok(ValueType::Int),
             "bool" => Ok(ValueType::Bool),
             "float" => Ok(ValueType::Float),
+            "list" => Ok(ValueType::List),
+            "map" => Ok(ValueType::Map),
+            "secret" => Ok(ValueType::Secret),
             _ => Err(anyhow::anyhow!("Invalid type: {}", s))
         }
     }
@@ -37,36 +47,28 @@ impl fmt::Display for ValueType {
             ValueType::Int => write!(f, "int"),
             ValueType::Bool => write!(f, "bool"),
             ValueType::Float => write!(f, "float"),
+            ValueType::List => write!(f, "list"),
+            ValueType::Map => write!(f, "map"),
+            ValueType::Secret => write!(f, "secret"),
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(untagged)]
 pub enum Value {
     String(String),
     Int(i32),
     Float(f32),
     Bool(bool),
+    /// Lists and maps hold scalar values, they can't be nested
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    /// A string which is passed to hooks as it is, but redacted wherever it's displayed
+    Secret(String),
 }
 
 impl Value {
-    pub fn from_string(s: &str) -> Self {
-        if s == "true" || s == "false" {
-            return Value::Bool(true);
-        }
-
-        if let Ok(i) = s.parse::<i32>() {
-            return Value::Int(i);
-        }
-
-        if let Ok(f) = s.parse::<f32>() {
-            return Value::Float(f);
-        }
-
-        Value::String(s.to_string())
-    }
-
     // Provides a display-friendly representation of the value,
     // truncated if necessary.
     pub fn tag(&self, max_len: usize) -> String {
@@ -81,6 +83,50 @@ impl Value {
             Value::Int(i) => format!("{}", i),
             Value::Float(f) => format!("{}", f),
             Value::Bool(b) => format!("{}", b),
+            Value::Secret(_) => REDACTED.to_string(),
+            Value::List(_) | Value::Map(_) => {
+                let s = self.to_string();
+                if s.chars().count() > max_len {
+                    format!("{}...", s.chars().take(max_len).collect::<String>())
+                } else {
+                    s
+                }
+            },
+        }
+    }
+
+    /// Writes the value tagged with its type, with strings prefixed by their length so
+    /// no two values share an encoding
+    fn write_canonical(&self, out: &mut String) {
+        match self {
+            Value::String(s) => out.push_str(&format!("s{}:{}", s.len(), s)),
+            Value::Int(i) => out.push_str(&format!("i:{}", i)),
+            Value::Float(f) => out.push_str(&format!("f:{:x}", f.to_bits())),
+            Value::Bool(b) => out.push_str(&format!("b:{}", b)),
+            Value::Secret(secret) => {
+                let salt = SECRET_SALT.get_or_init(rand::random::<u64>);
+                let mut hasher = Sha1::new();
+                hasher.update(salt.to_le_bytes());
+                hasher.update(secret.as_bytes());
+                out.push_str(&format!("x:{:x}", hasher.finalize()));
+            },
+            Value::List(items) => {
+                out.push_str(&format!("l{}[", items.len()));
+                for item in items {
+                    item.write_canonical(out);
+                    out.push(',');
+                }
+                out.push(']');
+            },
+            Value::Map(entries) => {
+                out.push_str(&format!("m{}{{", entries.len()));
+                for (k, v) in entries {
+                    out.push_str(&format!("{}:{}=", k.len(), k));
+                    v.write_canonical(out);
+                    out.push(',');
+                }
+                out.push('}');
+            },
         }
     }
 
@@ -122,6 +168,44 @@ impl Value {
             Value::Int(_) => ValueType::Int,
             Value::Float(_) => ValueType::Float,
             Value::Bool(_) => ValueType::Bool,
+            Value::List(_) => ValueType::List,
+            Value::Map(_) => ValueType::Map,
+            Value::Secret(_) => ValueType::Secret,
+        }
+    }
+
+    /// Strings parsed from units can't be secret until they're matched with a param,
+    /// capture or emit declared as one
+    pub fn into_type(self, value_type: &ValueType) -> Value {
+        match (self, value_type) {
+            (Value::String(s), ValueType::Secret) => Value::Secret(s),
+            (value, _) => value,
+        }
+    }
+
+    /// Formats a value within a list or map, quoting strings which wouldn't parse back
+    /// to the same value on their own
+    fn fmt_element(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) if s.is_empty() || s.contains(|c: char| " \t\n,=[]{}'\"\\".contains(c)) => {
+                write!(f, "{}", quote(s))
+            },
+            value => write!(f, "{}", value),
+        }
+    }
+
+    /// Provides a literal which parses back to the same value and type, with strings
+    /// always quoted and floats always given a decimal point.  Secrets are redacted.
+    pub fn literal(&self) -> String {
+        match self {
+            Value::String(s) => quote(s),
+            Value::Float(f) => format!("{:?}", f),
+            Value::List(items) => format!("[{}]", items.iter().map(Value::literal).collect::<Vec<_>>().join(", ")),
+            Value::Map(entries) => format!(
+                "{{{}}}",
+                entries.iter().map(|(k, v)| format!("{}={}", k, v.literal())).collect::<Vec<_>>().join(", "),
+            ),
+            value => value.to_string(),
         }
     }
 }
@@ -133,11 +217,60 @@ impl fmt::Display for Value {
             Value::Int(i) => write!(f, "{}", i),
             Value::Float(fl) => write!(f, "{}", fl),
             Value::Bool(b) => write!(f, "{}", b),
+            Value::Secret(_) => write!(f, "{}", REDACTED),
+            Value::List(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    value.fmt_element(f)?;
+                }
+                write!(f, "]")
+            },
+            Value::Map(values) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}=", key)?;
+                    value.fmt_element(f)?;
+                }
+                write!(f, "}}")
+            },
         }
     }
 }
 
 
+/// Quotes a string so it parses back as it is.  Single quotes take everything but
+/// themselves as it is, otherwise it's double quoted with escapes.
+fn quote(s: &str) -> String {
+    match s.contains('"') && !s.contains(['\'', '\n']) {
+        true => format!("'{}'", s),
+        false => format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")),
+    }
+}
+
+/// Salt for hashing secrets into fingerprints, random for each run unless one is kept
+static SECRET_SALT: OnceLock<u64> = OnceLock::new();
+
+/// Sets the salt secrets are hashed with, so fingerprints of units with secret args can
+/// be compared between runs.  Fails if a fingerprint has already been taken with another salt.
+pub fn set_secret_salt(salt: u64) -> Result<()> {
+    match *SECRET_SALT.get_or_init(|| salt) == salt {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!("Secret salt was set after it was used")),
+    }
+}
+
+/// Provides the salt secrets are hashed with, so tests can keep it in a state directory
+#[cfg(test)]
+pub fn secret_salt() -> u64 {
+    *SECRET_SALT.get_or_init(rand::random::<u64>)
+}
+
 /// A value set is a key-value collection of values.  It is used for
 /// primarily for emit captures and unit args.
 #[derive(Debug, Clone)]
@@ -172,15 +305,49 @@ impl ValueSet {
         }
     }
 
-    /* Provides SHA1sum of all values combined */
+    /* Provides SHA1sum of all values combined, with secrets redacted so it can be shown */
     pub fn get_sig(&self) -> String {
         let mut all = String::new();
-        for (k, v) in &self.values {
+        for (k, v) in self.values.iter().collect::<BTreeMap<_, _>>() {
             all.push_str(format!("{}={}", k, v).as_str());
         }
         format!("{:x}", Sha1::digest(all.as_bytes()))
     }
 
+    /// Provides a canonical fingerprint of the set for identifying units.  Entries are
+    /// taken in order of their names and each value is tagged with its type, so equal sets
+    /// always match, and `port=8080` doesn't match `port="8080"`.  Secrets are hashed
+    /// with a salt kept for the run or state directory, so the fingerprint says nothing about them if shown.
+    pub fn fingerprint(&self) -> String {
+        let mut canonical = String::new();
+        for (k, v) in self.values.iter().collect::<BTreeMap<_, _>>() {
+            canonical.push_str(&format!("{}:{}=", k.len(), k));
+            v.write_canonical(&mut canonical);
+            canonical.push(';');
+        }
+        format!("{:x}", Sha1::digest(canonical.as_bytes()))
+    }
+
+    /// Provides the names and text of secret values in order of their names, including
+    /// those held in lists and maps
+    pub fn secrets(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values
+            .iter()
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .flat_map(|(k, v)| {
+                let items: Vec<&Value> = match v {
+                    Value::List(items) => items.iter().collect(),
+                    Value::Map(entries) => entries.values().collect(),
+                    value => vec![value],
+                };
+                items.into_iter().filter_map(move |item| match item {
+                    Value::Secret(secret) => Some((k.as_str(), secret.as_str())),
+                    _ => None,
+                })
+            })
+    }
+
     pub fn tag(&self) -> String {
         let mut tag = String::new();
         for (k, v) in &self.values {
@@ -208,4 +375,56 @@ mod tests {
         assert!(value_set.get("blarp").unwrap().float_approx_equals(432.34));
         assert!(value_set.get("blip").unwrap().bool_equals(true));
     }
+
+    #[test]
+    fn test_secret() {
+        let mut first = ValueSet::new();
+        first.add_value("user", Value::String("admin".to_string()));
+        first.add_value("password", Value::Secret("hunter2".to_string()));
+
+        let mut second = first.clone();
+        second.add_value("password", Value::Secret("swordfish".to_string()));
+
+        assert_eq!(first.get("password").unwrap().to_string(), REDACTED);
+        assert!(!first.tag().contains("hunter2"));
+
+        // Secrets don't change the signature which is shown, only the fingerprint
+        assert_eq!(first.get_sig(), second.get_sig());
+        assert_ne!(first.fingerprint(), second.fingerprint());
+        assert_eq!(first.fingerprint(), first.clone().fingerprint());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut first = ValueSet::new();
+        let mut second = ValueSet::new();
+        for (k, v) in [("a", Value::Int(1)), ("b", Value::Bool(true)), ("c", Value::String("x".to_string()))] {
+            first.add_value(k, v.clone());
+        }
+        for (k, v) in [("c", Value::String("x".to_string())), ("a", Value::Int(1)), ("b", Value::Bool(true))] {
+            second.add_value(k, v);
+        }
+        assert_eq!(first.fingerprint(), second.fingerprint());
+
+        // Values which display the same but differ in type
+        let fingerprint_of = |value: Value| {
+            let mut set = ValueSet::new();
+            set.add_value("port", value);
+            set.fingerprint()
+        };
+        assert_ne!(fingerprint_of(Value::Int(1)), fingerprint_of(Value::String("1".to_string())));
+        assert_ne!(fingerprint_of(Value::Bool(true)), fingerprint_of(Value::String("true".to_string())));
+        assert_ne!(
+            fingerprint_of(Value::List(vec![Value::String("a, b".to_string())])),
+            fingerprint_of(Value::List(vec![Value::String("a".to_string()), Value::String("b".to_string())])),
+        );
+
+        // Entries can't run into each other
+        let mut joined = ValueSet::new();
+        joined.add_value("a", Value::String("1;b=2".to_string()));
+        let mut split = ValueSet::new();
+        split.add_value("a", Value::String("1".to_string()));
+        split.add_value("b", Value::String("2".to_string()));
+        assert_ne!(joined.fingerprint(), split.fingerprint());
+    }
 }
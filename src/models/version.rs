@@ -1,3 +1,7 @@
+//! Versions emitted by units, and the specifications dependencies can place on them
+use std::cmp::Ordering;
+use std::fmt;
+
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum Comparator {
     GreaterThan,
@@ -14,8 +18,120 @@ pub struct Version {
     pub patch: Option<u32>,
 }
 
+impl Version {
+    /// Provides the version's components for ordering, with missing ones as zero
+    pub fn components(&self) -> [u32; 3] {
+        [self.major, self.minor.unwrap_or(0), self.patch.unwrap_or(0)]
+    }
+}
+
+/// Constrains the version of a dependency, for example `>=2.3`.  Only the components
+/// given in the specification are compared, so `2.3` matches any `2.3.x`, and `>2.3`
+/// requires at least `2.4`.  Without a comparator, the version must match exactly.
 #[derive(Debug, Clone)]
 pub struct VersionSpecification {
     pub comparator: Option<Comparator>,
     pub version: Version
 }
+
+impl VersionSpecification {
+    pub fn matches(&self, version: &Version) -> bool {
+        use Comparator::*;
+
+        let ordering = self.compare(version);
+        match self.comparator.as_ref().unwrap_or(&Equal) {
+            GreaterThan => ordering == Ordering::Greater,
+            GreaterThanOrEqual => ordering != Ordering::Less,
+            LessThan => ordering == Ordering::Less,
+            LessThanOrEqual => ordering != Ordering::Greater,
+            Equal => ordering == Ordering::Equal,
+        }
+    }
+
+    /// Orders the given version relative to this specification's version, comparing
+    /// only as many components as the specification provides
+    fn compare(&self, version: &Version) -> Ordering {
+        let precision = match (self.version.minor, self.version.patch) {
+            (None, _) => 1,
+            (Some(_), None) => 2,
+            (Some(_), Some(_)) => 3,
+        };
+
+        version.components()[..precision].cmp(&self.version.components()[..precision])
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Comparator::GreaterThan => ">",
+            Comparator::GreaterThanOrEqual => ">=",
+            Comparator::LessThan => "<",
+            Comparator::LessThanOrEqual => "<=",
+            Comparator::Equal => "=",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.major)?;
+        if let Some(minor) = self.minor {
+            write!(f, ".{}", minor)?;
+        }
+        if let Some(patch) = self.patch {
+            write!(f, ".{}", patch)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VersionSpecification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref comparator) = self.comparator {
+            write!(f, "{}", comparator)?;
+        }
+        write!(f, "{}", self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u32, minor: Option<u32>, patch: Option<u32>) -> Version {
+        Version { major, minor, patch }
+    }
+
+    fn spec(comparator: Option<Comparator>, version: Version) -> VersionSpecification {
+        VersionSpecification { comparator, version }
+    }
+
+    #[test]
+    fn test_matches() {
+        use Comparator::*;
+
+        let v2_3_1 = version(2, Some(3), Some(1));
+
+        assert!(spec(None, version(2, Some(3), None)).matches(&v2_3_1));
+        assert!(spec(Some(Equal), version(2, None, None)).matches(&v2_3_1));
+        assert!(!spec(None, version(2, Some(3), Some(0))).matches(&v2_3_1));
+
+        assert!(spec(Some(GreaterThan), version(2, Some(2), None)).matches(&v2_3_1));
+        assert!(!spec(Some(GreaterThan), version(2, Some(3), None)).matches(&v2_3_1));
+        assert!(spec(Some(GreaterThanOrEqual), version(2, Some(3), None)).matches(&v2_3_1));
+        assert!(spec(Some(LessThan), version(3, None, None)).matches(&v2_3_1));
+        assert!(!spec(Some(LessThan), version(2, Some(3), Some(1))).matches(&v2_3_1));
+        assert!(spec(Some(LessThanOrEqual), version(2, Some(3), None)).matches(&v2_3_1));
+
+        // Missing components on the unit's version are treated as zero
+        assert!(spec(Some(GreaterThanOrEqual), version(2, Some(0), Some(0))).matches(&version(2, None, None)));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(spec(Some(Comparator::GreaterThanOrEqual), version(1, Some(2), None)).to_string(), ">=1.2");
+        assert_eq!(version(1, Some(2), Some(3)).to_string(), "1.2.3");
+    }
+}
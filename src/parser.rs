@@ -25,9 +25,11 @@ use self::{
     deps::deps,
     value::value,
     target::target,
+    version::version,
 };
 
-use crate::models::{Param, Dependency, Value, ValueSet, Target, StdoutData};
+use crate::models::{Param, Dependency, Value, ValueSet, ValueType, Target, StdoutData};
+use crate::models::version::Version;
 
 use anyhow::{Result, anyhow};
 use common::ws;
@@ -36,17 +38,17 @@ use common::ws;
 /// needs to be sent.
 pub enum StreamingResult<'a, T> {
     Incomplete,
-    Complete(Result<(&'a str, T)>),
+    Complete(Result<(&'a [u8], T)>),
 }
 
-/// A streaming parser for version specications.  Informs its caller
-/// via StreamingResult if more data is needed.
-#[instrument]
-pub fn parse_stdout_data(input: &str) -> StreamingResult<StdoutData> {
+/// A streaming parser for output from units, which recognizes messages framed with
+/// the executor's nonce.  Informs its caller via StreamingResult if more data is needed.
+#[instrument(skip(input))]
+pub fn parse_stdout_data<'a>(input: &'a [u8], nonce: &str) -> StreamingResult<'a, StdoutData> {
     use StreamingResult::*;
 
-    event!(Level::DEBUG, "Parsing stdout data: '{}'", input);
-    match stdout_data::stdout_data(input) {
+    event!(Level::DEBUG, "Parsing stdout data: '{}'", String::from_utf8_lossy(input));
+    match stdout_data::stdout_data(input, nonce) {
         Ok((remaining, data)) => {
             event!(Level::DEBUG, "Parsed stdout data: {:?}", data);
             Complete(Ok((remaining, data)))
@@ -54,8 +56,10 @@ pub fn parse_stdout_data(input: &str) -> StreamingResult<StdoutData> {
         Err(e) => {
             match e {
                 nom::Err::Error(inner_e) | nom::Err::Failure(inner_e) => {
-                    let fancy_error = convert_error(input, inner_e);
-                    Complete(Err(anyhow!("Failed to parse emit message: {}", fancy_error.to_string())))
+                    let (at, kind) = inner_e.errors.first()
+                        .map(|(at, kind)| (String::from_utf8_lossy(at).to_string(), format!("{:?}", kind)))
+                        .unwrap_or_default();
+                    Complete(Err(anyhow!("Failed to parse emit message: {} at: {:?}", kind, at)))
                 },
                 nom::Err::Incomplete(_) => Incomplete,
             }
@@ -96,6 +100,22 @@ pub fn parse_value(input: &str) -> Result<Value> {
     parse_with_better_errors(input, value)
 }
 
+pub fn parse_typed_value(input: &str, value_type: &ValueType) -> Result<Value> {
+    value::typed_value(input, value_type)
+}
+
+pub fn parse_emitted_value(input: &str, value_type: &ValueType) -> Result<Value> {
+    value::emitted_value(input, value_type)
+}
+
+pub fn parse_undeclared_value(input: &str) -> Result<Value> {
+    parse_value(input).map(value::inferred_value)
+}
+
+pub fn parse_version(input: &str) -> Result<Version> {
+    parse_with_better_errors(input, version)
+}
+
 pub fn parse_deps(input: &str) -> Result<Vec<Dependency>> {
     parse_with_better_errors(input, deps)
 }
@@ -10,7 +10,7 @@ use nom::{
     multi::separated_list0,
 };
 
-pub fn args(input: &str) -> VResult<ValueSet> {
+pub fn args(input: &str) -> VResult<'_, ValueSet> {
     map(separated_list0(tag(","), named_value), |args| {
         let mut args_set = ValueSet::new();
 
@@ -2,6 +2,7 @@
 
 use crate::models::CaptureDefinition;
 use super::common::{label, value_type, ws, VResult};
+use super::value::value;
 
 use nom::{
     bytes::complete::tag,
@@ -9,18 +10,31 @@ use nom::{
     sequence::preceded,
     sequence::tuple,
     branch::alt,
-    combinator::map,
+    combinator::{map, opt},
 };
 
-pub fn captures(input: &str) -> VResult<Vec<CaptureDefinition>> {
+/* capture format: !name:alias:type=default
+ *                 ^-- required  ^-- default for optional captures
+ *
+ * the alias, required marker and default are all optional
+ * */
+
+pub fn captures(input: &str) -> VResult<'_, Vec<CaptureDefinition>> {
     preceded(tag("->"), separated_list1(tag(","), ws(capture)))(input)
 }
 
-fn capture(input: &str) -> VResult<CaptureDefinition> {
-    alt((aliased_capture, unaliased_capture))(input)
+fn capture(input: &str) -> VResult<'_, CaptureDefinition> {
+    let (rest, (bang, capture, default)) = tuple((
+        opt(ws(tag("!"))),
+        alt((aliased_capture, unaliased_capture)),
+        opt(preceded(ws(tag("=")), value)),
+    ))(input)?;
+
+    let default = default.map(|default| default.into_type(&capture.value_type));
+    Ok((rest, CaptureDefinition { required: bang.is_some(), default, ..capture }))
 }
 
-fn unaliased_capture(input: &str) -> VResult<CaptureDefinition> {
+fn unaliased_capture(input: &str) -> VResult<'_, CaptureDefinition> {
     let parser = tuple((
         label,
         preceded(tag(":"), value_type)
@@ -31,10 +45,11 @@ fn unaliased_capture(input: &str) -> VResult<CaptureDefinition> {
         value_type,
         required: false,
         alias: None,
+        default: None,
     })(input)
 }
 
-fn aliased_capture(input: &str) -> VResult<CaptureDefinition> {
+fn aliased_capture(input: &str) -> VResult<'_, CaptureDefinition> {
     let parser = tuple((
         label,
         preceded(tag(":"), label),
@@ -46,13 +61,14 @@ fn aliased_capture(input: &str) -> VResult<CaptureDefinition> {
         value_type,
         required: false,
         alias: Some(alias.to_string()),
+        default: None,
     })(input)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::val::ValueType;
+    use crate::models::val::{Value, ValueType};
 
     #[test]
     fn test_capture() {
@@ -64,6 +80,7 @@ mod tests {
             value_type: ValueType::String,
             required: false,
             alias: None,
+            default: None,
         });
 
         let input = "foo:bar:string";
@@ -74,6 +91,19 @@ mod tests {
             value_type: ValueType::String,
             required: false,
             alias: Some("bar".to_string()),
+            default: None,
         });
+
+        let input = "!foo:bar:string";
+        let (rest, result) = capture(input).unwrap();
+        assert_eq!(rest, "");
+        assert!(result.required);
+        assert_eq!(result.default, None);
+
+        let input = "port:int = 8080, ";
+        let (rest, result) = capture(input).unwrap();
+        assert_eq!(rest, ", ");
+        assert!(!result.required);
+        assert_eq!(result.default, Some(Value::Int(8080)));
     }
 }
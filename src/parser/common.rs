@@ -26,7 +26,7 @@ pub fn label(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
 }
 
 pub fn value_type(input: &str) -> IResult<&str, ValueType, VerboseError<&str>> {
-    map(ws(alt((tag("string"), tag("int"), tag("bool"), tag("float")))), |value| {
+    map(ws(alt((tag("string"), tag("int"), tag("bool"), tag("float"), tag("list"), tag("map"), tag("secret")))), |value| {
         ValueType::from_str(value).expect("Invalid parsed type!")
     })(input)
 }
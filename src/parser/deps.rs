@@ -1,62 +1,99 @@
 /*
  * Example Deps Representation:
- * pkg name=python, dir.sh path='./tmp', curl.sh:>2.3 -> binary_path:curl_binary_path:string;
- * ^-- name                ^-^-+--^---^  ^----+-----^  ^    ^             ^            ^
- *                           /  |  \          |        |  capture name  capture alias capture type
- *                          /  arg  \     tagged name arrow
+ * pkg name=python, dir.sh path='./tmp', curl.sh:>2.3 as curl -> binary_path:curl_binary_path:string;
+ * ^-- name                ^-^-+--^---^  ^----+-----^  ^--+--^ ^    ^             ^            ^
+ *                           /  |  \          |          |    |  capture name  capture alias capture type
+ *                          /  arg  \     tagged name  alias  arrow
  *                          |        \
  *                        arg_name arg_value
+ *
+ * An arg value of each[git, tmux] fans the dependency out into one unit per item
  */
 
 
-use crate::models::{Dependency, version::VersionSpecification, Target};
+use crate::models::{Dependency, ValueSet, version::VersionSpecification, Target};
 
 use nom::{
-    combinator::opt,
+    branch::alt,
+    combinator::{opt, map},
     bytes::complete::{take_while1, tag},
-    multi::separated_list1,
-    sequence::{tuple, preceded, terminated},
+    character::complete::multispace1,
+    error::{VerboseError, VerboseErrorKind},
+    multi::{separated_list0, separated_list1},
+    sequence::{tuple, preceded, terminated, separated_pair},
 };
 
 use super::{
-    arg_values::args,
+    value::{list, value},
     captures::captures,
     version::version_spec,
-    common::{VResult, ws},
+    common::{self, VResult, ws},
     target::target,
 };
 
-pub fn deps(input: &str) -> VResult<Vec<Dependency>> {
+pub fn deps(input: &str) -> VResult<'_, Vec<Dependency>> {
     separated_list1(tag(","), dep)(input)
 }
 
 // labels in deps allow paths
-fn label(input: &str) -> VResult<&str> {
+fn label(input: &str) -> VResult<'_, &str> {
     ws(take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '/'))(input)
 }
 
-fn dep(input: &str) -> VResult<Dependency> {
+fn dep(input: &str) -> VResult<'_, Dependency> {
     let (rest, target) = target_tag(input)?;
-    let (rest, (name, _version)) = tagged_name(rest)?;
-    let (rest, args) = args(rest)?;
+    let (rest, (name, version)) = tagged_name(rest)?;
+    let (rest, (args, each)) = args(rest)?;
+    let (rest, alias) = opt(alias)(rest)?;
     let (rest, captures) = opt(captures)(rest)?;
 
     Ok((
         rest,
         Dependency {
             name: name.to_string(),
+            version,
             args,
+            each,
+            alias: alias.map(String::from),
             captures: captures.unwrap_or_default(),
             target,
         }
     ))
 }
 
-fn target_tag(input: &str) -> VResult<Option<Target>> {
+/// Args for a dependency, and the name of the one it fans out over, if any
+fn args(input: &str) -> VResult<'_, (ValueSet, Option<String>)> {
+    let each_list = map(ws(preceded(tag("each"), list)), |list| (list, true));
+    let arg_value = alt((each_list, map(value, |value| (value, false))));
+    let (rest, parsed) = separated_list0(tag(","), separated_pair(common::label, tag("="), arg_value))(input)?;
+
+    let mut args = ValueSet::new();
+    let mut each = None;
+    for (name, (value, is_each)) in parsed {
+        if is_each && each.replace(name.to_string()).is_some() {
+            return Err(nom::Err::Failure(VerboseError {
+                errors: vec![(input, VerboseErrorKind::Context("only one argument of a dependency can use each"))],
+            }));
+        }
+        args.add_value(name, value);
+    }
+
+    Ok((rest, (args, each)))
+}
+
+/// A label for the unit, used when rendering it in place of its name and arguments
+fn alias(input: &str) -> VResult<'_, &str> {
+    preceded(
+        ws(terminated(tag("as"), multispace1)),
+        ws(take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-')),
+    )(input)
+}
+
+fn target_tag(input: &str) -> VResult<'_, Option<Target>> {
     opt(terminated(target, tag(":")))(input)
 }
 
-fn tagged_name(input: &str) -> VResult<(&str, Option<VersionSpecification>)> {
+fn tagged_name(input: &str) -> VResult<'_, (&str, Option<VersionSpecification>)> {
     tuple((label, opt(preceded(tag(":"), version_spec))))(input)
 }
 
@@ -76,6 +113,43 @@ mod tests {
         assert!(dep.args.values.get("output").unwrap().string_equals("/tmp/cat.png"));
         assert_eq!(dep.captures.len(), 0);
         assert_eq!(dep.target, None);
+        assert!(dep.version.is_none());
+        assert!(dep.alias.is_none());
+    }
+
+    #[test]
+    fn test_alias() {
+        let input = "pkg.sh name=postgresql-15 as db_pkg -> path:string";
+        let (rest, dep) = dep(input).unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(dep.name, "pkg.sh");
+        assert!(dep.args.values.get("name").unwrap().string_equals("postgresql-15"));
+        assert_eq!(dep.alias, Some("db_pkg".to_string()));
+        assert_eq!(dep.captures.len(), 1);
+
+        // An argument starting with "as" isn't mistaken for an alias
+        let input = "pkg.sh assets=true";
+        let (rest, unaliased) = super::dep(input).unwrap();
+
+        assert_eq!(rest, "");
+        assert!(unaliased.alias.is_none());
+        assert!(unaliased.args.values.get("assets").unwrap().bool_equals(true));
+    }
+
+    #[test]
+    fn test_version() {
+        use crate::models::version::Comparator;
+
+        let input = "curl.sh:>=2.3 url=\"https://placekitten.com/200/200\"";
+        let (rest, dep) = dep(input).unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(dep.name, "curl.sh");
+        let version = dep.version.unwrap();
+        assert_eq!(version.comparator, Some(Comparator::GreaterThanOrEqual));
+        assert_eq!(version.version.to_string(), "2.3");
+        assert_eq!(dep.args.values.len(), 1);
     }
 
     #[test]
@@ -98,6 +172,28 @@ mod tests {
         assert_eq!(dep.target, None);
     }
 
+    #[test]
+    fn test_each() {
+        use crate::models::Value;
+
+        let input = "pkg.sh name=each[git, tmux], sudo=true -> version:string";
+        let (rest, dep) = dep(input).unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(dep.each, Some("name".to_string()));
+        let instances = dep.instances();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].get("name"), Some(&Value::String("git".to_string())));
+        assert_eq!(instances[1].get("name"), Some(&Value::String("tmux".to_string())));
+        assert!(instances[1].get("sudo").unwrap().bool_equals(true));
+
+        // Lists from shell variables are separated by newlines
+        let (_, from_var) = super::dep("pkg.sh name=each[git\ntmux\n]").unwrap();
+        assert_eq!(from_var.instances().len(), 2);
+
+        assert!(super::dep("pkg.sh name=each[git], other=each[tmux]").is_err());
+    }
+
     #[test]
     fn test_tagged_name() {
         let input = "curl.sh:>2.3";
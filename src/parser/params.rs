@@ -1,53 +1,104 @@
 //! Parser for paramument definitions
 
 use nom::{
-    character::complete::char,
-    bytes::complete::tag,
+    branch::alt,
+    character::complete::{char, digit1},
+    bytes::complete::{tag, take_while1},
     multi::separated_list0,
-    combinator::opt,
-    sequence::{preceded, tuple},
+    combinator::{map, map_res, opt, recognize, verify},
+    sequence::{pair, preceded, separated_pair, tuple},
     error::context,
 };
 
 use std::str;
-use crate::models::Param;
+use crate::models::{Constraint, Param, params::Pattern};
 use super::common::{ws, label, value_type, VResult};
+use super::value::{choices, value};
 
-/* param format:  !foo:string, bar:int, baz:bool
- *              ^-- required ^-+---^   |   ^-- type
+/* param format:  !foo:string, bar:int 1..10, baz:bool=true
+ *              ^-- required ^-+---^   |  ^-- constraint  ^-- default
  *                             |       +-- name
- *                           param 
+ *                           param
  *
  * name and type are both kinds of labels
+ *
+ * constraint format:  in(read, write)   one of the listed values
+ *                     1..65535          a number in an inclusive range, either end can be left off
+ *                     ~ ^[a-z0-9-]+$    a string matching a pattern, which can be quoted
  * */
 
-pub fn params(input: &str) -> VResult<Vec<Param>> {
+pub fn params(input: &str) -> VResult<'_, Vec<Param>> {
     context(
         "params",
         separated_list0(char(','), param)
     )(input)
 }
 
-fn param(input: &str) -> VResult<Param> {
-    let (rest, (bang, name, value_type)) = tuple((
+fn param(input: &str) -> VResult<'_, Param> {
+    let (rest, (bang, name, value_type, constraint, default)) = tuple((
         opt(ws(tag("!"))),
         label,
-        preceded(tag(":"), value_type)
+        preceded(tag(":"), value_type),
+        opt(ws(constraint)),
+        opt(preceded(ws(tag("=")), value)),
     ))(input)?;
 
     let param = Param {
         name: name.to_string(),
+        default: default.map(|default| default.into_type(&value_type)),
         value_type,
-        required: bang.is_some()
+        required: bang.is_some(),
+        constraint,
+        desc: None,
     };
 
     Ok((rest, param))
 }
 
+fn constraint(input: &str) -> VResult<'_, Constraint> {
+    context(
+        "constraint",
+        alt((
+            map(choices, Constraint::OneOf),
+            map(range, |(min, max)| Constraint::Range(min, max)),
+            map(preceded(ws(tag("~")), pattern), |pattern| Constraint::Pattern(Pattern::new(&pattern))),
+        ))
+    )(input)
+}
+
+fn range(input: &str) -> VResult<'_, (Option<f64>, Option<f64>)> {
+    verify(
+        separated_pair(opt(number), tag(".."), opt(number)),
+        |(min, max): &(Option<f64>, Option<f64>)| min.is_some() || max.is_some(),
+    )(input)
+}
+
+fn number(input: &str) -> VResult<'_, f64> {
+    map_res(
+        recognize(tuple((opt(char('-')), digit1, opt(pair(char('.'), digit1))))),
+        str::parse::<f64>,
+    )(input)
+}
+
+/// Patterns run until whitespace or a comma, unless quoted
+fn pattern(input: &str) -> VResult<'_, String> {
+    alt((
+        value_string,
+        map(take_while1(|c: char| !c.is_whitespace() && c != ','), str::to_string),
+    ))(input)
+}
+
+fn value_string(input: &str) -> VResult<'_, String> {
+    let (rest, quote) = alt((char('"'), char('\'')))(input)?;
+    let (rest, text) = take_while1(|c| c != quote)(rest)?;
+    let (rest, _) = char(quote)(rest)?;
+    Ok((rest, text.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::val::ValueType;
+    use crate::models::val::{Value, ValueType};
 
     #[test]
     fn test_param() {
@@ -58,13 +109,16 @@ mod tests {
         assert_eq!(result, Param {
             name: "foo".to_string(),
             value_type: ValueType::String,
-            required: true
+            required: true,
+            default: None,
+            constraint: None,
+            desc: None,
         });
     }
 
     #[test]
     fn test_params() {
-        let input = "  !foo: string, bar: int, baz: bool  ";
+        let input = "  !foo: string, bar: int, baz: bool = true  ";
         let (rest, result) = params(input).unwrap();
 
         assert_eq!(rest, "");
@@ -72,18 +126,51 @@ mod tests {
             Param {
                 name: "foo".to_string(),
                 value_type: ValueType::String,
-                required: true
+                required: true,
+                default: None,
+                constraint: None,
+                desc: None,
             },
             Param {
                 name: "bar".to_string(),
                 value_type: ValueType::Int,
-                required: false
+                required: false,
+                default: None,
+                constraint: None,
+                desc: None,
             },
             Param {
                 name: "baz".to_string(),
                 value_type: ValueType::Bool,
-                required: false
+                required: false,
+                default: Some(Value::Bool(true)),
+                constraint: None,
+                desc: None,
             }
         ]);
     }
+
+    #[test]
+    fn test_constraints() {
+        let input = "mode:string in(read, write)=read, port:int 1..65535, ratio:float ..1.5, name:string ~ ^[a-z0-9-]+$, tag:string ~ '^v[0-9]{1,3}$'";
+        let (rest, result) = params(input).unwrap();
+
+        assert_eq!(rest, "");
+        let constraints = result.iter().map(|p| p.constraint.clone().unwrap()).collect::<Vec<_>>();
+        assert_eq!(constraints, vec![
+            Constraint::OneOf(vec![Value::String("read".to_string()), Value::String("write".to_string())]),
+            Constraint::Range(Some(1.0), Some(65535.0)),
+            Constraint::Range(None, Some(1.5)),
+            Constraint::Pattern(Pattern::new("^[a-z0-9-]+$")),
+            Constraint::Pattern(Pattern::new("^v[0-9]{1,3}$")),
+        ]);
+        assert_eq!(result[0].default, Some(Value::String("read".to_string())));
+
+        let (_, result) = param("offset:int -10..").unwrap();
+        assert_eq!(result.constraint, Some(Constraint::Range(Some(-10.0), None)));
+
+        // A range needs at least one end
+        let (rest, _) = param("offset:int ..").unwrap();
+        assert_eq!(rest, "..");
+    }
 }
@@ -1,30 +1,64 @@
 //! Unit processes communicate to sysunit using messages delmited by headers and ASCII data characters
 //!
 //! These are streaming parsers, since the messages are sent over a pipe which is
-//! read incrementally.
-
-use super::common::{ws, VResult};
+//! read incrementally.  They operate on bytes, so output is only decoded once a
+//! complete line or message has been read, and output which isn't valid UTF-8 is
+//! kept as binary rather than being mangled.
+//!
+//! Version 2 messages carry a nonce chosen by the executor and the byte length of
+//! their text, so output from a unit which happens to contain the delimiters can't
+//! be mistaken for a message or corrupt one:
+//!
+//! `\x01sysu2:<nonce>:<name>.<field>:<length>\x02<text>\x03`
+//!
+//! Lines a hook writes to stderr are sent as version 2 `stderr` messages on the shell's
+//! stderr, and are parsed into their own kind of data rather than a message if an adapter
+//! merges them into stdout.
+//!
+//! Version 1 messages, `\x01<name>.<field>\x02<text>\x03`, are still accepted.  Anything
+//! which fails to parse as a message is treated as a line of output.
 
 use crate::models::emit::{Header, Message};
 use crate::models::stdout_data::StdoutData;
 
 use nom::{
-    bytes::streaming::{tag, take_until, take_while1},
+    IResult,
+    bytes::streaming::{tag, take, take_until, take_while1},
+    bytes::complete::take_while,
+    character::streaming::digit1,
+    error::{ErrorKind, ParseError, VerboseError},
     sequence::{preceded, tuple, terminated},
     branch::alt,
-    combinator::map,
+    combinator::{map, map_opt, map_res},
 };
 
+pub type BResult<'a, O> = IResult<&'a [u8], O, VerboseError<&'a [u8]>>;
 
-const START_OF_HEADER: &str = "\x01";
-const START_OF_TEXT: &str = "\x02";
-const END_OF_TEXT: &str = "\x03";
+const START_OF_HEADER: &[u8] = b"\x01";
+const START_OF_TEXT: &[u8] = b"\x02";
+const END_OF_TEXT: &[u8] = b"\x03";
+const V2_TAG: &[u8] = b"sysu2:";
+
+/// Skips whitespace around the given parser, like `common::ws` does for text
+fn ws<'a, O>(inner: impl FnMut(&'a [u8]) -> BResult<'a, O>) -> impl FnMut(&'a [u8]) -> BResult<'a, O> {
+    let space = |input: &'a [u8]| take_while(|b: u8| b.is_ascii_whitespace())(input);
+    preceded(space, terminated(inner, space))
+}
+
+/// Decodes bytes which must be UTF-8 to be valid, failing the parser otherwise
+fn utf8(input: &[u8]) -> Result<&str, nom::Err<VerboseError<&[u8]>>> {
+    std::str::from_utf8(input)
+        .map_err(|_| nom::Err::Error(VerboseError::from_error_kind(input, ErrorKind::Verify)))
+}
 
-pub fn header_label(input: &str) -> VResult<&str> {
-    ws(take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-'))(input)
+pub fn header_label(input: &[u8]) -> BResult<'_, &str> {
+    map_res(
+        ws(take_while1(|b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')),
+        std::str::from_utf8,
+    )(input)
 }
 
-fn text(input: &str) -> VResult<&str> {
+fn text(input: &[u8]) -> BResult<'_, &[u8]> {
     map(
         tuple((
             take_until(END_OF_TEXT),
@@ -34,14 +68,14 @@ fn text(input: &str) -> VResult<&str> {
     )(input)
 }
 
-fn header(input: &str) -> VResult<Header> {
+fn header(input: &[u8]) -> BResult<'_, Header> {
     preceded(
         tag(START_OF_HEADER),
         terminated(ws(header_text), tag(START_OF_TEXT))
     )(input)
 }
 
-fn header_text(input: &str) -> VResult<Header> {
+fn header_text(input: &[u8]) -> BResult<'_, Header> {
     
     alt((
         map(
@@ -56,31 +90,64 @@ fn header_text(input: &str) -> VResult<Header> {
     ))(input)
 }
 
-fn message(input: &str) -> VResult<Message> {
+fn message(input: &[u8]) -> BResult<'_, Message> {
+    let (rest, (header, text)) = tuple((header, text))(input)?;
+    Ok((rest, Message { header, text: utf8(text)?.to_string() }))
+}
+
+fn v2_header<'a>(input: &'a [u8], nonce: &str) -> BResult<'a, (Header, usize)> {
     map(
         tuple((
-            header,
-            text,
+            tag(START_OF_HEADER),
+            tag(V2_TAG),
+            tag(nonce.as_bytes()),
+            tag(":"),
+            header_text,
+            tag(":"),
+            map_opt(digit1, |len: &[u8]| std::str::from_utf8(len).ok()?.parse::<usize>().ok()),
+            tag(START_OF_TEXT),
         )),
-        |(header, text)| Message {
-            header,
-            text: text.to_string(),
+        |(_, _, _, _, header, _, len, _)| (header, len)
+    )(input)
+}
+
+fn v2_message<'a>(input: &'a [u8], nonce: &str) -> BResult<'a, Message> {
+    let (rest, (header, len)) = v2_header(input, nonce)?;
+    let (rest, text) = terminated(take(len), tag(END_OF_TEXT))(rest)?;
+    Ok((rest, Message { header, text: utf8(text)?.to_string() }))
+}
+
+/// Stderr is only framed by the slug, so only trust it with the executor's nonce
+fn v2_data<'a>(input: &'a [u8], nonce: &str) -> BResult<'a, StdoutData> {
+    map(
+        |i| v2_message(i, nonce),
+        |message| match message.header {
+            Header { ref name, field: None } if name == "stderr" => StdoutData::Stderr(message.text),
+            _ => StdoutData::Message(message),
         }
     )(input)
 }
 
-fn text_line(input: &str) -> VResult<&str> {
-    use nom::bytes::streaming::take_until;
+fn text_line(input: &[u8]) -> BResult<'_, &[u8]> {
     terminated(take_until("\n"), tag("\n"))(input)
 }
 
-pub fn stdout_data(input: &str) -> VResult<StdoutData> {
+/// Lines of output are text if they're valid UTF-8, or binary otherwise
+pub fn output_data(line: &[u8]) -> StdoutData {
+    match std::str::from_utf8(line) {
+        Ok(text) => StdoutData::TextLine(text.to_string()),
+        Err(_) => StdoutData::Binary(line.to_vec()),
+    }
+}
+
+pub fn stdout_data<'a>(input: &'a [u8], nonce: &str) -> BResult<'a, StdoutData> {
     alt((
+        ws(|i| v2_data(i, nonce)),
         map(
             ws(message),
             StdoutData::Message
         ),
-        map(text_line, |text| StdoutData::TextLine(text.to_string()))
+        map(text_line, output_data)
     ))(input)
 }
 
@@ -90,33 +157,33 @@ mod tests {
 
     #[test]
     fn test_header() {
-        let input = "\x01foo\x02";
+        let input = b"\x01foo\x02";
         let (rest, result) = header(input).unwrap();
-        assert_eq!(rest, "");
+        assert_eq!(rest, b"");
         assert_eq!(result, Header::build("foo", None));
     }
 
     #[test]
     fn test_header_text() {
-        let input = "foo.bar\x02";
+        let input = b"foo.bar\x02";
         let (rest, result) = header_text(input).unwrap();
-        assert_eq!(rest, "\x02");
+        assert_eq!(rest, b"\x02");
         assert_eq!(result, Header::build("foo", Some("bar")));
     }
 
     #[test]
     fn test_text() {
-        let input = "foo\x03";
+        let input = b"foo\x03";
         let (rest, result) = text(input).unwrap();
-        assert_eq!(rest, "");
-        assert_eq!(result, "foo");
+        assert_eq!(rest, b"");
+        assert_eq!(result, b"foo");
     }
 
     #[test]
     fn test_message() {
-        let input = "\x01foo\x02bar\x03";
+        let input = b"\x01foo\x02bar\x03";
         let (rest, result) = message(input).unwrap();
-        assert_eq!(rest, "");
+        assert_eq!(rest, b"");
         assert_eq!(result, Message {
             header: Header::build("foo", None),
             text: "bar".to_string(),
@@ -125,30 +192,83 @@ mod tests {
 
     #[test]
     fn test_data_line() {
-        let input = "foo\n";
+        let input = b"foo\n";
         let (rest, result) = text_line(input).unwrap();
-        assert_eq!(rest, "");
-        assert_eq!(result, "foo");
+        assert_eq!(rest, b"");
+        assert_eq!(result, b"foo");
+    }
+
+    #[test]
+    fn test_v2_message() {
+        let input = "\x01sysu2:abc123:value.out:10\x02a\x03b\x01c\x02\ndé\x03rest".as_bytes();
+        let (rest, result) = v2_message(input, "abc123").unwrap();
+        assert_eq!(rest, b"rest");
+        assert_eq!(result, Message {
+            header: Header::build("value", Some("out")),
+            text: "a\x03b\x01c\x02\ndé".to_string(),
+        });
+
+        // Partial messages wait for more data, even when split within a character
+        let input = "\x01sysu2:abc123:value.out:2\x02é".as_bytes();
+        assert!(matches!(v2_message(&input[..input.len() - 1], "abc123"), Err(nom::Err::Incomplete(_))));
+        assert!(matches!(v2_message(input, "abc123"), Err(nom::Err::Incomplete(_))));
+
+        // Messages with the wrong nonce, or whose length doesn't line up, are rejected
+        let input = b"\x01sysu2:forged:status:1\x020\x03";
+        assert!(matches!(v2_message(input, "abc123"), Err(nom::Err::Error(_))));
+        let input = b"\x01sysu2:abc123:status:1\x0200\x03";
+        assert!(matches!(v2_message(input, "abc123"), Err(nom::Err::Error(_))));
     }
 
     #[test]
     fn test_stdout_data() {
-        let input = "foo\n";
-        let (rest, result) = stdout_data(input).unwrap();
-        assert_eq!(rest, "");
+        let nonce = "abc123";
+
+        let input = b"\n\x01sysu2:abc123:status:1\x020\x03";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"");
+        assert_eq!(result, StdoutData::Message(Message {
+            header: Header::build("status", None),
+            text: "0".to_string(),
+        }));
+
+        let input = b"\n\x01sysu2:abc123:stderr:9\x02no such x\x03";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"");
+        assert_eq!(result, StdoutData::Stderr("no such x".to_string()));
+
+        // Output resembling a message from another executor is just output
+        let input = b"\x01sysu2:forged:status:1\x020\x03\n";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"");
+        assert_eq!(result, StdoutData::TextLine("\x01sysu2:forged:status:1\x020\x03".to_string()));
+
+        let input = b"\x01 not a header\n";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"");
+        assert_eq!(result, StdoutData::TextLine("\x01 not a header".to_string()));
+
+        let input = b"\xff\xfe binary\n";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"");
+        assert_eq!(result, StdoutData::Binary(b"\xff\xfe binary".to_vec()));
+
+        let input = b"foo\n";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"");
         assert_eq!(result, StdoutData::TextLine("foo".to_string()));
 
-        let input = "\x01foo\x02bar\x03\n";
-        let (rest, result) = stdout_data(input).unwrap();
-        assert_eq!(rest, "");
+        let input = b"\x01foo\x02bar\x03\n";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"");
         assert_eq!(result, StdoutData::Message(Message {
             header: Header::build("foo", None),
             text: "bar".to_string(),
         }));
 
-        let input = "\n\x01meta.params\x02!message:string, !foo_count:int\x03\n\x01status\x020\x03";
-        let (rest, result) = stdout_data(input).unwrap();
-        assert_eq!(rest, "\x01status\x020\x03");
+        let input = b"\n\x01meta.params\x02!message:string, !foo_count:int\x03\n\x01status\x020\x03";
+        let (rest, result) = stdout_data(input, nonce).unwrap();
+        assert_eq!(rest, b"\x01status\x020\x03");
         assert_eq!(result, StdoutData::Message(Message {
             header: Header::build("meta", Some("params")),
             text: "!message:string, !foo_count:int".to_string(),
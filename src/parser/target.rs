@@ -8,7 +8,7 @@ use nom::{
 
 use super::common::{label, VResult};
 
-pub fn target(input: &str) -> VResult<Target> {
+pub fn target(input: &str) -> VResult<'_, Target> {
     map(
         tuple((
             proto,
@@ -19,7 +19,7 @@ pub fn target(input: &str) -> VResult<Target> {
     )(input)
 }
 
-fn user(input: &str) -> VResult<&str> {
+fn user(input: &str) -> VResult<'_, &str> {
     map(
         tuple((
             label,
@@ -29,7 +29,7 @@ fn user(input: &str) -> VResult<&str> {
     )(input)
 }
 
-fn proto(input: &str) -> VResult<&str> {
+fn proto(input: &str) -> VResult<'_, &str> {
     map(
         tuple((
             label,
@@ -1,24 +1,29 @@
 use super::common::{ws, VResult, label};
+use super::version::version;
 
 use nom::{
     bytes::complete::tag,
-    combinator::map,
-    sequence::{preceded, delimited},
+    combinator::{map, opt},
+    sequence::{preceded, delimited, pair},
 
 };
 
-/// Returns the unit name parsed from a unit header line
-pub fn header(input: &str) -> VResult<String> {
+/// Returns the unit name parsed from a unit header line.  Headers may tag the
+/// unit with a version, as in `# [ nginx.sh@2.1.0 ]`, which is kept in the name.
+pub fn header(input: &str) -> VResult<'_, String> {
     map(
         preceded(
             ws(tag("#")),
             ws(delimited(
                 ws(tag("[")),
-                label,
+                pair(label, opt(preceded(tag("@"), version))),
                 ws(tag("]")),
             ))
         ),
-        |name| name.to_string()
+        |(name, version)| match version {
+            Some(version) => format!("{}@{}", name, version),
+            None => name.to_string(),
+        }
     )(input)
 }
 
@@ -40,6 +45,12 @@ mod tests {
         assert_eq!(rest, "");
         assert_eq!(result, "Blaaaarp");
 
+        let input = "# [ nginx.sh@2.1 ]";
+        let (rest, result) = header(input).unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(result, "nginx.sh@2.1");
+
         let input = "# This is a normal comment";
         let res = header(input);
         assert!(res.is_err());
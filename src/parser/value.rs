@@ -1,6 +1,12 @@
-/// Parses values used for args and emits
+//! Parses values used for args and emits
 
-use crate::models::val::Value;
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use nom::combinator::all_consuming;
+
+use crate::models::val::{Value, ValueType};
 
 use super::common::{VResult, label, ws};
 
@@ -8,45 +14,212 @@ use nom::{
     bytes::complete::tag,
     character::complete::digit1,
     branch::alt,
-    bytes::complete::{take_until1, take_while1},
-    character::complete::i32,
-    sequence::{terminated, separated_pair},
-    combinator::{recognize, map},
+    bytes::complete::{is_not, take_until, take_while, take_while1},
+    character::complete::{anychar, char, i32, one_of, line_ending, multispace0, space0},
+    multi::{fold_many0, separated_list0, separated_list1},
+    Parser,
+    sequence::{delimited, preceded, terminated, separated_pair},
+    combinator::{opt, peek, recognize, map, map_res, value as to},
 };
 
-fn string(input: &str) -> VResult<Value> {
-    let (rest, quote) = alt((tag("\""), tag("'")))(input)?;
-    let (rest, value) = terminated(take_until1(quote), tag(quote))(rest)?;
-    Ok((rest, Value::String(value.to_string())))
+/// Double quoted strings take backslash escapes, single quoted ones are taken as they are.
+/// Backslashes which don't start a known escape are kept, so `"C:\dir"` is still a path.
+fn string(input: &str) -> VResult<'_, Value> {
+    let escape = preceded(char('\\'), alt((
+        to("\\".to_string(), char('\\')),
+        to("\"".to_string(), char('"')),
+        to("'".to_string(), char('\'')),
+        to("\n".to_string(), char('n')),
+        to("\t".to_string(), char('t')),
+        to("\r".to_string(), char('r')),
+        map(anychar, |c| format!("\\{}", c)),
+    )));
+    let double_quoted = delimited(
+        char('"'),
+        fold_many0(alt((map(is_not("\\\""), str::to_string), escape)), String::new, |mut text, fragment| {
+            text.push_str(&fragment);
+            text
+        }),
+        char('"'),
+    );
+    let single_quoted = map(delimited(char('\''), take_while(|c| c != '\''), char('\'')), str::to_string);
+
+    map(alt((double_quoted, single_quoted)), Value::String)(input)
+}
+
+/// Lines of text up to a line holding only the tag which follows `<<`, like a heredoc
+/// in shell.  The newline before the closing tag isn't part of the text.
+fn heredoc(input: &str) -> VResult<'_, Value> {
+    let (rest, delimiter) = delimited(tag("<<"), take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'), line_ending)(input)?;
+
+    // The text can be empty, in which case the closing tag follows straight away
+    if let Some(rest) = rest.strip_prefix(delimiter).filter(|r| r.is_empty() || r.starts_with(['\n', '\r'])) {
+        return Ok((rest, Value::String(String::new())));
+    }
+
+    let mut offset = 0;
+    loop {
+        let (_, line) = take_until("\n")(&rest[offset..])?;
+        let end = offset + line.len();
+        let after = &rest[end + 1..];
+        if let Some(after) = after.strip_prefix(delimiter).filter(|a| a.is_empty() || a.starts_with(['\n', '\r', ',', ' ', '\t'])) {
+            let text = rest[..end].strip_suffix('\r').unwrap_or(&rest[..end]);
+            return Ok((after, Value::String(text.to_string())));
+        }
+        offset = end + 1;
+    }
+}
+
+/// Base64 encoded text, like `b64:aGVsbG8=`, for values which are awkward to quote
+fn base64(input: &str) -> VResult<'_, Value> {
+    let encoded = take_while1(|c: char| c.is_ascii_alphanumeric() || "+/=".contains(c));
+    map_res(preceded(tag("b64:"), encoded), |encoded: &str| {
+        STANDARD.decode(encoded)
+            .map_err(|e| anyhow!("{}", e))
+            .and_then(|bytes| String::from_utf8(bytes).map_err(|e| anyhow!("{}", e)))
+            .map(Value::String)
+    })(input)
 }
 
-fn int(input: &str) -> VResult<Value> {
+fn int(input: &str) -> VResult<'_, Value> {
     map(i32, |value: i32| Value::Int(value))(input)
 }
 
-fn float(input: &str) -> VResult<Value> {
+fn float(input: &str) -> VResult<'_, Value> {
     let get_number = recognize(separated_pair(digit1, tag("."), digit1));
     map(get_number, |value: &str| {
         Value::Float(value.parse::<f32>().unwrap())
     })(input)
 }
 
-fn bool(input: &str) -> VResult<Value> {
+fn bool(input: &str) -> VResult<'_, Value> {
     let (rest, val) = alt((tag("true"), tag("false")))(input)?;
     Ok((rest, Value::Bool(val == "true")))
 }
 
-fn unquoted_string(input: &str) -> VResult<Value> {
+fn unquoted_string(input: &str) -> VResult<'_, Value> {
     map(take_while1(|c| c != ' ' && c != '\t' && c != '\n' && c != ','), |value: &str| {
         Value::String(value.to_string())
     })(input)
 }
 
-pub fn value(input: &str) -> VResult<Value> {
-    ws(alt((string, float, int, bool, unquoted_string)))(input)
+/// Unquoted strings within a list or map run until the next separator or closing
+/// bracket, so they can contain spaces
+fn unquoted_element<'a>(closers: &'static str) -> impl FnMut(&'a str) -> VResult<'a, Value> {
+    map(take_while1(move |c: char| c != '\n' && c != ',' && !closers.contains(c)), |value: &str| {
+        Value::String(value.trim_end().to_string())
+    })
+}
+
+/// Elements only skip spaces after themselves, since a newline can separate them
+fn element_until<'a>(closers: &'static str) -> impl FnMut(&'a str) -> VResult<'a, Value> {
+    move |input: &'a str| {
+        // Typed values must make up the whole element, so `2 x` is the string it looks like
+        let end = peek(preceded(space0, one_of(",\n").or(one_of(closers))));
+        delimited(
+            multispace0,
+            alt((terminated(alt((string, float, int, bool)), end), unquoted_element(closers))),
+            space0,
+        )(input)
+    }
+}
+
+fn element(input: &str) -> VResult<'_, Value> {
+    element_until("]}")(input)
+}
+
+/// The values a param is limited to, like `in(read, write)`
+pub fn choices(input: &str) -> VResult<'_, Vec<Value>> {
+    delimited(
+        tag("in("),
+        terminated(separated_list1(tag(","), element_until(")")), opt(ws(tag(",")))),
+        ws(tag(")")),
+    )(input)
+}
+
+/// Items are separated by commas or newlines, so a list can be built from a
+/// newline separated variable in shell, like `[$pkgs]`
+fn separator(input: &str) -> VResult<'_, &str> {
+    alt((tag(","), line_ending))(input)
 }
 
-pub fn named_value(input: &str) -> VResult<(&str, Value)> {
+/// A list of scalar values, like `[git, tmux]`
+pub fn list(input: &str) -> VResult<'_, Value> {
+    map(
+        delimited(
+            tag("["),
+            terminated(separated_list0(separator, element), opt(ws(tag(",")))),
+            ws(tag("]")),
+        ),
+        Value::List,
+    )(input)
+}
+
+/// Map names are passed to units as part of a shell variable's name, like `$env_EDITOR`,
+/// so they're limited to letters, digits and underscores
+fn map_key(input: &str) -> VResult<'_, &str> {
+    ws(take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'))(input)
+}
+
+/// A map of names to scalar values, like `{A=1, B=two}`
+fn map_value(input: &str) -> VResult<'_, Value> {
+    map(
+        delimited(
+            tag("{"),
+            terminated(separated_list0(separator, separated_pair(map_key, tag("="), element)), opt(ws(tag(",")))),
+            ws(tag("}")),
+        ),
+        |entries| Value::Map(entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect::<BTreeMap<_, _>>()),
+    )(input)
+}
+
+pub fn value(input: &str) -> VResult<'_, Value> {
+    ws(alt((string, heredoc, base64, list, map_value, float, int, bool, unquoted_string)))(input)
+}
+
+/// Reads raw text, such as an argument given on the CLI, as a value of a known type.
+/// Unlike values in units, the text isn't quoted, so strings are taken as they are.
+pub fn typed_value(input: &str, value_type: &ValueType) -> Result<Value> {
+    let invalid = || anyhow!("{:?} is not a valid {}", input, value_type);
+    match value_type {
+        ValueType::String => Ok(Value::String(input.to_string())),
+        ValueType::Secret => Ok(Value::Secret(input.to_string())),
+        ValueType::Int => input.trim().parse().map(Value::Int).map_err(|_| invalid()),
+        ValueType::Float => input.trim().parse().map(Value::Float).map_err(|_| invalid()),
+        ValueType::Bool => match input.trim() {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => Err(invalid()),
+        },
+        ValueType::List => all_consuming(ws(list))(input).map(|(_, list)| list).map_err(|_| invalid()),
+        ValueType::Map => all_consuming(ws(map_value))(input).map(|(_, map)| map).map_err(|_| invalid()),
+    }
+}
+
+/// Reads an emitted value as the type its emit declares.  Emitted text is a literal, but a
+/// plain word or quoted string is read as the declared type rather than whatever it looks
+/// like, the same way raw args are read as the type of their param.
+pub fn emitted_value(input: &str, value_type: &ValueType) -> Result<Value> {
+    match all_consuming(ws(value))(input).map(|(_, value)| value.into_type(value_type)) {
+        Ok(value) if value.get_type() == *value_type => Ok(value),
+        Ok(Value::String(text)) => typed_value(&text, value_type),
+        _ => typed_value(input.trim(), value_type),
+    }
+}
+
+/// Reads an emitted value whose emit isn't declared.  Emitted text is quoted, so it's read
+/// as a number, bool, list or map where it looks like one, and otherwise kept as it is.
+pub fn inferred_value(value: Value) -> Value {
+    match value {
+        Value::String(text) => match all_consuming(ws(self::value))(&text).map(|(_, inferred)| inferred) {
+            Ok(Value::String(_)) | Err(_) => Value::String(text.clone()),
+            Ok(inferred) => inferred,
+        },
+        value => value,
+    }
+}
+
+pub fn named_value(input: &str) -> VResult<'_, (&str, Value)> {
     separated_pair(label, tag("="), value)(input)
 }
 
@@ -74,6 +247,55 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_escapes() {
+        let (rest, value) = string(r#""it's \"quoted\"\\\n\tdone" next"#).unwrap();
+        assert_eq!(rest, " next");
+        assert_eq!(value, Value::String("it's \"quoted\"\\\n\tdone".to_string()));
+
+        // Single quotes take backslashes as they are
+        let (_, value) = string(r"'C:\dir\n'").unwrap();
+        assert_eq!(value, Value::String(r"C:\dir\n".to_string()));
+
+        let (_, value) = super::value(r#""""#).unwrap();
+        assert_eq!(value, Value::String(String::new()));
+
+        // Unknown escapes are kept as they're written
+        let (_, value) = string(r#""C:\dir\q \" end""#).unwrap();
+        assert_eq!(value, Value::String(r#"C:\dir\q " end"#.to_string()));
+        // while known ones are still read, so such paths are best single quoted
+        let (_, value) = string(r#""C:\new""#).unwrap();
+        assert_eq!(value, Value::String("C:\new".to_string()));
+    }
+
+    #[test]
+    fn test_heredoc() {
+        let input = "<<END\n-----BEGIN CERT-----\nit's \"raw\" \\ text\n\nEND, next";
+        let (rest, value) = super::value(input).unwrap();
+        assert_eq!(rest, ", next");
+        assert_eq!(value, Value::String("-----BEGIN CERT-----\nit's \"raw\" \\ text\n".to_string()));
+
+        // The closing tag must be on a line of its own
+        let (rest, value) = heredoc("<<EOF\nEOFS\nEOF").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(value, Value::String("EOFS".to_string()));
+
+        let (_, value) = heredoc("<<EOF\nEOF").unwrap();
+        assert_eq!(value, Value::String(String::new()));
+
+        assert!(heredoc("<<EOF\nnever closed\n").is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        let (rest, value) = super::value("b64:aXQncyAieCIKeQ==, next").unwrap();
+        assert_eq!(rest, ", next");
+        assert_eq!(value, Value::String("it's \"x\"\ny".to_string()));
+
+        assert!(base64("b64:abc").is_err(), "unpadded base64 should be rejected");
+        assert!(base64("b64:/w==").is_err(), "invalid UTF-8 should be rejected");
+    }
+
     #[test]
     fn test_float() {
         let input = "123.456";
@@ -124,6 +346,124 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_list() {
+        let input = "[git, 'tmux 3', 2, true, 2 x, ]";
+        let (rest, value) = value(input).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(value, Value::List(vec![
+            Value::String("git".to_string()),
+            Value::String("tmux 3".to_string()),
+            Value::Int(2),
+            Value::Bool(true),
+            Value::String("2 x".to_string()),
+        ]));
+
+        let (rest, value) = super::value("[], next").unwrap();
+        assert_eq!(rest, ", next");
+        assert_eq!(value, Value::List(vec![]));
+
+        let (rest, value) = super::value("[git\ntmux 3\n]").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(value, Value::List(vec![
+            Value::String("git".to_string()),
+            Value::String("tmux 3".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn test_choices() {
+        let (rest, values) = choices("in(read, 'read write', 3)=read").unwrap();
+        assert_eq!(rest, "=read");
+        assert_eq!(values, vec![
+            Value::String("read".to_string()),
+            Value::String("read write".to_string()),
+            Value::Int(3),
+        ]);
+
+        assert!(choices("in()").is_err());
+    }
+
+    #[test]
+    fn test_map() {
+        let input = "{A=1, B = two,C=\"x, y\"}";
+        let (rest, value) = value(input).unwrap();
+        assert_eq!(rest, "");
+        let expected = BTreeMap::from([
+            ("A".to_string(), Value::Int(1)),
+            ("B".to_string(), Value::String("two".to_string())),
+            ("C".to_string(), Value::String("x, y".to_string())),
+        ]);
+        assert_eq!(value, Value::Map(expected));
+
+        // Names which can't be part of a shell variable's name aren't maps
+        assert!(map_value("{a-b=1}").is_err());
+        assert!(map_value("{a.b=1}").is_err());
+        assert!(typed_value("{EDITOR=vim, a-b=1}", &ValueType::Map).is_err());
+        assert_eq!(inferred_value(Value::String("{a-b=1}".to_string())), Value::String("{a-b=1}".to_string()));
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for input in ["[git, \"a b\", 'say \"hi\"', \"\", 1.5]", "{A=1, B=\"x=y\"}", r#"["it's \"both\"", 'C:\dir', "two\nlines"]"#] {
+            let (_, parsed) = value(input).unwrap();
+            let displayed = parsed.to_string();
+            let (rest, reparsed) = value(&displayed).unwrap();
+            assert_eq!(rest, "");
+            assert_eq!(reparsed, parsed);
+        }
+    }
+
+    #[test]
+    fn test_typed_value() {
+        assert_eq!(typed_value("01234", &ValueType::String).unwrap(), Value::String("01234".to_string()));
+        assert_eq!(typed_value("123", &ValueType::String).unwrap(), Value::String("123".to_string()));
+        assert_eq!(typed_value("01234", &ValueType::Int).unwrap(), Value::Int(1234));
+        assert_eq!(typed_value("8", &ValueType::Float).unwrap(), Value::Float(8.0));
+        assert_eq!(typed_value("false", &ValueType::Bool).unwrap(), Value::Bool(false));
+        assert_eq!(typed_value("[a, b]", &ValueType::List).unwrap(), Value::List(vec![
+            Value::String("a".to_string()),
+            Value::String("b".to_string()),
+        ]));
+
+        assert!(typed_value("abc", &ValueType::Int).is_err());
+        assert!(typed_value("yes", &ValueType::Bool).is_err());
+        assert!(typed_value("a, b", &ValueType::List).is_err());
+        assert!(typed_value("[a]", &ValueType::Map).is_err());
+    }
+
+    #[test]
+    fn test_emitted_value() {
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(emitted_value("12", &ValueType::String).unwrap(), string("12"));
+        assert_eq!(emitted_value("0700", &ValueType::String).unwrap(), string("0700"));
+        assert_eq!(emitted_value("1.50", &ValueType::String).unwrap(), string("1.50"));
+        assert_eq!(emitted_value("true", &ValueType::String).unwrap(), string("true"));
+        assert_eq!(emitted_value("\"[WARN] disk [sda]\"", &ValueType::String).unwrap(), string("[WARN] disk [sda]"));
+        assert_eq!(emitted_value("\"12\"", &ValueType::Int).unwrap(), Value::Int(12));
+        assert_eq!(emitted_value("3", &ValueType::Float).unwrap(), Value::Float(3.0));
+        assert_eq!(emitted_value("hunter2", &ValueType::Secret).unwrap(), Value::Secret("hunter2".to_string()));
+        assert_eq!(emitted_value("[a, 1]", &ValueType::List).unwrap(), Value::List(vec![string("a"), Value::Int(1)]));
+
+        assert!(emitted_value("abc", &ValueType::Int).is_err());
+        assert!(emitted_value("[a]", &ValueType::Map).is_err());
+    }
+
+    #[test]
+    fn test_inferred_value() {
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(inferred_value(string("12")), Value::Int(12));
+        assert_eq!(inferred_value(string("true")), Value::Bool(true));
+        assert_eq!(inferred_value(string("[a, 1]")), Value::List(vec![string("a"), Value::Int(1)]));
+        assert_eq!(inferred_value(string("{A=1}")).get_type(), ValueType::Map);
+
+        // Text which only partly looks like something else, or is quoted, is kept as it is
+        assert_eq!(inferred_value(string("[WARN] disk [sda]")), string("[WARN] disk [sda]"));
+        assert_eq!(inferred_value(string("\"quoted\"")), string("\"quoted\""));
+        assert_eq!(inferred_value(string("two words")), string("two words"));
+        assert_eq!(inferred_value(Value::Int(3)), Value::Int(3));
+    }
+
     #[test]
     fn test_unquoted_string() {
         let input = "hello ";
@@ -10,14 +10,14 @@ use nom::{
 
 use crate::models::version::{Version, Comparator, VersionSpecification};
 
-pub fn version_spec(input: &str) -> VResult<VersionSpecification> {
+pub fn version_spec(input: &str) -> VResult<'_, VersionSpecification> {
     let (rest, (comparator, version)) = tuple((opt(comparator), version))(input)?;
     let spec = VersionSpecification { comparator, version };
 
     Ok((rest, spec))
 }
 
-pub fn version(input: &str) -> VResult<Version> {
+pub fn version(input: &str) -> VResult<'_, Version> {
     let (rest, (major, minor, patch)) = tuple((
         digit1,
         opt(preceded(tag("."), digit1)),
@@ -37,7 +37,7 @@ pub fn version(input: &str) -> VResult<Version> {
     Ok((rest, Version { major, minor, patch }))
 }
 
-fn comparator(input: &str) -> VResult<Comparator> {
+fn comparator(input: &str) -> VResult<'_, Comparator> {
     alt((
         value(Comparator::GreaterThanOrEqual, tag(">=")),
         value(Comparator::LessThanOrEqual, tag("<=")),